@@ -1,9 +1,11 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use easy_fs::{BlockDevice, DiskInodeType, EasyFileSystem, Inode};
 use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const BLOCK_SZ: usize = 512;
 
@@ -25,70 +27,612 @@ impl BlockDevice for BlockFile {
     }
 }
 
-fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
+/// Seconds since the Unix epoch, used for inode timestamps
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() as u32)
 }
 
-fn easy_fs_pack() -> std::io::Result<()> {
-    let matches = App::new("EasyFileSystem packer")
+/// Command line of the packer and of the subcommands working on an image
+fn cli() -> App<'static, 'static> {
+    let image = || {
+        Arg::with_name("image")
+            .required(true)
+            .help("Image to work on, e.g. fs.img")
+    };
+    let path = |help| Arg::with_name("path").required(true).help(help);
+    App::new("EasyFileSystem packer")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("source")
                 .short("s")
                 .long("source")
                 .takes_value(true)
-                .help("Executable source dir(with backslash)"),
+                .required(true)
+                .help("Host directory whose tree is copied into the image"),
         )
         .arg(
             Arg::with_name("target")
                 .short("t")
                 .long("target")
                 .takes_value(true)
-                .help("Executable target dir(with backslash)"),
+                .required(true)
+                .help("Directory to write fs.img to"),
+        )
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .default_value("16M")
+                .help("Size of the image in bytes, a K, M or G suffix is allowed"),
+        )
+        .arg(
+            Arg::with_name("inodes")
+                .long("inodes")
+                .takes_value(true)
+                .default_value("4096")
+                .help("Number of inodes, rounded up to a multiple of 4096"),
+        )
+        .arg(
+            Arg::with_name("cache-blocks")
+                .long("cache-blocks")
+                .takes_value(true)
+                .default_value("16")
+                .help("Number of blocks in the block cache while packing"),
+        )
+        .arg(
+            Arg::with_name("strip-ext")
+                .short("e")
+                .long("strip-ext")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .default_value("elf")
+                .help("Extension removed from the names of the files copied"),
+        )
+        .arg(
+            Arg::with_name("symlink")
+                .short("l")
+                .long("symlink")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Symbolic link to create in the image as name:target, e.g. sh:ch8b_user_shell",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check an easy-fs image")
+                .arg(image())
+                .arg(
+                    Arg::with_name("repair")
+                        .short("r")
+                        .long("repair")
+                        .help("Repair the problems that can be repaired"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List a directory in an image")
+                .arg(image())
+                .arg(Arg::with_name("path").default_value("/")),
+        )
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("Print a file in an image")
+                .arg(image())
+                .arg(path("File to print")),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Copy a file out of an image")
+                .arg(image())
+                .arg(path("File to copy"))
+                .arg(
+                    Arg::with_name("host")
+                        .help("Host file to write, the name of the file by default"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("put")
+                .about("Copy a host file into an image, replacing the file there")
+                .arg(image())
+                .arg(
+                    Arg::with_name("host")
+                        .required(true)
+                        .help("Host file to copy"),
+                )
+                .arg(Arg::with_name("path").help("File or directory to copy to, \"/\" by default")),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a file or an empty directory from an image")
+                .arg(image())
+                .arg(path("File to remove")),
+        )
+        .subcommand(
+            SubCommand::with_name("stat")
+                .about("Show the metadata of a file in an image")
+                .arg(image())
+                .arg(path("File to show")),
+        )
+        .subcommand(
+            SubCommand::with_name("df")
+                .about("Show the free blocks and inodes of an image")
+                .arg(image()),
+        )
+        .subcommand(
+            SubCommand::with_name("resize")
+                .about("Grow an image and its data area, keeping the data in place")
+                .arg(image())
+                .arg(
+                    Arg::with_name("size")
+                        .required(true)
+                        .help("New size of the image in bytes, a K, M or G suffix is allowed"),
+                ),
+        )
+}
+
+fn main() {
+    let matches = cli().get_matches();
+    match matches.subcommand() {
+        ("", _) => {
+            if let Err(err) = easy_fs_pack(&matches) {
+                eprintln!("Error when packing easy-fs: {}", err);
+                std::process::exit(1);
+            }
+        }
+        ("fsck", Some(matches)) => {
+            let status = easy_fs_fsck(matches).expect("Error when checking easy-fs!");
+            std::process::exit(status);
+        }
+        ("resize", Some(matches)) => {
+            if let Err(err) = easy_fs_resize(matches) {
+                eprintln!("resize: {}", err);
+                std::process::exit(1);
+            }
+        }
+        (command, Some(matches)) => {
+            if let Err(err) = easy_fs_command(command, matches, &mut std::io::stdout()) {
+                eprintln!("{}: {}", command, err);
+                std::process::exit(1);
+            }
+        }
+        (_, None) => unreachable!(),
+    }
+}
+
+/// Check the image and return the exit status of fsck: 0 if it is clean,
+/// 1 if all problems are repaired and 4 if some are left
+fn easy_fs_fsck(matches: &ArgMatches) -> std::io::Result<i32> {
+    let image = matches.value_of("image").unwrap();
+    let repair = matches.is_present("repair");
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(repair).open(image)?,
+    )));
+    let efs = EasyFileSystem::open(block_file, easy_fs::BLOCK_CACHE_SIZE);
+    let problems = efs.lock().fsck(repair);
+    for problem in problems.iter() {
+        let repaired = repair && problem.is_repairable();
+        println!("{}{}", problem, if repaired { ", repaired" } else { "" });
+    }
+    let left = problems
+        .iter()
+        .filter(|problem| !repair || !problem.is_repairable())
+        .count();
+    println!(
+        "{}: {} problems found, {} left",
+        image,
+        problems.len(),
+        left
+    );
+    Ok(match (problems.len(), left) {
+        (0, _) => 0,
+        (_, 0) => 1,
+        _ => 4,
+    })
+}
+
+/// Grow the image to the given size, the free space is added to the data area
+fn easy_fs_resize(matches: &ArgMatches) -> std::io::Result<()> {
+    let image = matches.value_of("image").unwrap();
+    let size = matches.value_of("size").unwrap();
+    let total_blocks = parse_size(size)
+        .filter(|size| size % BLOCK_SZ as u64 == 0)
+        .map(|size| size / BLOCK_SZ as u64)
+        .filter(|total_blocks| *total_blocks <= u32::MAX as u64)
+        .ok_or_else(|| {
+            invalid_input(format!(
+                "Bad size {}, it should be a multiple of {} of at most {} bytes",
+                size,
+                BLOCK_SZ,
+                u32::MAX as u64 * BLOCK_SZ as u64
+            ))
+        })? as u32;
+    let efs = open_image(image)?;
+    let old_blocks = efs.lock().total_blocks();
+    if total_blocks < old_blocks {
+        return Err(invalid_input(format!(
+            "{}: Cannot shrink the image from {} to {} bytes",
+            image,
+            old_blocks as u64 * BLOCK_SZ as u64,
+            total_blocks as u64 * BLOCK_SZ as u64
+        )));
+    }
+    OpenOptions::new()
+        .write(true)
+        .open(image)?
+        .set_len(total_blocks as u64 * BLOCK_SZ as u64)?;
+    assert!(efs.lock().resize(total_blocks));
+    let statfs = efs.lock().statfs();
+    println!(
+        "{}: {} blocks, {} of {} data blocks free",
+        image, total_blocks, statfs.free_blocks, statfs.total_blocks
+    );
+    Ok(())
+}
+
+/// Open an existing image
+fn open_image(image: &str) -> std::io::Result<Arc<spin::Mutex<EasyFileSystem>>> {
+    let block_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new().read(true).write(true).open(image)?,
+    )));
+    let efs = EasyFileSystem::open(block_file, easy_fs::BLOCK_CACHE_SIZE);
+    efs.lock().set_time_source(unix_time);
+    Ok(efs)
+}
+
+/// Open the image file of a test as a block device, creating it if needed.
+/// Each call gets a device of its own, so nothing is served from the cache of another.
+#[cfg(test)]
+fn open_image_file(image: &str) -> Arc<BlockFile> {
+    Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(image)
+            .unwrap(),
+    )))
+}
+
+fn invalid_input(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn no_space(path: &str) -> Error {
+    Error::other(format!("{}: No space left on image", path))
+}
+
+fn not_found(path: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("{}: No such file or directory", path),
+    )
+}
+
+/// Find `path` in the image, a symbolic link as the last component is not followed
+fn lookup(root_inode: &Inode, path: &str) -> std::io::Result<Arc<Inode>> {
+    match root_inode.find_parent(path) {
+        Some((parent, name)) => parent.find(name),
+        // "/" has no last component
+        None => root_inode.find_path(path),
+    }
+    .ok_or_else(|| not_found(path))
+}
+
+/// Read all data of a file in the image
+fn read_inode(inode: &Inode) -> Vec<u8> {
+    let mut data = vec![0u8; inode.size()];
+    let len = inode.read_at(0, &mut data);
+    data.truncate(len);
+    data
+}
+
+/// A line of `ls` for `inode` named `name`
+fn ls_line(inode: &Inode, name: &str) -> String {
+    let metadata = inode.metadata();
+    let type_ = match metadata.type_ {
+        DiskInodeType::File => '-',
+        DiskInodeType::Directory => 'd',
+        DiskInodeType::Symlink => 'l',
+    };
+    let mut line = format!(
+        "{}{:04o} {:>5} {:>9} {}",
+        type_, metadata.mode, metadata.inode_id, metadata.size, name
+    );
+    if let Some(target) = inode.readlink() {
+        line.push_str(&format!(" -> {}", target));
+    }
+    line
+}
+
+/// Run a subcommand working on an image, printing to `out`
+fn easy_fs_command(
+    command: &str,
+    matches: &ArgMatches,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let efs = open_image(matches.value_of("image").unwrap())?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let path = matches.value_of("path").unwrap_or("/");
+    match command {
+        "ls" => {
+            let inode = lookup(&root_inode, path)?;
+            if inode.is_dir() {
+                for name in inode.ls() {
+                    let child = inode.find(&name).unwrap();
+                    writeln!(out, "{}", ls_line(&child, &name))?;
+                }
+            } else {
+                writeln!(out, "{}", ls_line(&inode, path))?;
+            }
+        }
+        "cat" => {
+            let inode = root_inode.find_path(path).ok_or_else(|| not_found(path))?;
+            out.write_all(&read_inode(&inode))?;
+        }
+        "get" => {
+            let inode = root_inode.find_path(path).ok_or_else(|| not_found(path))?;
+            let name = path.trim_end_matches('/').rsplit('/').next().unwrap();
+            let host = matches.value_of("host").unwrap_or(name);
+            std::fs::write(host, read_inode(&inode))?;
+        }
+        "put" => {
+            let host = matches.value_of("host").unwrap();
+            let data = std::fs::read(host)?;
+            let host_name = std::path::Path::new(host)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| invalid_input(format!("{}: Bad host file name", host)))?;
+            let inode = match root_inode.find_path(path) {
+                Some(dir) if dir.is_dir() => dir.find(host_name).or_else(|| dir.create(host_name)),
+                Some(inode) => Some(inode),
+                None => {
+                    let (parent, name) = root_inode
+                        .find_parent(path)
+                        .ok_or_else(|| not_found(path))?;
+                    parent.create(name)
+                }
+            }
+            .ok_or_else(|| no_space(path))?;
+            if inode.is_dir() {
+                return Err(invalid_input(format!("{}: Is a directory", path)));
+            }
+            inode.clear();
+            if inode.write_at(0, &data) < data.len() {
+                return Err(no_space(path));
+            }
+        }
+        "rm" => {
+            let (parent, name) = root_inode
+                .find_parent(path)
+                .ok_or_else(|| not_found(path))?;
+            let inode = parent.find(name).ok_or_else(|| not_found(path))?;
+            let removed = if inode.is_dir() {
+                // an empty directory only has "." and ".."
+                if inode.ls().len() > 2 {
+                    return Err(invalid_input(format!("{}: Directory not empty", path)));
+                }
+                parent.rmdir(name)
+            } else {
+                parent.remove(name)
+            };
+            if removed != 0 {
+                return Err(invalid_input(format!("{}: Cannot remove", path)));
+            }
+        }
+        "stat" => {
+            let inode = lookup(&root_inode, path)?;
+            let metadata = inode.metadata();
+            let type_ = match metadata.type_ {
+                DiskInodeType::File => "regular file",
+                DiskInodeType::Directory => "directory",
+                DiskInodeType::Symlink => "symbolic link",
+            };
+            writeln!(out, "  File: {}", path)?;
+            if let Some(target) = inode.readlink() {
+                writeln!(out, "Target: {}", target)?;
+            }
+            writeln!(out, "  Type: {}", type_)?;
+            writeln!(
+                out,
+                " Inode: {}  Links: {}",
+                metadata.inode_id,
+                inode.link_count()
+            )?;
+            writeln!(
+                out,
+                "  Mode: {:04o}  Uid: {}  Gid: {}",
+                metadata.mode, metadata.uid, metadata.gid
+            )?;
+            writeln!(out, "  Size: {}", metadata.size)?;
+            writeln!(out, "Access: {}", metadata.atime)?;
+            writeln!(out, "Modify: {}", metadata.mtime)?;
+            writeln!(out, "Change: {}", metadata.ctime)?;
+        }
+        "df" => {
+            let statfs = efs.lock().statfs();
+            writeln!(
+                out,
+                "{:>8} {:>10} {:>10} {:>10} {:>5}",
+                "", "Total", "Used", "Free", "Use%"
+            )?;
+            for (what, total, free) in [
+                ("Blocks", statfs.total_blocks, statfs.free_blocks),
+                ("Inodes", statfs.total_inodes, statfs.free_inodes),
+            ] {
+                let used = total - free;
+                writeln!(
+                    out,
+                    "{:>8} {:>10} {:>10} {:>10} {:>4}%",
+                    what,
+                    total,
+                    used,
+                    free,
+                    (used as u64 * 100).div_ceil(total.max(1) as u64)
+                )?;
+            }
+            writeln!(out, "Block size: {}", statfs.block_size)?;
+        }
+        _ => unreachable!(),
+    }
+    // reading a file updates its access time as well
+    easy_fs::block_cache_sync_all();
+    Ok(())
+}
+
+/// Time source of packed images, so that packing the same tree gives the same
+/// image. It is `SOURCE_DATE_EPOCH` if set and 0 otherwise.
+fn pack_time() -> u32 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|time| time.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Parse a number of bytes with an optional K, M or G suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, unit) = match size.char_indices().last()? {
+        (pos, 'K') => (&size[..pos], 1 << 10),
+        (pos, 'M') => (&size[..pos], 1 << 20),
+        (pos, 'G') => (&size[..pos], 1 << 30),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// Copy the host directory `src` into `dir` of the image, entries in the order
+/// of their names. Host symbolic links are copied as symbolic links.
+/// Return the number of files copied.
+fn pack_dir(src: &Path, dir: &Inode, strip_exts: &[&str]) -> std::io::Result<usize> {
+    let mut entries = read_dir(src)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut files = 0;
+    for entry in entries {
+        let host_path = entry.path();
+        let file_type = entry.file_type()?;
+        let mut name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| invalid_input(format!("{:?}: Not a UTF-8 name", name)))?;
+        if file_type.is_file() {
+            if let Some((stem, ext)) = name.rsplit_once('.') {
+                if !stem.is_empty() && strip_exts.contains(&ext) {
+                    name.truncate(stem.len());
+                }
+            }
+        }
+        if name.len() > dir.name_limit() {
+            return Err(invalid_input(format!(
+                "{}: Name longer than {} bytes",
+                host_path.display(),
+                dir.name_limit()
+            )));
+        }
+        // creating fails either on a name clash or on a full image
+        let exists = || match dir.find(&name) {
+            Some(_) => invalid_input(format!(
+                "{}: {} exists in the image",
+                host_path.display(),
+                name
+            )),
+            None => no_space(&host_path.display().to_string()),
+        };
+        if file_type.is_dir() {
+            let sub_dir = dir.mkdir(&name).ok_or_else(exists)?;
+            files += pack_dir(&host_path, &sub_dir, strip_exts)?;
+        } else if file_type.is_symlink() {
+            let target = std::fs::read_link(&host_path)?;
+            let target = target.to_str().ok_or_else(|| {
+                invalid_input(format!("{}: Not a UTF-8 target", host_path.display()))
+            })?;
+            dir.symlink(&name, target).ok_or_else(exists)?;
+        } else {
+            let data = std::fs::read(&host_path)?;
+            let inode = dir.create(&name).ok_or_else(exists)?;
+            if inode.write_at(0, &data) < data.len() {
+                return Err(no_space(&host_path.display().to_string()));
+            }
+            files += 1;
+        }
+    }
+    Ok(files)
+}
+
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
     let src_path = matches.value_of("source").unwrap();
     let target_path = matches.value_of("target").unwrap();
     println!("src_path = {}\ntarget_path = {}", src_path, target_path);
+    let parse = |name: &str| {
+        let value = matches.value_of(name).unwrap();
+        parse_size(value).ok_or_else(|| invalid_input(format!("Bad --{} {}", name, value)))
+    };
+    let size = parse("size")?;
+    let inodes = parse("inodes")?;
+    let cache_blocks = parse("cache-blocks")?.max(1) as usize;
+    // 4096 inodes per bitmap block, and 4 inodes per block
+    let inode_bitmap_blocks = inodes.div_ceil(4096).max(1);
+    let inode_blocks = inode_bitmap_blocks * (1 + 4096 / 4);
+    let total_blocks = size / BLOCK_SZ as u64;
+    // the super block, the journal, the inodes and a data bitmap block
+    let min_blocks = 1 + easy_fs::JOURNAL_BLOCKS as u64 + inode_blocks + 2;
+    if size % BLOCK_SZ as u64 != 0 || total_blocks < min_blocks || total_blocks > u32::MAX as u64 {
+        return Err(invalid_input(format!(
+            "--size should be a multiple of {} between {} and {} bytes for {} inodes",
+            BLOCK_SZ,
+            min_blocks * BLOCK_SZ as u64,
+            u32::MAX as u64 * BLOCK_SZ as u64,
+            inode_bitmap_blocks * 4096
+        )));
+    }
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len(16 * 2048 * 512).unwrap();
+            .truncate(true)
+            .open(Path::new(target_path).join("fs.img"))?;
+        f.set_len(size)?;
         f
     })));
-    // 16MiB, at most 4095 files
-    let efs = EasyFileSystem::create(block_file, 16 * 2048, 1);
+    EasyFileSystem::create(block_file.clone(), total_blocks as u32, inode_bitmap_blocks as u32);
+    let efs = EasyFileSystem::open(block_file, cache_blocks);
+    efs.lock().set_time_source(pack_time);
     let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
-    let apps: Vec<_> = read_dir(src_path)
-        .unwrap()
-        .into_iter()
-        .map(|dir_entry| {
-            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
-            name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
-            name_with_ext
-        })
-        .collect();
-    for app in apps {
-        // load app data from host file system
-        let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
-        let mut all_data: Vec<u8> = Vec::new();
-        host_file.read_to_end(&mut all_data).unwrap();
-        // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
-        // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+    let strip_exts: Vec<&str> = matches.values_of("strip-ext").unwrap().collect();
+    let files = pack_dir(Path::new(src_path), &root_inode, &strip_exts)?;
+    for symlink in matches.values_of("symlink").into_iter().flatten() {
+        let (name, target) = symlink
+            .split_once(':')
+            .expect("Symbolic link should be given as name:target");
+        root_inode
+            .symlink(name, target)
+            .ok_or_else(|| match root_inode.find(name) {
+                Some(_) => invalid_input(format!("{}: Exists in the image", name)),
+                None => no_space(name),
+            })?;
     }
-    // list apps
-    // for app in root_inode.ls() {
-    //     println!("{}", app);
-    // }
+    // the block cache is written back lazily
+    easy_fs::block_cache_sync_all();
+    let statfs = efs.lock().statfs();
+    println!(
+        "{} files packed, {} of {} blocks and {} of {} inodes free",
+        files, statfs.free_blocks, statfs.total_blocks, statfs.free_inodes, statfs.total_inodes
+    );
     Ok(())
 }
 
+/// Tests share the global block cache and its statistics
+#[cfg(test)]
+static TEST_LOCK: Mutex<()> = Mutex::new(());
+
 #[test]
 fn efs_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
     let block_file = Arc::new(BlockFile(Mutex::new({
         let f = OpenOptions::new()
             .read(true)
@@ -99,8 +643,10 @@ fn efs_test() -> std::io::Result<()> {
         f
     })));
     EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
+    let efs = EasyFileSystem::open(block_file.clone(), easy_fs::BLOCK_CACHE_SIZE);
+    efs.lock().set_time_source(unix_time);
     let root_inode = EasyFileSystem::root_inode(&efs);
+    let start = unix_time();
     root_inode.create("filea");
     root_inode.create("fileb");
     for name in root_inode.ls() {
@@ -113,6 +659,16 @@ fn efs_test() -> std::io::Result<()> {
     let mut buffer = [0u8; 233];
     let len = filea.read_at(0, &mut buffer);
     assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
+    // metadata
+    let metadata = filea.metadata();
+    assert_eq!(metadata.type_, easy_fs::DiskInodeType::File);
+    assert_eq!((metadata.mode, metadata.uid, metadata.gid), (0o644, 0, 0));
+    assert_eq!(metadata.size, greet_str.len());
+    assert!(metadata.mtime >= start && metadata.atime >= metadata.mtime);
+    assert!(root_inode.metadata().mtime >= start);
+    filea.chmod(0o100600);
+    assert_eq!(filea.metadata().mode, 0o600);
+    assert_eq!(root_inode.metadata().mode, 0o755);
 
     let mut random_str_test = |len: usize| {
         filea.clear();
@@ -147,5 +703,1445 @@ fn efs_test() -> std::io::Result<()> {
     random_str_test(1000 * BLOCK_SZ);
     random_str_test(2000 * BLOCK_SZ);
 
+    // directories
+    assert_eq!(root_inode.ls(), [".", "..", "filea", "fileb"]);
+    let dira = root_inode.mkdir("dira").unwrap();
+    assert!(dira.is_dir());
+    assert!(root_inode.mkdir("dira").is_none());
+    assert_eq!(dira.ls(), [".", ".."]);
+    let dirb = dira.mkdir("dirb").unwrap();
+    let filec = dirb.create("filec").unwrap();
+    filec.write_at(0, greet_str.as_bytes());
+    // absolute and relative paths, "." and ".."
+    let found = root_inode.find_path("/dira/dirb/filec").unwrap();
+    assert_eq!(found.inode_id(), filec.inode_id());
+    let found = dirb.find_path("../../dira/./dirb/filec").unwrap();
+    assert_eq!(found.inode_id(), filec.inode_id());
+    assert_eq!(dirb.find_path("/").unwrap().inode_id(), 0);
+    assert_eq!(root_inode.find_path("..").unwrap().inode_id(), 0);
+    assert!(root_inode.find_path("dira/filec").is_none());
+    assert!(root_inode.find_path("filea/x").is_none());
+    let (parent, name) = root_inode.find_parent("/dira/dirb/").unwrap();
+    assert_eq!((parent.inode_id(), name), (dira.inode_id(), "dirb"));
+    let (parent, name) = dirb.find_parent("filed").unwrap();
+    assert_eq!((parent.inode_id(), name), (dirb.inode_id(), "filed"));
+    assert!(root_inode.find_parent("/").is_none());
+    assert_eq!(dira.find_name(dirb.inode_id()).unwrap(), "dirb");
+    // dirents are read by offset, each giving the offset of the next one
+    let (name, _, _, next) = dira.read_dirent(0).unwrap();
+    assert_eq!(name, ".");
+    let (name, _, _, next) = dira.read_dirent(next).unwrap();
+    assert_eq!(name, "..");
+    let (name, inode_id, type_, next) = dira.read_dirent(next).unwrap();
+    assert_eq!(
+        (name, inode_id, type_),
+        (
+            String::from("dirb"),
+            dirb.inode_id(),
+            easy_fs::DiskInodeType::Directory
+        )
+    );
+    assert!(dira.read_dirent(next).is_none());
+    assert_eq!(next, dira.size());
+    assert!(dira.find_name(dira.inode_id()).is_none());
+    // link counts
+    assert_eq!(root_inode.link_count(), 3);
+    assert_eq!(dira.link_count(), 3);
+    assert_eq!(dirb.link_count(), 2);
+    assert_eq!(filec.link_count(), 1);
+    root_inode.link_inode("filec", &filec).unwrap();
+    assert_eq!(filec.link_count(), 2);
+    assert_eq!(root_inode.unlink("filec"), 0);
+    assert_eq!(filec.link_count(), 1);
+    // rename in place, across directories and over an existing file
+    assert_eq!(dirb.rename("filec", &dirb, "filed"), 0);
+    assert!(dirb.find("filec").is_none());
+    assert_eq!(dirb.find("filed").unwrap().inode_id(), filec.inode_id());
+    assert_eq!(dirb.rename("filed", &dira, "filec"), 0);
+    assert_eq!(dira.find("filec").unwrap().inode_id(), filec.inode_id());
+    let filee = dirb.create("filee").unwrap();
+    assert_eq!(dirb.rename("filee", &dira, "filec"), 0);
+    assert_eq!(dira.find("filec").unwrap().inode_id(), filee.inode_id());
+    assert!(dirb.find("filee").is_none());
+    assert_eq!(dira.rename("filec", &dirb, "filec"), 0);
+    // directories: "..", loops and non-empty targets
+    assert_eq!(dira.rename("dirb", &root_inode, "dirb"), 0);
+    let dirb = root_inode.find("dirb").unwrap();
+    assert_eq!(dirb.find("..").unwrap().inode_id(), 0);
+    assert_eq!(dira.link_count(), 2);
+    assert_eq!(root_inode.rename("dirb", &dirb, "dirc"), -1);
+    assert_eq!(root_inode.rename("dira", &root_inode, "dirb"), -1);
+    assert_eq!(root_inode.rename("dirb", &root_inode, "dira"), 0);
+    assert!(root_inode.find("dirb").is_none());
+    // the replaced directory no longer links to the root
+    assert_eq!(root_inode.link_count(), 3);
+    let dira = root_inode.find("dira").unwrap();
+    assert_eq!(dira.inode_id(), dirb.inode_id());
+    assert_eq!(root_inode.rename("dira", &root_inode, "filea"), -1);
+    assert_eq!(root_inode.mkdir("dira2").unwrap().link_count(), 2);
+    assert_eq!(root_inode.rename("dira2", &dira, "dirb"), 0);
+    let dirb = dira.find("dirb").unwrap();
+    assert_eq!(dirb.find("..").unwrap().inode_id(), dira.inode_id());
+    assert_eq!(dira.link_count(), 3);
+    assert_eq!(dira.rename("filec", &dirb, "filec"), 0);
+
+    // only empty directories can be removed
+    assert_eq!(dira.rmdir("dirb"), -1);
+    assert_eq!(dirb.unlink("filec"), 0);
+    assert_eq!(dira.rmdir("dirb"), 0);
+    assert!(root_inode.find_path("/dira/dirb").is_none());
+    assert_eq!(root_inode.rmdir("dira"), 0);
+    assert_eq!(root_inode.ls(), [".", "..", "filea", "fileb"]);
+    assert_eq!(root_inode.link_count(), 2);
+
+    // symbolic links, relative to the directory holding them or absolute
+    let dira = root_inode.mkdir("dira").unwrap();
+    let filec = dira.create("filec").unwrap();
+    let linka = root_inode.symlink("linka", "dira/filec").unwrap();
+    assert!(linka.is_symlink() && !filec.is_symlink());
+    assert_eq!(linka.readlink().unwrap(), "dira/filec");
+    assert!(filec.readlink().is_none());
+    let metadata = linka.metadata();
+    assert_eq!(metadata.type_, easy_fs::DiskInodeType::Symlink);
+    assert_eq!((metadata.mode, metadata.size), (0o777, "dira/filec".len()));
+    assert!(root_inode.symlink("linka", "filea").is_none());
+    assert!(root_inode.symlink("linkb", "").is_none());
+    let found = root_inode.find_path("linka").unwrap();
+    assert_eq!(found.inode_id(), filec.inode_id());
+    root_inode.symlink("linkd", "/dira").unwrap();
+    let found = root_inode.find_path("/linkd/filec").unwrap();
+    assert_eq!(found.inode_id(), filec.inode_id());
+    dira.symlink("up", "..").unwrap();
+    let found = root_inode.find_path("dira/up/linka").unwrap();
+    assert_eq!(found.inode_id(), filec.inode_id());
+    let (parent, name) = root_inode.find_parent("linkd/up").unwrap();
+    assert_eq!((parent.inode_id(), name), (dira.inode_id(), "up"));
+    // dangling links, loops and the limit of links followed
+    root_inode.symlink("dangling", "nothing").unwrap();
+    assert!(root_inode.find_path("dangling").is_none());
+    root_inode.symlink("loop1", "loop2").unwrap();
+    root_inode.symlink("loop2", "loop1").unwrap();
+    assert!(root_inode.find_path("loop1").is_none());
+    for i in 0..=easy_fs::SYMLOOP_MAX {
+        let target = match i {
+            0 => String::from("filea"),
+            _ => format!("chain{}", i - 1),
+        };
+        root_inode.symlink(&format!("chain{}", i), &target).unwrap();
+    }
+    let last = format!("chain{}", easy_fs::SYMLOOP_MAX - 1);
+    let found = root_inode.find_path(&last).unwrap();
+    assert_eq!(found.inode_id(), filea.inode_id());
+    assert!(root_inode
+        .find_path(&format!("chain{}", easy_fs::SYMLOOP_MAX))
+        .is_none());
+    // removing a link keeps its target
+    assert_eq!(root_inode.unlink("linka"), 0);
+    assert_eq!(dira.find("filec").unwrap().inode_id(), filec.inode_id());
+    for name in ["linkd", "dangling", "loop1", "loop2"] {
+        assert_eq!(root_inode.unlink(name), 0);
+    }
+    for i in 0..=easy_fs::SYMLOOP_MAX {
+        assert_eq!(root_inode.unlink(&format!("chain{}", i)), 0);
+    }
+    assert_eq!(dira.unlink("up"), 0);
+    assert_eq!(dira.unlink("filec"), 0);
+    assert_eq!(root_inode.rmdir("dira"), 0);
+    assert_eq!(root_inode.ls(), [".", "..", "filea", "fileb"]);
+
+    // a small block cache evicts blocks but keeps the data intact
+    let device: Arc<dyn BlockDevice> = block_file.clone();
+    let before = easy_fs::block_cache_stats();
+    easy_fs::set_block_cache_capacity(&device, 8);
+    random_str_test(100 * BLOCK_SZ);
+    let stats = easy_fs::block_cache_stats();
+    assert!(stats.hits > before.hits && stats.misses > before.misses);
+    assert!(stats.evictions > before.evictions);
+    // blocks of a transaction modifying more blocks than the cache holds are
+    // evicted through the journal, and the cache stays within its capacity
+    easy_fs::set_block_cache_capacity(&device, 4);
+    let dirb = root_inode.mkdir("dirb").unwrap();
+    for i in 0..40 {
+        dirb.create(&format!("file{}", i)).unwrap();
+    }
+    assert_eq!(dirb.ls().len(), 2 + 40);
+    for i in 0..40 {
+        assert_eq!(dirb.unlink(&format!("file{}", i)), 0);
+    }
+    assert_eq!(root_inode.rmdir("dirb"), 0);
+    let spilled = easy_fs::block_cache_stats();
+    assert!(spilled.spills > stats.spills);
+    assert_eq!(spilled.overflows, stats.overflows);
+    assert!(efs.lock().fsck(false).is_empty());
+    easy_fs::set_block_cache_capacity(&device, easy_fs::BLOCK_CACHE_SIZE);
+
+    // the block cache is write-back, file data only reaches the disk when synced
+    let read_on_disk = |name: &str| {
+        // a new device is not served from the cache of `block_file`
+        let block_file = Arc::new(BlockFile(Mutex::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("target/fs.img")
+                .unwrap(),
+        )));
+        let efs = EasyFileSystem::open(block_file, easy_fs::BLOCK_CACHE_SIZE);
+        let inode = EasyFileSystem::root_inode(&efs).find(name).unwrap();
+        let mut buf = vec![0u8; inode.size()];
+        inode.read_at(0, &mut buf);
+        String::from_utf8(buf).unwrap()
+    };
+    let fileb = root_inode.find("fileb").unwrap();
+    // growing a file commits its new size at once
+    fileb.write_at(0, greet_str.as_bytes());
+    assert_eq!(read_on_disk("fileb").len(), greet_str.len());
+    fileb.sync();
+    assert_eq!(read_on_disk("fileb"), greet_str);
+    fileb.write_at(0, greet_str.to_uppercase().as_bytes());
+    assert_eq!(read_on_disk("fileb"), greet_str);
+    fileb.sync();
+    assert_eq!(read_on_disk("fileb"), greet_str.to_uppercase());
+
+    Ok(())
+}
+
+/// A block device that loses power after a number of writes.
+/// Later writes only reach a volatile overlay, so the image keeps
+/// exactly what was written before the power loss.
+#[cfg(test)]
+struct CrashingBlockFile {
+    file: BlockFile,
+    /// writes that still reach the image
+    writes_left: Mutex<usize>,
+    /// writes made so far
+    writes: Mutex<usize>,
+    overlay: Mutex<std::collections::HashMap<usize, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl CrashingBlockFile {
+    fn open(path: &str, writes_left: usize) -> Arc<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        Arc::new(Self {
+            file: BlockFile(Mutex::new(file)),
+            writes_left: Mutex::new(writes_left),
+            writes: Mutex::new(0),
+            overlay: Mutex::new(std::collections::HashMap::new()),
+        })
+    }
+
+    fn power_off(&self) {
+        *self.writes_left.lock().unwrap() = 0;
+    }
+
+    fn writes(&self) -> usize {
+        *self.writes.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl BlockDevice for CrashingBlockFile {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        match self.overlay.lock().unwrap().get(&block_id) {
+            Some(block) => buf.copy_from_slice(block),
+            None => self.file.read_block(block_id, buf),
+        }
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        *self.writes.lock().unwrap() += 1;
+        let mut writes_left = self.writes_left.lock().unwrap();
+        if *writes_left > 0 {
+            *writes_left -= 1;
+            self.file.write_block(block_id, buf);
+        } else {
+            self.overlay.lock().unwrap().insert(block_id, buf.to_vec());
+        }
+    }
+}
+
+/// Operations interrupted by power loss in `efs_crash_test`
+#[cfg(test)]
+fn crash_scenario(efs: &Arc<easy_fs::Inode>) {
+    let root = efs;
+    let d = root.find("d").unwrap();
+    let a = root.create("a").unwrap();
+    a.write_at(0, &[b'a'; 2 * BLOCK_SZ]);
+    let e = d.mkdir("e").unwrap();
+    assert_eq!(root.rename("a", &e, "b"), 0);
+    root.symlink("l", "d/e/b").unwrap();
+    let big = root.create("big").unwrap();
+    big.write_at(0, &vec![b'b'; 150 * BLOCK_SZ]);
+    big.clear();
+    assert_eq!(root.unlink("big"), 0);
+}
+
+/// Check "." and ".." of directories under `dir` and collect the inodes in use
+#[cfg(test)]
+fn walk_tree(dir: &easy_fs::Inode, parent_id: u32, inodes: &mut Vec<u32>) {
+    // the dirents end right at the size
+    let mut offset = 0;
+    while let Some((_, _, _, next)) = dir.read_dirent(offset) {
+        offset = next;
+    }
+    assert_eq!(offset, dir.size());
+    assert_eq!(dir.find(".").unwrap().inode_id(), dir.inode_id());
+    assert_eq!(dir.find("..").unwrap().inode_id(), parent_id);
+    inodes.push(dir.inode_id());
+    for name in dir.ls() {
+        if name == "." || name == ".." {
+            continue;
+        }
+        let inode = dir.find(&name).unwrap();
+        if inode.is_dir() {
+            walk_tree(&inode, dir.inode_id(), inodes);
+        } else {
+            inodes.push(inode.inode_id());
+        }
+    }
+}
+
+#[cfg(test)]
+fn read_all(inode: &easy_fs::Inode) -> Vec<u8> {
+    let mut buf = vec![0u8; inode.size()];
+    assert_eq!(inode.read_at(0, &mut buf), buf.len());
+    buf
+}
+
+#[test]
+fn efs_crash_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    let image = "target/crash.img";
+    let keep_data: Vec<u8> = (0..3 * BLOCK_SZ).map(|i| i as u8).collect();
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(image)?;
+        f.set_len(4096 * 512)?;
+    }
+    let block_file = CrashingBlockFile::open(image, usize::MAX);
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("keep").unwrap().write_at(0, &keep_data);
+    root_inode.mkdir("d").unwrap();
+    root_inode.sync();
+    block_file.power_off();
+    let base = std::fs::read(image)?;
+
+    let mut total_writes = None;
+    let mut budget = 0;
+    while total_writes.is_none_or(|total| budget <= total) {
+        std::fs::write(image, &base)?;
+        let block_file = CrashingBlockFile::open(image, budget);
+        let efs = EasyFileSystem::open(block_file.clone(), easy_fs::BLOCK_CACHE_SIZE);
+        crash_scenario(&Arc::new(EasyFileSystem::root_inode(&efs)));
+        block_file.power_off();
+        total_writes.get_or_insert(block_file.writes());
+
+        // power on again, the journal is replayed
+        let block_file = CrashingBlockFile::open(image, usize::MAX);
+        let efs = EasyFileSystem::open(block_file.clone(), easy_fs::BLOCK_CACHE_SIZE);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let mut inodes = Vec::new();
+        walk_tree(&root_inode, 0, &mut inodes);
+        let keep = root_inode.find("keep").unwrap();
+        assert_eq!(read_all(&keep), keep_data);
+        // every operation is either done or not at all
+        let a = root_inode.find("a");
+        let e = root_inode.find_path("d/e");
+        let b = e.as_ref().and_then(|e| e.find("b"));
+        let l = root_inode.find("l");
+        let big = root_inode.find("big");
+        let file_a = a.as_ref().or(b.as_ref());
+        let a_data = file_a.map(|file| read_all(file));
+        match (
+            a.is_some(),
+            e.is_some(),
+            b.is_some(),
+            l.is_some(),
+            big.is_some(),
+        ) {
+            (false, false, false, false, false) => {}
+            (true, false, _, false, false) => {
+                let len = a_data.as_ref().unwrap().len();
+                assert!(len == 0 || len == 2 * BLOCK_SZ);
+            }
+            (true, true, false, false, false) | (false, true, true, false, false) => {
+                assert_eq!(a_data.as_deref().unwrap(), [b'a'; 2 * BLOCK_SZ]);
+            }
+            (false, true, true, true, _) => {
+                assert_eq!(a_data.as_deref().unwrap(), [b'a'; 2 * BLOCK_SZ]);
+                let target = root_inode.find_path("l").unwrap();
+                assert_eq!(target.inode_id(), b.as_ref().unwrap().inode_id());
+                if let Some(big) = big {
+                    assert!(big.size() <= 150 * BLOCK_SZ);
+                }
+            }
+            state => panic!("Inconsistent state {:?} after {} writes", state, budget),
+        }
+        // only the inode of "big" is left after it is unlinked
+        assert!(efs
+            .lock()
+            .fsck(false)
+            .iter()
+            .all(|problem| matches!(problem, easy_fs::FsckProblem::OrphanInode { .. })));
+        // the bitmaps agree with the tree
+        let probe = root_inode.create("probe").unwrap();
+        assert!(!inodes.contains(&probe.inode_id()));
+        probe.write_at(0, &[0xab; 40 * BLOCK_SZ]);
+        assert_eq!(read_all(&keep), keep_data);
+        if let Some(file_a) = file_a {
+            assert_eq!(read_all(file_a), a_data.unwrap());
+        }
+        block_file.power_off();
+        budget += 1;
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_fsck_test() -> std::io::Result<()> {
+    use easy_fs::FsckProblem;
+    let _guard = TEST_LOCK.lock().unwrap();
+    let image = "target/fsck.img";
+    let block_file = open_image_file(image);
+    block_file.0.lock().unwrap().set_len(4096 * 512)?;
+    let efs = EasyFileSystem::create(block_file.clone(), 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert!(efs.lock().fsck(false).is_empty());
+    let d = root_inode.mkdir("d").unwrap();
+    let f_data = [b'f'; 3 * BLOCK_SZ];
+    d.create("f").unwrap().write_at(0, &f_data);
+    let x = root_inode.create("x").unwrap();
+    let g = root_inode.create("g").unwrap();
+    g.write_at(0, &[b'g'; 30 * BLOCK_SZ]);
+    root_inode.link_inode("d2", &d).unwrap();
+    // "g" is unlinked while open, and the image is opened again as after a crash,
+    // which frees it as it is recorded as an orphan in the super block
+    g.open();
+    assert_eq!(root_inode.unlink("g"), 0);
+    // "d2" is not counted as a link of "d"
+    let hard_link = [
+        FsckProblem::DirectoryHardLink {
+            dir_id: 0,
+            name: String::from("d2"),
+            inode_id: d.inode_id(),
+        },
+        FsckProblem::BadLinkCount {
+            inode_id: d.inode_id(),
+            found: 3,
+            expected: 2,
+        },
+    ];
+    assert_eq!(efs.lock().fsck(false), hard_link);
+    root_inode.sync();
+    let reopened = EasyFileSystem::open(open_image_file(image), easy_fs::BLOCK_CACHE_SIZE);
+    assert_eq!(reopened.lock().fsck(false), hard_link);
+    assert_eq!(
+        reopened.lock().statfs().free_blocks,
+        efs.lock().statfs().free_blocks + 30
+    );
+    drop(reopened);
+
+    // overwrite the dirent of "x" with a copy of the one of "d",
+    // which are both 6 bytes after the 6 bytes of "." and the 7 bytes of ".."
+    root_inode.sync();
+    let root_block = efs.lock().get_data_block_id(0) as usize;
+    let mut block = [0u8; BLOCK_SZ];
+    block_file.read_block(root_block, &mut block);
+    block.copy_within(13..19, 19);
+    block_file.write_block(root_block, &block);
+    // a new device is not served from the stale cache of `block_file`
+    let efs = EasyFileSystem::open(open_image_file(image), easy_fs::BLOCK_CACHE_SIZE);
+    let problems = efs.lock().fsck(true);
+    assert!(problems.contains(&FsckProblem::DuplicateDirent {
+        dir_id: 0,
+        name: String::from("d"),
+    }));
+    assert!(problems.contains(&FsckProblem::OrphanInode {
+        inode_id: x.inode_id()
+    }));
+    assert!(problems.iter().all(|problem| problem.is_repairable()));
+    assert!(efs.lock().fsck(false).is_empty());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls(), [".", "..", "d"]);
+    let f = root_inode.find_path("d/f").unwrap();
+    let mut buf = [0u8; 3 * BLOCK_SZ];
+    assert_eq!(f.read_at(0, &mut buf), buf.len());
+    assert_eq!(buf, f_data);
+    // the freed blocks can be allocated again
+    root_inode
+        .create("h")
+        .unwrap()
+        .write_at(0, &[b'h'; 30 * BLOCK_SZ]);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // point the extent of the root directory out of the image,
+    // its dirents are not read through it nor repaired
+    root_inode.sync();
+    let (inode_block, inode_offset) = efs.lock().get_disk_inode_pos(0);
+    block_file.read_block(inode_block as usize, &mut block);
+    // the start of the first extent follows the size and the logical block
+    let start = inode_offset + size_of::<u64>() + size_of::<u32>();
+    block[start..start + 4].copy_from_slice(&0x00FF_FFFFu32.to_ne_bytes());
+    block_file.write_block(inode_block as usize, &block);
+    for repair in [false, true] {
+        let problems = EasyFileSystem::open(open_image_file(image), easy_fs::BLOCK_CACHE_SIZE)
+            .lock()
+            .fsck(repair);
+        assert!(problems.contains(&FsckProblem::BadBlockPointer {
+            inode_id: 0,
+            block_id: 0x00FF_FFFF,
+        }));
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_cli_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    let image = "target/cli.img";
+    {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(image)?;
+        f.set_len(4096 * 512)?;
+        let block_file = Arc::new(BlockFile(Mutex::new(f)));
+        let efs = EasyFileSystem::create(block_file, 4096, 1);
+        EasyFileSystem::root_inode(&efs).mkdir("d").unwrap();
+        easy_fs::block_cache_sync_all();
+    }
+    let run = |args: &[&str]| -> std::io::Result<String> {
+        let matches = cli().get_matches_from(["easy-fs-fuse"].iter().chain(args));
+        let (command, matches) = matches.subcommand();
+        let mut out = Vec::new();
+        easy_fs_command(command, matches.unwrap(), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    };
+    let statfs = |line: usize| -> Vec<u32> {
+        let df = run(&["df", image]).unwrap();
+        let fields: Vec<&str> = df.lines().nth(line).unwrap().split_whitespace().collect();
+        fields[1..4]
+            .iter()
+            .map(|field| field.parse().unwrap())
+            .collect()
+    };
+    let (blocks, inodes) = (statfs(1), statfs(2));
+    // "/" and "d" have a block of dirents and the root and a leaf of their name index
+    assert_eq!((blocks[1], inodes[1]), (6, 2));
+
+    run(&["put", image, "Cargo.toml"])?;
+    run(&["put", image, "src/main.rs", "/d"])?;
+    run(&["put", image, "Cargo.toml", "/d/copy"])?;
+    let cargo_toml = std::fs::read_to_string("Cargo.toml")?;
+    assert_eq!(run(&["cat", image, "/Cargo.toml"])?, cargo_toml);
+    assert_eq!(run(&["cat", image, "d/copy"])?, cargo_toml);
+    let ls = run(&["ls", image, "/d"])?;
+    let names: Vec<&str> = ls
+        .lines()
+        .map(|line| line.split_whitespace().last().unwrap())
+        .collect();
+    assert_eq!(names, [".", "..", "main.rs", "copy"]);
+    assert!(ls.lines().next().unwrap().starts_with("d0755"));
+    run(&["get", image, "/d/main.rs", "target/main.rs"])?;
+    assert_eq!(
+        std::fs::read("target/main.rs")?,
+        std::fs::read("src/main.rs")?
+    );
+    let stat = run(&["stat", image, "/d/main.rs"])?;
+    assert!(stat.contains("Type: regular file"));
+    assert!(stat.contains(&format!(
+        "Size: {}",
+        std::fs::metadata("src/main.rs")?.len()
+    )));
+    // a shorter file replaces the data
+    run(&["put", image, "Cargo.toml", "/d/main.rs"])?;
+    assert_eq!(run(&["cat", image, "/d/main.rs"])?, cargo_toml);
+
+    let not_found = run(&["cat", image, "/nope"]).unwrap_err();
+    assert_eq!(not_found.kind(), ErrorKind::NotFound);
+    assert!(run(&["rm", image, "/d"]).is_err());
+    run(&["rm", image, "/d/main.rs"])?;
+    run(&["rm", image, "/d/copy"])?;
+    // "." and ".." of the empty directory are not removed
+    assert!(run(&["rm", image, "/d/."]).is_err());
+    run(&["rm", image, "/d"])?;
+    run(&["rm", image, "/Cargo.toml"])?;
+    assert_eq!(run(&["ls", image])?.lines().count(), 2);
+    // everything removed is freed, "d" included
+    assert_eq!((statfs(1)[1], statfs(2)[1]), (blocks[1] - 3, inodes[1] - 1));
+    let efs = open_image(image)?;
+    assert!(efs.lock().fsck(false).is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_pack_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    assert_eq!(parse_size("16M"), Some(16 << 20));
+    assert_eq!(parse_size("4096"), Some(4096));
+    assert_eq!(parse_size("M"), None);
+    let src = Path::new("target/pack-src");
+    let _ = std::fs::remove_dir_all(src);
+    std::fs::create_dir_all(src.join("bin"))?;
+    std::fs::create_dir_all(src.join("etc/empty"))?;
+    std::fs::write(src.join("bin/app.elf"), [0x7f, b'E', b'L', b'F'])?;
+    std::fs::write(src.join("etc/a.b.conf"), "a = b")?;
+    std::os::unix::fs::symlink("../bin/app", src.join("etc/app"))?;
+    std::fs::create_dir_all("target/pack")?;
+    let pack = || -> std::io::Result<Vec<u8>> {
+        let matches = cli().get_matches_from([
+            "easy-fs-fuse",
+            "-s",
+            "target/pack-src",
+            "-t",
+            "target/pack",
+            "--size",
+            "2M",
+            "--inodes",
+            "8000",
+            "-l",
+            "sh:bin/app",
+        ]);
+        easy_fs_pack(&matches)?;
+        std::fs::read("target/pack/fs.img")
+    };
+    let image = pack()?;
+    assert_eq!(image.len(), 2 << 20);
+    // packing again gives the same image
+    assert!(image == pack()?);
+
+    let efs = open_image("target/pack/fs.img")?;
+    assert!(efs.lock().fsck(false).is_empty());
+    assert_eq!(efs.lock().statfs().total_inodes, 8192);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls(), [".", "..", "bin", "etc", "sh"]);
+    assert_eq!(
+        root_inode.find_path("etc").unwrap().ls(),
+        [".", "..", "a.b.conf", "app", "empty"]
+    );
+    let app = root_inode.find_path("bin/app").unwrap();
+    assert_eq!(read_inode(&app), [0x7f, b'E', b'L', b'F']);
+    for link in ["sh", "etc/app"] {
+        assert_eq!(
+            root_inode.find_path(link).unwrap().inode_id(),
+            app.inode_id()
+        );
+    }
+    assert_eq!(app.metadata().mtime, pack_time());
+    Ok(())
+}
+
+#[test]
+fn efs_large_file_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open("target/large.img")?;
+    f.set_len(40960 * 512)?;
+    let device: Arc<dyn BlockDevice> = Arc::new(BlockFile(Mutex::new(f)));
+    // the data blocks are mapped by a tree of index blocks without extents
+    let efs = EasyFileSystem::create_with_features(
+        device.clone(),
+        40960,
+        1,
+        easy_fs::FEATURE_LONG_NAMES,
+    );
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("large").unwrap();
+    let used_blocks = || {
+        let statfs = efs.lock().statfs();
+        statfs.total_blocks - statfs.free_blocks
+    };
+    // past the direct, indirect1 and indirect2 blocks into indirect3
+    let data_blocks = 20 + 128 + 128 * 128 + 300;
+    let data: Vec<u8> = (0..data_blocks * BLOCK_SZ + 77)
+        .map(|_| rand::random::<u8>())
+        .collect();
+    assert_eq!(file.write_at(0, &data), data.len());
+    assert_eq!(file.size(), data.len());
+    // the root directory, the data blocks with the last partial one, indirect1,
+    // indirect2 with its 128 indirect1 blocks and indirect3 with 1 indirect2 and 3 indirect1 blocks
+    assert_eq!(
+        used_blocks(),
+        1 + data_blocks as u32 + 1 + 1 + 129 + 1 + 1 + 3
+    );
+    let read_back = |file: &Inode| {
+        let mut read_data = Vec::new();
+        let mut buffer = [0u8; 4093];
+        loop {
+            let len = file.read_at(read_data.len(), &mut buffer);
+            if len == 0 {
+                break;
+            }
+            read_data.extend_from_slice(&buffer[..len]);
+        }
+        read_data
+    };
+    assert!(read_back(&file) == data);
+    // overwrite across the boundary of indirect2 and indirect3
+    let offset = (20 + 128 + 128 * 128) * BLOCK_SZ - 1000;
+    let patch = [0xa5u8; 3000];
+    assert_eq!(file.write_at(offset, &patch), patch.len());
+    let mut patched = data.clone();
+    patched[offset..offset + patch.len()].copy_from_slice(&patch);
+    assert!(read_back(&file) == patched);
+    assert!(efs.lock().fsck(false).is_empty());
+    // nothing is written past the max size
+    assert_eq!(file.write_at(easy_fs::MAX_FILE_SIZE as usize, &patch), 0);
+    // freed in steps that fit in the journal and in a small cache
+    easy_fs::set_block_cache_capacity(&device, 8);
+    let overflows = easy_fs::block_cache_stats().overflows;
+    file.clear();
+    assert_eq!(easy_fs::block_cache_stats().overflows, overflows);
+    easy_fs::set_block_cache_capacity(&device, easy_fs::BLOCK_CACHE_SIZE);
+    assert_eq!(used_blocks(), 1);
+    Ok(())
+}
+
+#[test]
+fn efs_long_name_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    let image = "target/long_name.img";
+    let block_file = open_image_file(image);
+    block_file.0.lock().unwrap().set_len(4096 * 512)?;
+    let efs = EasyFileSystem::create(block_file, 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.name_limit(), easy_fs::NAME_LENGTH_LIMIT);
+    let long_name = "n".repeat(easy_fs::NAME_LENGTH_LIMIT);
+    let file = root_inode.create(&long_name).unwrap();
+    assert!(root_inode.create(&format!("{}n", long_name)).is_none());
+    assert_eq!(
+        root_inode.find(&long_name).unwrap().inode_id(),
+        file.inode_id()
+    );
+    assert_eq!(root_inode.rename(&long_name, &root_inode, "short"), 0);
+    assert_eq!(root_inode.rename("short", &root_inode, &long_name), 0);
+    assert_eq!(root_inode.ls(), [".", "..", long_name.as_str()]);
+
+    // dirents spanning several blocks are kept packed as they are unlinked,
+    // the last one is moved into the place of an unlinked one
+    let used_blocks = || {
+        let statfs = efs.lock().statfs();
+        statfs.total_blocks - statfs.free_blocks
+    };
+    let dir = root_inode.mkdir("d").unwrap();
+    let empty_size = dir.size();
+    let blocks = used_blocks();
+    let names: Vec<String> = (0..20).map(|i| format!("{:0>200}", i)).collect();
+    for name in names.iter() {
+        dir.create(name).unwrap();
+    }
+    assert!(used_blocks() >= blocks + 8);
+    for name in names.iter().step_by(2) {
+        assert_eq!(dir.remove(name), 0);
+    }
+    let kept: Vec<&str> = names
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .map(String::as_str)
+        .collect();
+    let mut listed = dir.ls().split_off(2);
+    listed.sort();
+    assert_eq!(listed, kept);
+    assert!(efs.lock().fsck(false).is_empty());
+    for name in kept {
+        assert_eq!(dir.remove(name), 0);
+    }
+    assert_eq!(dir.size(), empty_size);
+    assert_eq!(used_blocks(), blocks);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // unlinking the first of more dirents than the journal holds modifies a few blocks only
+    let names: Vec<String> = (0..200).map(|i| format!("{:0>200}", i)).collect();
+    for name in names.iter() {
+        dir.create(name).unwrap();
+    }
+    assert!(dir.size() > easy_fs::JOURNAL_BLOCKS as usize * BLOCK_SZ);
+    assert_eq!(dir.remove(&names[0]), 0);
+    dir.create("short").unwrap();
+    // a dirent as long as the last one is not is left deleted until a new one fits in its place
+    let size = dir.size();
+    assert_eq!(dir.remove(&names[1]), 0);
+    assert_eq!(dir.size(), size);
+    assert!(dir.find(&names[1]).is_none());
+    assert_eq!(dir.ls().len(), 2 + 199);
+    assert!(efs.lock().fsck(false).is_empty());
+    dir.create("new").unwrap();
+    assert_eq!(dir.size(), size);
+    assert!(dir.find("new").is_some());
+    assert!(efs.lock().fsck(false).is_empty());
+    for name in names[2..].iter().map(String::as_str).chain(["short", "new"]) {
+        assert_eq!(dir.remove(name), 0);
+    }
+    assert_eq!(dir.ls(), [".", ".."]);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // an image without variable-length dirents keeps the short names
+    let block_file = open_image_file(image);
+    let efs = EasyFileSystem::create_with_features(block_file, 4096, 1, 0);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.name_limit(), easy_fs::SHORT_NAME_LENGTH_LIMIT);
+    let short_name = "s".repeat(easy_fs::SHORT_NAME_LENGTH_LIMIT);
+    root_inode
+        .create(&short_name)
+        .unwrap()
+        .write_at(0, b"short");
+    assert!(root_inode.create(&format!("{}s", short_name)).is_none());
+    root_inode.create("x").unwrap();
+    assert_eq!(root_inode.size(), 4 * 32);
+    assert_eq!(root_inode.remove(&short_name), 0);
+    assert_eq!(root_inode.size(), 3 * 32);
+    root_inode
+        .create(&short_name)
+        .unwrap()
+        .write_at(0, b"short");
+    root_inode.sync();
+    let efs = EasyFileSystem::open(open_image_file(image), easy_fs::BLOCK_CACHE_SIZE);
+    assert!(efs.lock().fsck(false).is_empty());
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls(), [".", "..", "x", short_name.as_str()]);
+    assert_eq!(read_all(&root_inode.find(&short_name).unwrap()), b"short");
+    Ok(())
+}
+
+#[test]
+fn efs_extent_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    let image = "target/extent.img";
+    let block_file = open_image_file(image);
+    block_file.0.lock().unwrap().set_len(8192 * 512)?;
+    let efs = EasyFileSystem::create(block_file, 8192, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used_blocks = || {
+        let statfs = efs.lock().statfs();
+        statfs.total_blocks - statfs.free_blocks
+    };
+    // a file written at once is a single run, with no index block,
+    // after the 3 blocks of the root directory and its name index
+    let data: Vec<u8> = (0..3000 * BLOCK_SZ + 77)
+        .map(|_| rand::random::<u8>())
+        .collect();
+    let large = root_inode.create("large").unwrap();
+    assert_eq!(large.write_at(0, &data), data.len());
+    assert_eq!(used_blocks(), 3 + 3001);
+    assert!(read_all(&large) == data);
+
+    // files growing in turn are fragmented, past the inline extents
+    // into an index block and two extent blocks each
+    let a = root_inode.create("a").unwrap();
+    let b = root_inode.create("b").unwrap();
+    for i in 0..60 {
+        a.write_at(i * BLOCK_SZ, &[i as u8; BLOCK_SZ]);
+        b.write_at(i * BLOCK_SZ, &[!(i as u8); BLOCK_SZ]);
+    }
+    assert_eq!(used_blocks(), 3 + 3001 + 2 * (60 + 3));
+    let a_data = read_all(&a);
+    let b_data = read_all(&b);
+    for i in 0..60 {
+        assert_eq!(
+            a_data[i * BLOCK_SZ..(i + 1) * BLOCK_SZ],
+            [i as u8; BLOCK_SZ]
+        );
+        assert_eq!(
+            b_data[i * BLOCK_SZ..(i + 1) * BLOCK_SZ],
+            [!(i as u8); BLOCK_SZ]
+        );
+    }
+    assert!(efs.lock().fsck(false).is_empty());
+    assert_eq!(root_inode.remove("a"), 0);
+    assert_eq!(used_blocks(), 3 + 3001 + 60 + 3);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // "c" is small enough for the inline extents wherever its blocks are
+    let c = root_inode.create("c").unwrap();
+    c.write_at(0, &[b'c'; 10 * BLOCK_SZ]);
+    assert_eq!(used_blocks(), 3 + 3001 + 60 + 3 + 10);
+    b.clear();
+    assert_eq!(used_blocks(), 3 + 3001 + 10);
+    root_inode.sync();
+
+    // the free counts are rebuilt from the bitmap of a reopened image
+    let efs = EasyFileSystem::open(open_image_file(image), easy_fs::BLOCK_CACHE_SIZE);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert!(read_all(&root_inode.find("large").unwrap()) == data);
+    let b = root_inode.find("b").unwrap();
+    b.write_at(0, &[b'b'; 100 * BLOCK_SZ]);
+    assert_eq!(read_all(&b), [b'b'; 100 * BLOCK_SZ]);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // a file written every other block stops growing at 6 inline extents
+    // and 32 extent blocks, and the writes past them fail
+    let s = root_inode.create("s").unwrap();
+    let mut head = [b'h'; 3 * BLOCK_SZ];
+    s.write_at(0, &head);
+    let written = (0..2000)
+        .take_while(|i| s.write_at((4 + 2 * i) * BLOCK_SZ, &[b's'; BLOCK_SZ]) == BLOCK_SZ)
+        .count();
+    assert_eq!(1 + written, 6 + 32 * (BLOCK_SZ / 12));
+    assert!(!s.fallocate(3 * BLOCK_SZ as u64, BLOCK_SZ as u64, false));
+    // nor can an extent be split in two
+    assert!(!s.punch_hole(BLOCK_SZ as u64, BLOCK_SZ as u64));
+    head.fill(0);
+    assert_eq!(s.read_at(0, &mut head), head.len());
+    assert_eq!(head, [b'h'; 3 * BLOCK_SZ]);
+    assert!(efs.lock().fsck(false).is_empty());
+    assert_eq!(root_inode.remove("s"), 0);
+    assert!(efs.lock().fsck(false).is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_sparse_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    // the same file on an image with extents and on one with index blocks,
+    // where the block at 1000 takes indirect2 and an indirect1 block
+    for (features, index_blocks) in [
+        (easy_fs::FEATURE_LONG_NAMES | easy_fs::FEATURE_EXTENTS, 0),
+        (easy_fs::FEATURE_LONG_NAMES, 2),
+    ] {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/sparse.img")?;
+        f.set_len(4096 * 512)?;
+        let block_file = Arc::new(BlockFile(Mutex::new(f)));
+        let efs = EasyFileSystem::create_with_features(block_file, 4096, 1, features);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let used_blocks = || {
+            let statfs = efs.lock().statfs();
+            statfs.total_blocks - statfs.free_blocks
+        };
+        let file = root_inode.create("sparse").unwrap();
+        let data: Vec<u8> = (0..20 * BLOCK_SZ).map(|_| rand::random::<u8>()).collect();
+        let tail: Vec<u8> = (0..BLOCK_SZ).map(|_| rand::random::<u8>()).collect();
+        assert_eq!(file.write_at(0, &data), data.len());
+        // writing past the end leaves a hole that reads as zeros
+        let offset = 1000 * BLOCK_SZ + 10;
+        assert_eq!(file.write_at(offset, &tail), tail.len());
+        let mut expected = data.clone();
+        expected.resize(offset, 0);
+        expected.extend_from_slice(&tail);
+        assert_eq!(file.size(), expected.len());
+        assert!(read_all(&file) == expected);
+        assert_eq!(used_blocks(), 1 + 20 + 2 + index_blocks);
+        assert!(efs.lock().fsck(false).is_empty());
+
+        // the blocks partly in the hole are zeroed, the others freed,
+        // splitting the extent of the first 20 blocks
+        let (start, len) = (3 * BLOCK_SZ + 100, 10 * BLOCK_SZ);
+        file.punch_hole(start as u64, len as u64);
+        expected[start..start + len].fill(0);
+        assert!(read_all(&file) == expected);
+        assert_eq!(used_blocks(), 1 + 11 + 2 + index_blocks);
+        file.punch_hole(start as u64, len as u64);
+        assert_eq!(used_blocks(), 1 + 11 + 2 + index_blocks);
+        assert!(efs.lock().fsck(false).is_empty());
+
+        // fallocate maps the holes, past the end too when the size is kept
+        assert!(file.fallocate(5 * BLOCK_SZ as u64, 2 * BLOCK_SZ as u64, false));
+        assert_eq!(used_blocks(), 1 + 13 + 2 + index_blocks);
+        let size = expected.len() as u64;
+        assert!(file.fallocate(size, 3 * BLOCK_SZ as u64, true));
+        assert_eq!(file.size(), expected.len());
+        assert_eq!(used_blocks(), 1 + 16 + 2 + index_blocks);
+        assert!(efs.lock().fsck(false).is_empty());
+        assert!(file.fallocate(size, 100, false));
+        expected.resize(expected.len() + 100, 0);
+        assert_eq!(file.size(), expected.len());
+        assert_eq!(used_blocks(), 1 + 16 + 2 + index_blocks);
+        assert!(read_all(&file) == expected);
+        assert!(!file.fallocate(easy_fs::MAX_FILE_SIZE, 1, false));
+
+        // punching everything frees the index blocks too, the size is unchanged
+        file.punch_hole(0, easy_fs::MAX_FILE_SIZE);
+        assert_eq!(used_blocks(), 1);
+        assert_eq!(read_all(&file), vec![0u8; expected.len()]);
+        assert!(efs.lock().fsck(false).is_empty());
+        assert_eq!(file.write_at(7 * BLOCK_SZ, &tail), tail.len());
+        expected.fill(0);
+        expected[7 * BLOCK_SZ..8 * BLOCK_SZ].copy_from_slice(&tail);
+        assert!(read_all(&file) == expected);
+        assert_eq!(used_blocks(), 2);
+        assert_eq!(root_inode.remove("sparse"), 0);
+        assert_eq!(used_blocks(), 1);
+        assert!(efs.lock().fsck(false).is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_enospc_test() -> std::io::Result<()> {
+    use easy_fs::FsckProblem;
+    let _guard = TEST_LOCK.lock().unwrap();
+    let image = "target/enospc.img";
+    let block_file = open_image_file(image);
+    block_file.0.lock().unwrap().set_len(2048 * 512)?;
+    let efs = EasyFileSystem::create(block_file, 2048, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let statfs = efs.lock().statfs();
+    // the root directory, and the root and a leaf of its name index
+    assert_eq!(statfs.free_blocks, statfs.total_blocks - 3);
+    assert_eq!(statfs.free_inodes, statfs.total_inodes - 1);
+
+    // a write past the free blocks is cut short, and then writes nothing.
+    // The free blocks are a single run that takes no extent block.
+    let data = vec![b'a'; statfs.total_blocks as usize * BLOCK_SZ];
+    let big = root_inode.create("big").unwrap();
+    let written = big.write_at(0, &data);
+    assert_eq!(written, statfs.free_blocks as usize * BLOCK_SZ);
+    assert_eq!(big.size(), written);
+    assert_eq!(efs.lock().statfs().free_blocks, 0);
+    assert_eq!(big.write_at(written, b"more"), 0);
+    assert!(!big.fallocate(written as u64, 1, true));
+    // no block is left for a dirent
+    assert!(root_inode.create("small").is_none());
+    assert!(root_inode.mkdir("dir").is_none());
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // the counts are kept in the super block of a reopened image
+    assert_eq!(root_inode.remove("big"), 0);
+    assert_eq!(efs.lock().statfs(), statfs);
+    root_inode
+        .create("small")
+        .unwrap()
+        .write_at(0, &data[..10 * BLOCK_SZ]);
+    root_inode.sync();
+    let efs = EasyFileSystem::open(open_image_file(image), easy_fs::BLOCK_CACHE_SIZE);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(efs.lock().statfs().free_blocks, statfs.free_blocks - 10);
+    assert_eq!(efs.lock().statfs().free_inodes, statfs.free_inodes - 1);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // free counts that do not match the bitmaps are counted again by fsck
+    root_inode.sync();
+    let mut block = [0u8; BLOCK_SZ];
+    let block_file = open_image_file(image);
+    block_file.read_block(0, &mut block);
+    let free_blocks = core::mem::offset_of!(easy_fs::SuperBlock, free_blocks);
+    block[free_blocks..free_blocks + 4].copy_from_slice(&7u32.to_ne_bytes());
+    block_file.write_block(0, &block);
+    let efs = EasyFileSystem::open(open_image_file(image), easy_fs::BLOCK_CACHE_SIZE);
+    assert_eq!(efs.lock().statfs().free_blocks, 7);
+    assert_eq!(
+        efs.lock().fsck(true),
+        [FsckProblem::BadFreeCounts {
+            found: (7, statfs.free_inodes - 1),
+            expected: (statfs.free_blocks - 10, statfs.free_inodes - 1),
+        }]
+    );
+    assert_eq!(efs.lock().statfs().free_blocks, statfs.free_blocks - 10);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // with index blocks, the blocks left after them are all written,
+    // and an old image without the counts has them counted on open
+    let block_file = open_image_file(image);
+    let efs =
+        EasyFileSystem::create_with_features(block_file, 2048, 1, easy_fs::FEATURE_LONG_NAMES);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let free_blocks = efs.lock().statfs().free_blocks;
+    let big = root_inode.create("big").unwrap();
+    let written = big.write_at(0, &data);
+    // indirect1, and indirect2 with 7 indirect1 blocks
+    assert_eq!(written, (free_blocks as usize - 9) * BLOCK_SZ);
+    assert_eq!(efs.lock().statfs().free_blocks, 0);
+    assert!(read_all(&big) == data[..written]);
+    root_inode.sync();
+    let efs = EasyFileSystem::open(open_image_file(image), easy_fs::BLOCK_CACHE_SIZE);
+    assert_eq!(efs.lock().statfs().free_blocks, 0);
+    assert!(efs.lock().fsck(false).is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_dir_index_test() -> std::io::Result<()> {
+    use easy_fs::FsckProblem;
+    let _guard = TEST_LOCK.lock().unwrap();
+    let image = "target/dir_index.img";
+    let block_file = open_image_file(image);
+    block_file.0.lock().unwrap().set_len(8192 * 512)?;
+    let efs = EasyFileSystem::create(block_file, 8192, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used_blocks = || {
+        let statfs = efs.lock().statfs();
+        statfs.total_blocks - statfs.free_blocks
+    };
+
+    // names spread over leaves split by hash, and over overflow blocks
+    // once the index root is full: at most 7 of these fit in a leaf
+    let dir = root_inode.mkdir("d").unwrap();
+    let blocks = used_blocks();
+    let names: Vec<String> = (0..800)
+        .map(|i| format!("{}-{}", "f".repeat(56), i))
+        .collect();
+    let mut ids = Vec::new();
+    for name in names.iter() {
+        ids.push(dir.create(name).unwrap().inode_id());
+    }
+    assert!(dir.create(&names[123]).is_none());
+    for (name, id) in names.iter().zip(ids.iter()) {
+        assert_eq!(dir.find(name).unwrap().inode_id(), *id);
+    }
+    assert!(dir.find(&format!("{}-800", "f".repeat(56))).is_none());
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // renamed and removed names leave the index, and a subdirectory
+    // gets an index of its own. The names are removed from the last,
+    // so that few dirent blocks are compacted in each transaction.
+    assert_eq!(dir.rename(&names[799], &dir, "last"), 0);
+    assert!(dir.find(&names[799]).is_none());
+    assert_eq!(dir.find("last").unwrap().inode_id(), ids[799]);
+    assert_eq!(dir.rename("last", &root_inode, "last"), 0);
+    assert_eq!(root_inode.find("last").unwrap().inode_id(), ids[799]);
+    let sub = dir.mkdir("sub").unwrap();
+    sub.create("inner").unwrap();
+    assert_eq!(
+        dir.find("sub").unwrap().find("inner").unwrap().inode_id(),
+        sub.find("inner").unwrap().inode_id()
+    );
+    assert_eq!(dir.rmdir("sub"), -1);
+    assert_eq!(sub.remove("inner"), 0);
+    assert_eq!(dir.rmdir("sub"), 0);
+    for name in names[..799].iter().rev() {
+        assert_eq!(dir.remove(name), 0);
+    }
+    assert!(dir.find(&names[0]).is_none());
+    assert_eq!(dir.ls(), [".", ".."]);
+    // the leaves left empty are merged again
+    assert_eq!(used_blocks(), blocks);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // a leaf that does not match the dirents is found by fsck,
+    // and the directory falls back to a linear lookup without its index
+    let only_id = dir.create("only").unwrap().inode_id();
+    root_inode.sync();
+    let block_file = open_image_file(image);
+    let mut block = [0u8; BLOCK_SZ];
+    // from the last block, past the copies left in the journal
+    let leaf = (0..8192)
+        .rev()
+        .find(|&block_id| {
+            block_file.read_block(block_id, &mut block);
+            // a leaf starts with no next block, dirents with "." of a non-root dir
+            block[..4] == [0; 4] && block.windows(4).any(|name| name == b"only")
+        })
+        .unwrap();
+    let offset = block.windows(4).position(|name| name == b"only").unwrap();
+    block[offset + 3] = b'z';
+    block_file.write_block(leaf, &block);
+    let efs = EasyFileSystem::open(open_image_file(image), easy_fs::BLOCK_CACHE_SIZE);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let dir = root_inode.find("d").unwrap();
+    assert!(dir.find("only").is_none());
+    let dir_id = dir.inode_id();
+    let problems = efs.lock().fsck(true);
+    assert_eq!(problems[0], FsckProblem::BadDirIndex { dir_id });
+    // the root and the leaf of the dropped index are freed
+    assert!(matches!(
+        problems[1..],
+        [FsckProblem::LeakedBlocks { count: 2, .. }]
+    ));
+    assert_eq!(dir.find("only").unwrap().inode_id(), only_id);
+    dir.create("other").unwrap();
+    assert!(dir.find("other").is_some());
+    assert_eq!(dir.remove("only"), 0);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // an image without the index looks names up linearly
+    let block_file = open_image_file(image);
+    let efs = EasyFileSystem::create_with_features(
+        block_file,
+        8192,
+        1,
+        easy_fs::FEATURE_LONG_NAMES | easy_fs::FEATURE_EXTENTS | easy_fs::FEATURE_FREE_COUNTS,
+    );
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used_blocks = || {
+        let statfs = efs.lock().statfs();
+        statfs.total_blocks - statfs.free_blocks
+    };
+    let blocks = used_blocks();
+    let dir = root_inode.mkdir("d").unwrap();
+    // a single dirent block
+    assert_eq!(used_blocks(), blocks + 1);
+    let mut last_id = 0;
+    for name in names.iter().take(300) {
+        last_id = dir.create(name).unwrap().inode_id();
+    }
+    assert_eq!(dir.find(&names[299]).unwrap().inode_id(), last_id);
+    assert_eq!(dir.remove(&names[299]), 0);
+    assert!(dir.find(&names[299]).is_none());
+    assert!(dir.find(&names[0]).is_some());
+    assert!(efs.lock().fsck(false).is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_open_unlink_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/open_unlink.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    let efs = EasyFileSystem::create(block_file, 4096, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used = || {
+        let statfs = efs.lock().statfs();
+        (
+            statfs.total_blocks - statfs.free_blocks,
+            statfs.total_inodes - statfs.free_inodes,
+        )
+    };
+    let empty = used();
+
+    // lookups of an inode in use share it
+    let a = root_inode.create("a").unwrap();
+    assert!(Arc::ptr_eq(&root_inode.find("a").unwrap(), &a));
+    let data = [b'a'; 10 * BLOCK_SZ];
+    a.write_at(0, &data);
+    let full = used();
+
+    // an open file outlives its last link until it is last closed
+    a.open();
+    a.open();
+    root_inode.link("a", "b").unwrap();
+    assert_eq!(root_inode.unlink("a"), 0);
+    assert_eq!(root_inode.unlink("b"), 0);
+    assert_eq!(root_inode.ls(), [".", ".."]);
+    assert_eq!(a.link_count(), 0);
+    assert_eq!(used(), full);
+    assert!(efs.lock().fsck(false).is_empty());
+    assert_eq!(read_all(&a), data);
+    a.close();
+    assert_eq!(used(), full);
+    a.close();
+    assert_eq!(used(), empty);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // so does a file replaced by a rename
+    let c = root_inode.create("c").unwrap();
+    c.write_at(0, &data);
+    c.open();
+    root_inode.create("d").unwrap();
+    assert_eq!(root_inode.rename("d", &root_inode, "c"), 0);
+    assert_eq!(read_all(&c), data);
+    assert_eq!(c.link_count(), 0);
+    assert_eq!(used().0, full.0);
+    c.close();
+    assert_eq!(used().0, empty.0);
+
+    // and a closed one is freed with its last link
+    let e = root_inode.create("e").unwrap();
+    e.open();
+    e.close();
+    e.write_at(0, &data);
+    assert_eq!(root_inode.unlink("e"), 0);
+    assert_eq!(root_inode.unlink("c"), 0);
+    assert_eq!(used(), empty);
+    assert!(efs.lock().fsck(false).is_empty());
+
+    // an open directory, such as a cwd, outlives its removal too,
+    // but nothing is added to it
+    let f = root_inode.mkdir("f").unwrap();
+    let g = f.mkdir("g").unwrap();
+    f.open();
+    g.open();
+    assert_eq!(f.rmdir("g"), 0);
+    assert_eq!(root_inode.rmdir("f"), 0);
+    assert_eq!(root_inode.link_count(), 2);
+    assert_eq!((f.link_count(), g.link_count()), (0, 0));
+    assert_eq!(f.ls(), [".", ".."]);
+    assert!(f.create("h").is_none() && f.mkdir("h").is_none());
+    let h = root_inode.mkdir("h").unwrap();
+    assert_eq!(root_inode.rename("h", &f, "h"), -1);
+    assert!(efs.lock().fsck(false).is_empty());
+    f.close();
+    g.close();
+    drop(h);
+    assert_eq!(root_inode.rmdir("h"), 0);
+    assert_eq!(used(), empty);
+    assert!(efs.lock().fsck(false).is_empty());
+    Ok(())
+}
+
+#[test]
+fn efs_truncate_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    let block_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/truncate.img")?;
+        f.set_len(4096 * 512).unwrap();
+        f
+    })));
+    // with extents, and with the indirect blocks of images made before them
+    for features in [
+        easy_fs::FEATURE_LONG_NAMES | easy_fs::FEATURE_EXTENTS | easy_fs::FEATURE_FREE_COUNTS,
+        easy_fs::FEATURE_LONG_NAMES,
+    ] {
+        let efs = EasyFileSystem::create_with_features(block_file.clone(), 4096, 1, features);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let used_blocks = || {
+            let statfs = efs.lock().statfs();
+            statfs.total_blocks - statfs.free_blocks
+        };
+        let empty = used_blocks();
+        let file = root_inode.create("f").unwrap();
+        let data: Vec<u8> = (0..300 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+        file.write_at(0, &data);
+
+        // shrinking frees the blocks past the new size, indirect blocks included
+        let size = 5 * BLOCK_SZ + 100;
+        assert!(file.truncate(size as u64));
+        assert_eq!(read_all(&file), data[..size]);
+        assert_eq!(used_blocks(), empty + 6);
+
+        // growing leaves a hole, and the rest of the last block reads as zeros
+        assert!(file.truncate(40 * BLOCK_SZ as u64));
+        assert_eq!(used_blocks(), empty + 6);
+        let mut expected = data[..size].to_vec();
+        expected.resize(40 * BLOCK_SZ, 0);
+        assert!(read_all(&file) == expected);
+        file.write_at(39 * BLOCK_SZ, &data[..BLOCK_SZ]);
+        expected[39 * BLOCK_SZ..].copy_from_slice(&data[..BLOCK_SZ]);
+        assert!(read_all(&file) == expected);
+        assert!(efs.lock().fsck(false).is_empty());
+
+        // nothing changes past the max size or on a directory
+        assert!(!file.truncate(easy_fs::MAX_FILE_SIZE + 1));
+        assert_eq!(file.size(), 40 * BLOCK_SZ);
+        assert!(!root_inode.truncate(0));
+        assert!(file.truncate(0));
+        assert_eq!(file.size(), 0);
+        assert_eq!(used_blocks(), empty);
+        assert!(efs.lock().fsck(false).is_empty());
+
+        // every other block is mapped, an extent each, and they are freed in steps
+        for i in (0..2400).step_by(2) {
+            assert_eq!(file.write_at(i * BLOCK_SZ, &data[..BLOCK_SZ]), BLOCK_SZ);
+        }
+        assert!(file.punch_hole(BLOCK_SZ as u64, 1200 * BLOCK_SZ as u64));
+        assert!(read_all(&file)[BLOCK_SZ..1201 * BLOCK_SZ].iter().all(|byte| *byte == 0));
+        assert!(efs.lock().fsck(false).is_empty());
+        assert!(file.truncate(0));
+        assert_eq!(used_blocks(), empty);
+        assert!(efs.lock().fsck(false).is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+fn efs_resize_test() -> std::io::Result<()> {
+    let _guard = TEST_LOCK.lock().unwrap();
+    let image = "target/resize.img";
+    let data: Vec<u8> = (0..5000 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let written = {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(image)?;
+        f.set_len(4096 * 512)?;
+        let block_file = Arc::new(BlockFile(Mutex::new(f)));
+        let efs = EasyFileSystem::create(block_file, 4096, 1);
+        // the image is full
+        let file = EasyFileSystem::root_inode(&efs).create("f").unwrap();
+        let written = file.write_at(0, &data);
+        assert!(written < data.len());
+        assert_eq!(efs.lock().statfs().free_blocks, 0);
+        easy_fs::block_cache_sync_all();
+        written
+    };
+    let resize = |size: &str| {
+        let matches = cli().get_matches_from(["easy-fs-fuse", "resize", image, size]);
+        easy_fs_resize(matches.subcommand_matches("resize").unwrap())
+    };
+    let check = |total_blocks: u32| -> std::io::Result<Arc<spin::Mutex<EasyFileSystem>>> {
+        let efs = open_image(image)?;
+        assert_eq!(efs.lock().total_blocks(), total_blocks);
+        assert_eq!(std::fs::metadata(image)?.len(), total_blocks as u64 * 512);
+        let file = EasyFileSystem::root_inode(&efs).find("f").unwrap();
+        assert!(read_all(&file) == data[..written]);
+        assert!(efs.lock().fsck(false).is_empty());
+        Ok(efs)
+    };
+    let data_blocks = check(4096)?.lock().statfs().total_blocks;
+
+    // the slack of the last data bitmap block is enough for 4096 data blocks
+    resize("2500K")?;
+    let efs = check(5000)?;
+    let statfs = efs.lock().statfs();
+    assert_eq!(statfs.total_blocks, data_blocks + 904);
+    assert_eq!(statfs.free_blocks, 904);
+    drop(efs);
+    // then a data bitmap block is added at the end of the image,
+    // and moved to the new end when it grows again
+    resize("3000K")?;
+    assert_eq!(
+        check(6000)?.lock().statfs().total_blocks,
+        data_blocks + 1903
+    );
+    resize("7000K")?;
+    let efs = check(14000)?;
+    let statfs = efs.lock().statfs();
+    assert_eq!(statfs.total_blocks, data_blocks + 9901);
+    assert_eq!(statfs.free_blocks, 9901);
+
+    // the new blocks are allocated, the last bitmap blocks excluded
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let g = root_inode.create("g").unwrap();
+    assert!(g.write_at(0, &data) == data.len());
+    let h = root_inode.create("h").unwrap();
+    let more = h.write_at(0, &data);
+    assert!(more < data.len());
+    assert_eq!(efs.lock().statfs().free_blocks, 0);
+    assert!(efs.lock().fsck(false).is_empty());
+    easy_fs::block_cache_sync_all();
+    drop(efs);
+    let efs = check(14000)?;
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert!(read_all(&root_inode.find("g").unwrap()) == data);
+    assert!(read_all(&root_inode.find("h").unwrap()) == data[..more]);
+
+    // the kernel is told of the size before the first resize once, and reports
+    // it with the new size as it mounts the image, see `ROOT_INODE` in os/src/fs
+    let resized = efs.lock().take_resized_from();
+    let reported = resized.map(|old_blocks| (old_blocks, efs.lock().total_blocks()));
+    assert_eq!(reported, Some((4096, 14000)));
+    assert_eq!(efs.lock().take_resized_from(), None);
+    easy_fs::block_cache_sync_all();
+    drop(efs);
+
+    let shrink = resize("3000K").unwrap_err();
+    assert_eq!(shrink.kind(), ErrorKind::InvalidInput);
+    assert!(resize("7000K").is_ok());
+    assert!(resize("1000").is_err());
+    assert_eq!(check(14000)?.lock().take_resized_from(), None);
     Ok(())
 }
@@ -1,5 +1,8 @@
 use super::{get_block_cache, BlockDevice, BLOCK_SZ};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
 
 /// A bitmap block
 type BitmapBlock = [u64; 64];
@@ -11,6 +14,21 @@ const BLOCK_BITS: usize = BLOCK_SZ * 8;
 pub struct Bitmap {
     start_block_id: usize,
     blocks: usize,
+    /// Where the blocks of the bitmap past the first `blocks` ones are,
+    /// for a bitmap grown after its area was laid out
+    ext_start_block_id: usize,
+    ext_blocks: usize,
+    /// Number of bits that can be allocated
+    bits: usize,
+    cache: Mutex<FreeCache>,
+}
+
+/// In-memory state of the allocator of a bitmap
+struct FreeCache {
+    /// Number of free bits in each bitmap block, counted on the first allocation
+    free: Vec<u32>,
+    /// Where the next run is searched from, right after the last one
+    next: usize,
 }
 
 /// Decompose bits into (block_pos, bits64_pos, inner_pos)
@@ -23,51 +41,233 @@ fn decomposition(mut bit: usize) -> (usize, usize, usize) {
 impl Bitmap {
     /// A new bitmap from start block id and number of blocks
     pub fn new(start_block_id: usize, blocks: usize) -> Self {
+        Self::with_bits(start_block_id, blocks, blocks * BLOCK_BITS)
+    }
+    /// A new bitmap of which only the first `bits` bits can be allocated
+    pub fn with_bits(start_block_id: usize, blocks: usize, bits: usize) -> Self {
+        Self::with_ext(start_block_id, blocks, 0, 0, bits)
+    }
+    /// A new bitmap of which only the first `bits` bits can be allocated, with
+    /// `blocks` blocks from `start_block_id` on followed by `ext_blocks` blocks
+    /// from `ext_start_block_id` on
+    pub fn with_ext(
+        start_block_id: usize,
+        blocks: usize,
+        ext_start_block_id: usize,
+        ext_blocks: usize,
+        bits: usize,
+    ) -> Self {
+        assert!(bits <= (blocks + ext_blocks) * BLOCK_BITS);
         Self {
             start_block_id,
             blocks,
+            ext_start_block_id,
+            ext_blocks,
+            bits,
+            cache: Mutex::new(FreeCache {
+                free: Vec::new(),
+                next: 0,
+            }),
+        }
+    }
+    /// Get the block id of the `block_pos`-th block of the bitmap
+    fn block_id(&self, block_pos: usize) -> usize {
+        if block_pos < self.blocks {
+            self.start_block_id + block_pos
+        } else {
+            self.ext_start_block_id + block_pos - self.blocks
         }
     }
-    /// Allocate a new block from a block device
+    /// Allocate the first free bit
     pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
-        for block_id in 0..self.blocks {
-            let pos = get_block_cache(
-                block_id + self.start_block_id as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .modify(0, |bitmap_block: &mut BitmapBlock| {
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
-                    .iter()
-                    .enumerate()
-                    .find(|(_, bits64)| **bits64 != u64::MAX)
-                    .map(|(bits64_pos, bits64)| (bits64_pos, bits64.trailing_ones() as usize))
-                {
-                    // modify cache
-                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
-                    Some(block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos as usize)
-                } else {
-                    None
+        let mut cache = self.cache.lock();
+        self.count_free(block_device, &mut cache);
+        let bit = self.find_free(block_device, &cache.free, 0, self.bits)?;
+        assert_eq!(self.take_run(block_device, bit, 1), 1);
+        cache.free[bit / BLOCK_BITS] -= 1;
+        Some(bit)
+    }
+    /// Allocate a run of at most `count` consecutive bits, return the first bit
+    /// and the length of the run.
+    /// The search is next-fit: the run starts at the first free bit after the
+    /// last run, and wraps around to the start of the bitmap.
+    pub fn alloc_run(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        count: usize,
+    ) -> Option<(usize, usize)> {
+        assert!(count > 0);
+        let mut cache = self.cache.lock();
+        self.count_free(block_device, &mut cache);
+        let start = self
+            .find_free(block_device, &cache.free, cache.next, self.bits)
+            .or_else(|| self.find_free(block_device, &cache.free, 0, cache.next))?;
+        let mut len = 0;
+        loop {
+            let max = (count - len).min(self.bits - start - len);
+            let taken = self.take_run(block_device, start + len, max);
+            cache.free[(start + len) / BLOCK_BITS] -= taken as u32;
+            len += taken;
+            // a run goes on into the next bitmap block
+            if taken == 0 || len == count || (start + len) % BLOCK_BITS != 0 {
+                break;
+            }
+        }
+        cache.next = start + len;
+        Some((start, len))
+    }
+    /// Count the free bits of each bitmap block if they are not counted yet
+    fn count_free(&self, block_device: &Arc<dyn BlockDevice>, cache: &mut FreeCache) {
+        if !cache.free.is_empty() {
+            return;
+        }
+        cache.free = (0..self.blocks + self.ext_blocks)
+            .map(|block_pos| {
+                let bits = self
+                    .bits
+                    .saturating_sub(block_pos * BLOCK_BITS)
+                    .min(BLOCK_BITS);
+                get_block_cache(self.block_id(block_pos), Arc::clone(block_device))
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| {
+                        (0..bits)
+                            .filter(|bit| bitmap_block[bit / 64] & (1u64 << (bit % 64)) == 0)
+                            .count() as u32
+                    })
+            })
+            .collect();
+    }
+    /// Find the first free bit in `from..to`, skipping the bitmap blocks with no free bit
+    fn find_free(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        free: &[u32],
+        from: usize,
+        to: usize,
+    ) -> Option<usize> {
+        let mut bit = from;
+        while bit < to {
+            let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+            let block_start = block_pos * BLOCK_BITS;
+            if free[block_pos] > 0 {
+                let found = get_block_cache(self.block_id(block_pos), Arc::clone(block_device))
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| {
+                        bitmap_block.iter().enumerate().skip(bits64_pos).find_map(
+                            |(pos, bits64)| {
+                                // the bits before `bit` are taken as used
+                                let bits64 = if pos == bits64_pos {
+                                    bits64 | ((1u64 << inner_pos) - 1)
+                                } else {
+                                    *bits64
+                                };
+                                (bits64 != u64::MAX)
+                                    .then(|| pos * 64 + bits64.trailing_ones() as usize)
+                            },
+                        )
+                    });
+                if let Some(found) = found {
+                    return Some(block_start + found).filter(|found| *found < to);
                 }
-            });
-            if pos.is_some() {
-                return pos;
             }
+            bit = block_start + BLOCK_BITS;
         }
         None
     }
+    /// Set at most `max` consecutive free bits from `bit` on in the bitmap block
+    /// holding it, return how many are set
+    fn take_run(&self, block_device: &Arc<dyn BlockDevice>, bit: usize, max: usize) -> usize {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(self.block_id(block_pos), Arc::clone(block_device))
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                let mut pos = bits64_pos * 64 + inner_pos;
+                let mut taken = 0;
+                while taken < max
+                    && pos < BLOCK_BITS
+                    && bitmap_block[pos / 64] & (1u64 << (pos % 64)) == 0
+                {
+                    bitmap_block[pos / 64] |= 1u64 << (pos % 64);
+                    pos += 1;
+                    taken += 1;
+                }
+                taken
+            })
+    }
     /// Deallocate a block
     pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
-        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+        get_block_cache(self.block_id(block_pos), Arc::clone(block_device))
             .lock()
             .modify(0, |bitmap_block: &mut BitmapBlock| {
                 assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
                 bitmap_block[bits64_pos] -= 1u64 << inner_pos;
             });
+        if let Some(free) = self.cache.lock().free.get_mut(block_pos) {
+            if bit < self.bits {
+                *free += 1;
+            }
+        }
+    }
+    /// Mark `bit` as allocated
+    pub fn set(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        let was_free = get_block_cache(self.block_id(block_pos), Arc::clone(block_device))
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                let was_free = bitmap_block[bits64_pos] & (1u64 << inner_pos) == 0;
+                bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                was_free
+            });
+        if let Some(free) = self.cache.lock().free.get_mut(block_pos) {
+            // bits past the allocatable ones are not counted
+            if was_free && bit < self.bits {
+                *free -= 1;
+            }
+        }
+    }
+    /// Whether `bit` is allocated
+    pub fn is_set(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(self.block_id(block_pos), Arc::clone(block_device))
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bits64_pos] & (1u64 << inner_pos) != 0
+            })
+    }
+    /// Get all allocated bits in ascending order
+    pub fn allocated(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<usize> {
+        let mut bits = Vec::new();
+        for block_pos in 0..self.blocks + self.ext_blocks {
+            get_block_cache(self.block_id(block_pos), Arc::clone(block_device))
+                .lock()
+                .read(0, |bitmap_block: &BitmapBlock| {
+                    for (bits64_pos, bits64) in bitmap_block.iter().enumerate() {
+                        for inner_pos in
+                            (0..64).filter(|inner_pos| bits64 & (1u64 << inner_pos) != 0)
+                        {
+                            bits.push(block_pos * BLOCK_BITS + bits64_pos * 64 + inner_pos);
+                        }
+                    }
+                });
+        }
+        bits
+    }
+    /// Clear the bits in `bits`, which need not be set
+    pub fn clear(&self, block_device: &Arc<dyn BlockDevice>, bits: Range<usize>) {
+        for bit in bits {
+            let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+            get_block_cache(self.block_id(block_pos), Arc::clone(block_device))
+                .lock()
+                .modify(0, |bitmap_block: &mut BitmapBlock| {
+                    bitmap_block[bits64_pos] &= !(1u64 << inner_pos);
+                });
+        }
+        // counted again on the next allocation
+        self.cache.lock().free.clear();
     }
     /// Get the max number of allocatable blocks
     pub fn maximum(&self) -> usize {
-        self.blocks * BLOCK_BITS
+        self.bits
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,7 @@
-use super::{BlockDevice, BLOCK_SZ};
-use alloc::collections::VecDeque;
+use super::{spill_block, take_spilled, track_block, BlockDevice, BLOCK_SZ};
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use lazy_static::*;
 use spin::Mutex;
 
@@ -10,18 +11,22 @@ pub struct BlockCache {
     block_id: usize,                    // underlying block id
     block_device: Arc<dyn BlockDevice>, // underlying block device
     modified: bool,                     // whether the block is dirty
+    journaled: bool,                    // whether the block is in a running transaction
 }
 
 impl BlockCache {
-    /// Load a new BlockCache from disk.
+    /// Load a new BlockCache from disk. A block of a running transaction
+    /// written to the journal to make room in the cache is loaded from there.
     pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
         let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
+        let spilled = take_spilled(cache_key(block_id, &block_device).0, block_id);
+        block_device.read_block(spilled.unwrap_or(block_id), &mut cache);
         Self {
             cache,
             block_id,
             block_device,
-            modified: false,
+            modified: spilled.is_some(),
+            journaled: spilled.is_some(),
         }
     }
     /// Get the address of an offset inside the cached block data
@@ -46,6 +51,10 @@ impl BlockCache {
         let type_size = core::mem::size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
         self.modified = true;
+        if !self.journaled {
+            let device = cache_key(self.block_id, &self.block_device).0;
+            self.journaled = track_block(device, self.block_id);
+        }
         let addr = self.addr_of_offset(offset);
         unsafe { &mut *(addr as *mut T) }
     }
@@ -58,67 +67,334 @@ impl BlockCache {
         f(self.get_mut(offset))
     }
 
+    /// Write the block back if it is dirty. Blocks in a running transaction
+    /// are kept until it is committed.
     pub fn sync(&mut self) {
-        if self.modified {
+        if self.modified && !self.journaled {
             self.modified = false;
             self.block_device.write_block(self.block_id, &self.cache);
         }
     }
 }
 
+impl BlockCache {
+    /// Write the block to its home location once its transaction is committed
+    pub fn checkpoint(&mut self) {
+        self.journaled = false;
+        self.sync();
+    }
+}
+
 impl Drop for BlockCache {
     fn drop(&mut self) {
         self.sync()
     }
 }
 
-/// Use a block cache of 16 blocks
-const BLOCK_CACHE_SIZE: usize = 16;
+/// Default number of blocks of a device held by the block cache
+pub const BLOCK_CACHE_SIZE: usize = 16;
+
+/// End of the LRU list or a hash chain
+const NIL: usize = usize::MAX;
+
+/// Blocks are cached by the address of their device and their block id,
+/// so that several devices can share the cache
+type CacheKey = (usize, usize);
+
+fn cache_key(block_id: usize, block_device: &Arc<dyn BlockDevice>) -> CacheKey {
+    (Arc::as_ptr(block_device) as *const () as usize, block_id)
+}
+
+/// Statistics of the block cache, see [`block_cache_stats`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    /// Lookups that found the block in the cache
+    pub hits: usize,
+    /// Lookups that had to read the block from its device
+    pub misses: usize,
+    /// Blocks dropped from the cache to make room for others
+    pub evictions: usize,
+    /// Blocks of a running transaction written to the journal to be dropped
+    pub spills: usize,
+    /// Blocks loaded past the capacity as every cached block was pinned
+    pub overflows: usize,
+}
+
+struct CacheSlot {
+    key: CacheKey,
+    cache: Arc<Mutex<BlockCache>>,
+    /// neighbours in the LRU list, `prev` is the more recently used one
+    prev: usize,
+    next: usize,
+    /// next slot in the same hash bucket
+    chain: usize,
+}
+
+/// Number of blocks of a device in the cache and how many it may hold
+struct DeviceCache {
+    device: usize,
+    len: usize,
+    capacity: usize,
+}
 
+/// A LRU cache of blocks with a hash index, each device holding at most
+/// its capacity of blocks. A block is pinned while someone other than the
+/// manager holds it, and only unpinned blocks are evicted. A block of a running
+/// transaction is written to its place in the journal to be evicted, see
+/// `spill_block`. Only when every block of a device is pinned, the cache holds
+/// more of them than its capacity until enough of them are released.
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    slots: Vec<Option<CacheSlot>>,
+    free_slots: Vec<usize>,
+    buckets: Vec<usize>,
+    /// most and least recently used slots
+    head: usize,
+    tail: usize,
+    devices: Vec<DeviceCache>,
+    stats: BlockCacheStats,
 }
 
 impl BlockCacheManager {
     pub fn new() -> Self {
         Self {
-            queue: VecDeque::new(),
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            buckets: vec![NIL; (BLOCK_CACHE_SIZE * 2).next_power_of_two()],
+            head: NIL,
+            tail: NIL,
+            devices: Vec::new(),
+            stats: BlockCacheStats::default(),
         }
     }
 
+    fn slot(&self, idx: usize) -> &CacheSlot {
+        self.slots[idx].as_ref().unwrap()
+    }
+
+    fn slot_mut(&mut self, idx: usize) -> &mut CacheSlot {
+        self.slots[idx].as_mut().unwrap()
+    }
+
+    fn bucket_of(&self, key: CacheKey) -> usize {
+        let hash = (key.0 >> 4) ^ key.1.wrapping_mul(0x9e37_79b9);
+        hash & (self.buckets.len() - 1)
+    }
+
+    fn find(&self, key: CacheKey) -> Option<usize> {
+        let mut idx = self.buckets[self.bucket_of(key)];
+        while idx != NIL {
+            let slot = self.slot(idx);
+            if slot.key == key {
+                return Some(idx);
+            }
+            idx = slot.chain;
+        }
+        None
+    }
+
+    fn lru_remove(&mut self, idx: usize) {
+        let (prev, next) = {
+            let slot = self.slot(idx);
+            (slot.prev, slot.next)
+        };
+        match prev {
+            NIL => self.head = next,
+            prev => self.slot_mut(prev).next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.slot_mut(next).prev = prev,
+        }
+    }
+
+    fn lru_push_front(&mut self, idx: usize) {
+        let head = self.head;
+        {
+            let slot = self.slot_mut(idx);
+            slot.prev = NIL;
+            slot.next = head;
+        }
+        match head {
+            NIL => self.tail = idx,
+            head => self.slot_mut(head).prev = idx,
+        }
+        self.head = idx;
+    }
+
+    fn bucket_insert(&mut self, idx: usize) {
+        let bucket = self.bucket_of(self.slot(idx).key);
+        self.slot_mut(idx).chain = self.buckets[bucket];
+        self.buckets[bucket] = idx;
+    }
+
+    fn bucket_remove(&mut self, idx: usize) {
+        let bucket = self.bucket_of(self.slot(idx).key);
+        let chain = self.slot(idx).chain;
+        if self.buckets[bucket] == idx {
+            self.buckets[bucket] = chain;
+            return;
+        }
+        let mut cur = self.buckets[bucket];
+        while self.slot(cur).chain != idx {
+            cur = self.slot(cur).chain;
+        }
+        self.slot_mut(cur).chain = chain;
+    }
+
+    /// Get the blocks of `device` in the cache, added with the default capacity
+    fn device_mut(&mut self, device: usize) -> &mut DeviceCache {
+        let i = match self.devices.iter().position(|cache| cache.device == device) {
+            Some(i) => i,
+            None => {
+                self.devices.push(DeviceCache {
+                    device,
+                    len: 0,
+                    capacity: BLOCK_CACHE_SIZE,
+                });
+                self.devices.len() - 1
+            }
+        };
+        &mut self.devices[i]
+    }
+
+    /// Evict the least recently used block of `device` that is not pinned,
+    /// return whether there is one. A block of a running transaction is
+    /// evicted only if it can be written to the journal.
+    fn evict_lru(&mut self, device: usize) -> bool {
+        let mut idx = self.tail;
+        while idx != NIL {
+            let slot = self.slot(idx);
+            let prev = slot.prev;
+            // nobody else can lock a block that is not pinned
+            let evicted = match slot.cache.try_lock() {
+                Some(cache) if slot.key.0 == device && Arc::strong_count(&slot.cache) == 1 => {
+                    if !cache.journaled {
+                        Some(false)
+                    } else if spill_block(device, cache.block_id, &cache.cache) {
+                        Some(true)
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            };
+            if let Some(spilled) = evicted {
+                if spilled {
+                    self.stats.spills += 1;
+                }
+                self.evict(idx);
+                return true;
+            }
+            idx = prev;
+        }
+        false
+    }
+
+    /// Drop a block from the cache, it is written back if modified
+    fn evict(&mut self, idx: usize) {
+        self.lru_remove(idx);
+        self.bucket_remove(idx);
+        let slot = self.slots[idx].take().unwrap();
+        self.free_slots.push(idx);
+        self.device_mut(slot.key.0).len -= 1;
+        self.stats.evictions += 1;
+    }
+
+    /// Get the cached block, loading it from the device if needed
     pub fn get_block_cache(
         &mut self,
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
-            Arc::clone(&pair.1)
-        } else {
-            // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
-                if let Some((idx, _)) = self
-                    .queue
-                    .iter()
-                    .enumerate()
-                    .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
-                {
-                    self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
-                }
+        let key = cache_key(block_id, &block_device);
+        if let Some(idx) = self.find(key) {
+            self.stats.hits += 1;
+            self.lru_remove(idx);
+            self.lru_push_front(idx);
+            return Arc::clone(&self.slot(idx).cache);
+        }
+        loop {
+            let cache = self.device_mut(key.0);
+            if cache.len < cache.capacity {
+                break;
+            }
+            if !self.evict_lru(key.0) {
+                self.stats.overflows += 1;
+                break;
+            }
+        }
+        self.stats.misses += 1;
+        // load block into mem and push front
+        let block_cache = Arc::new(Mutex::new(BlockCache::new(block_id, block_device)));
+        let slot = CacheSlot {
+            key,
+            cache: Arc::clone(&block_cache),
+            prev: NIL,
+            next: NIL,
+            chain: NIL,
+        };
+        let idx = match self.free_slots.pop() {
+            Some(idx) => {
+                self.slots[idx] = Some(slot);
+                idx
+            }
+            None => {
+                self.slots.push(Some(slot));
+                self.slots.len() - 1
+            }
+        };
+        self.bucket_insert(idx);
+        self.lru_push_front(idx);
+        self.device_mut(key.0).len += 1;
+        block_cache
+    }
+
+    /// Change the number of blocks of the device at `device` held by the cache.
+    /// When shrinking, blocks still pinned stay cached until they are released.
+    pub fn set_capacity(&mut self, device: usize, capacity: usize) {
+        assert!(capacity > 0, "Block cache capacity must not be 0");
+        self.device_mut(device).capacity = capacity;
+        while self.device_mut(device).len > capacity && self.evict_lru(device) {}
+        // keep buckets at most half full
+        let total: usize = self.devices.iter().map(|cache| cache.capacity).sum();
+        self.buckets = vec![NIL; (total * 2).next_power_of_two()];
+        for idx in 0..self.slots.len() {
+            if self.slots[idx].is_some() {
+                self.bucket_insert(idx);
+            }
+        }
+    }
+
+    /// Write all modified blocks back to their devices
+    pub fn sync_all(&self) {
+        for slot in self.slots.iter().flatten() {
+            slot.cache.lock().sync();
+        }
+    }
+
+    /// Write modified blocks of the device at `device` back to it
+    fn sync_device(&self, device: usize) {
+        for slot in self.slots.iter().flatten() {
+            if slot.key.0 == device {
+                slot.cache.lock().sync();
             }
-            // load block into mem and push back
-            let block_cache = Arc::new(Mutex::new(BlockCache::new(
-                block_id,
-                Arc::clone(&block_device),
-            )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
-            block_cache
         }
     }
-}
 
+    /// Drop the blocks of the device at `device` and its capacity,
+    /// they are written back if modified
+    fn release_device(&mut self, device: usize) {
+        for idx in 0..self.slots.len() {
+            if self.slots[idx]
+                .as_ref()
+                .is_some_and(|slot| slot.key.0 == device)
+            {
+                self.evict(idx);
+            }
+        }
+        self.devices.retain(|cache| cache.device != device);
+    }
+}
 
 //* The global block cache manager
 lazy_static! {
@@ -136,11 +412,34 @@ pub fn get_block_cache(
         .get_block_cache(block_id, block_device)
 }
 
+/// Set the number of blocks of `block_device` held by the block cache,
+/// `BLOCK_CACHE_SIZE` until it is set, see [`crate::EasyFileSystem::open`]
+pub fn set_block_cache_capacity(block_device: &Arc<dyn BlockDevice>, capacity: usize) {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .set_capacity(cache_key(0, block_device).0, capacity);
+}
+
+/// Drop the blocks of `block_device` from the block cache once it is no longer used
+pub fn block_cache_release_device(block_device: &Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .release_device(cache_key(0, block_device).0);
+}
+
+/// Get the hit/miss statistics of the block cache
+pub fn block_cache_stats() -> BlockCacheStats {
+    BLOCK_CACHE_MANAGER.lock().stats
+}
+
 /// Sync all block cache to block device
 pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
-        cache.lock().sync();
-    }
+    BLOCK_CACHE_MANAGER.lock().sync_all();
 }
 
+/// Sync the block cache of one block device to it
+pub fn block_cache_sync_device(block_device: &Arc<dyn BlockDevice>) {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .sync_device(cache_key(0, block_device).0);
+}
@@ -0,0 +1,477 @@
+//! Hashed index of the names in a directory
+//!
+//! A directory mapped by extents may keep an index of its dirents besides the
+//! dirents themselves, so that a name is found in a couple of blocks instead of
+//! by reading every dirent. The index is a tree of two levels in the style of
+//! an htree: the root block holds up to `ROOT_ENTRIES` pairs of the smallest hash
+//! of a leaf and the leaf block, sorted by hash, and a leaf block holds records
+//! laid out like variable-length dirents. A full leaf is split in two by hash,
+//! and once the root is full it gets overflow blocks chained after it. Leaves
+//! whose records fit in one block after a removal are merged again.
+//!
+//! The dirents stay the authority: they are what is listed and what an image
+//! without the index reads, so an index can always be dropped and the directory
+//! looked up linearly again. Removing a name still walks the dirents to find
+//! and compact it.
+
+use super::{get_block_cache, BlockDevice, DirEntry, BLOCK_SZ};
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// The max number of leaves under the root block
+const ROOT_ENTRIES: usize = BLOCK_SZ / 8 - 1;
+/// Bytes of records in a leaf block
+const LEAF_BYTES: usize = BLOCK_SZ - 8;
+
+/// The root block of an index
+#[repr(C)]
+struct IndexRoot {
+    /// number of leaves
+    count: u32,
+    _reserved: u32,
+    /// the smallest hash and the block of each leaf, the first hash is 0
+    entries: [[u32; 2]; ROOT_ENTRIES],
+}
+
+/// A leaf block of an index, or an overflow block chained after one
+#[repr(C)]
+struct IndexLeaf {
+    /// the next overflow block, 0 if none
+    next: u32,
+    /// bytes of records in use
+    used: u32,
+    records: [u8; LEAF_BYTES],
+}
+
+/// FNV-1a hash of a name
+fn hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// The hash of a record, `None` if its name is not valid
+fn record_hash(dirent: &DirEntry) -> Option<u32> {
+    dirent.try_name().map(|name| hash(name.as_bytes()))
+}
+
+/// The records of a leaf along with their offsets
+fn records(leaf: &IndexLeaf) -> Vec<(usize, DirEntry)> {
+    let used = (leaf.used as usize).min(LEAF_BYTES);
+    let mut v = Vec::new();
+    let mut offset = 0;
+    while let Some(dirent) = DirEntry::from_bytes(&leaf.records[offset..used]) {
+        let len = dirent.len(true);
+        v.push((offset, dirent));
+        offset += len;
+    }
+    v
+}
+
+/// Find the leaf of `hash`, return its position in the root and its block
+fn find_leaf(root: u32, hash: u32, block_device: &Arc<dyn BlockDevice>) -> (usize, u32) {
+    get_block_cache(root as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |index_root: &IndexRoot| {
+            let count = (index_root.count as usize).clamp(1, ROOT_ENTRIES);
+            let i = index_root.entries[..count]
+                .partition_point(|entry| entry[0] <= hash)
+                .max(1)
+                - 1;
+            (i, index_root.entries[i][1])
+        })
+}
+
+/// Find the record named `name` in the chain from `leaf`,
+/// return its block, its offset there and the block before it in the chain
+fn find_record(
+    leaf: u32,
+    name: &str,
+    block_device: &Arc<dyn BlockDevice>,
+) -> Option<(u32, usize, DirEntry, u32)> {
+    let mut prev = 0;
+    let mut block_id = leaf;
+    while block_id != 0 {
+        let (found, next) = get_block_cache(block_id as usize, Arc::clone(block_device))
+            .lock()
+            .read(0, |leaf: &IndexLeaf| {
+                let found = records(leaf)
+                    .into_iter()
+                    .find(|(_, dirent)| dirent.try_name() == Some(name));
+                (found, leaf.next)
+            });
+        if let Some((offset, dirent)) = found {
+            return Some((block_id, offset, dirent, prev));
+        }
+        prev = block_id;
+        block_id = next;
+    }
+    None
+}
+
+/// Put `record` in leaf block `block_id` if there is room for it
+fn try_put(block_id: u32, record: &[u8], block_device: &Arc<dyn BlockDevice>) -> bool {
+    let cache = get_block_cache(block_id as usize, Arc::clone(block_device));
+    let mut cache = cache.lock();
+    if cache.read(0, |leaf: &IndexLeaf| {
+        leaf.used as usize + record.len() > LEAF_BYTES
+    }) {
+        return false;
+    }
+    cache.modify(0, |leaf: &mut IndexLeaf| {
+        let used = leaf.used as usize;
+        leaf.records[used..used + record.len()].copy_from_slice(record);
+        leaf.used += record.len() as u32;
+    });
+    true
+}
+
+/// Split leaf `i` of the root, which has no overflow block, in two halves by hash.
+/// Return false if the root is full or all records of the leaf have one hash.
+fn split_leaf(
+    root: u32,
+    i: usize,
+    leaf: u32,
+    alloc: &mut impl FnMut() -> u32,
+    block_device: &Arc<dyn BlockDevice>,
+) -> bool {
+    let count = get_block_cache(root as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |index_root: &IndexRoot| index_root.count as usize);
+    if count >= ROOT_ENTRIES {
+        return false;
+    }
+    let mut records: Vec<(u32, Vec<u8>)> = get_block_cache(leaf as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |leaf: &IndexLeaf| {
+            records(leaf)
+                .into_iter()
+                .map(|(_, dirent)| (record_hash(&dirent).unwrap_or(0), dirent.to_bytes(true)))
+                .collect()
+        });
+    if records.len() < 2 {
+        return false;
+    }
+    records.sort_by_key(|(hash, _)| *hash);
+    // records of the same hash stay in the same leaf
+    let same_hash = |split: usize| records[split].0 == records[split - 1].0;
+    let mut split = records.len() / 2;
+    while split < records.len() && same_hash(split) {
+        split += 1;
+    }
+    if split == records.len() {
+        split = records.len() / 2;
+        while split > 0 && same_hash(split) {
+            split -= 1;
+        }
+        if split == 0 {
+            return false;
+        }
+    }
+    let fill = |block_id: u32, records: &[(u32, Vec<u8>)]| {
+        get_block_cache(block_id as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |leaf: &mut IndexLeaf| {
+                leaf.records.fill(0);
+                let mut used = 0;
+                for (_, record) in records {
+                    leaf.records[used..used + record.len()].copy_from_slice(record);
+                    used += record.len();
+                }
+                leaf.used = used as u32;
+            });
+    };
+    let new_leaf = alloc();
+    fill(leaf, &records[..split]);
+    fill(new_leaf, &records[split..]);
+    get_block_cache(root as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |index_root: &mut IndexRoot| {
+            index_root.entries.copy_within(i + 1..count, i + 2);
+            index_root.entries[i + 1] = [records[split].0, new_leaf];
+            index_root.count += 1;
+        });
+    true
+}
+
+/// Find the inode number of `name` in the index rooted at block `root`
+pub fn lookup(root: u32, name: &str, block_device: &Arc<dyn BlockDevice>) -> Option<u32> {
+    if root == 0 {
+        return None;
+    }
+    let (_, leaf) = find_leaf(root, hash(name.as_bytes()), block_device);
+    find_record(leaf, name, block_device).map(|(_, _, dirent, _)| dirent.inode_id())
+}
+
+/// Add `name` of inode `inode_id` to the index rooted at block `root`, which is
+/// allocated first if it is 0, and return the root.
+/// At most two blocks are taken from `alloc` besides the root and its first leaf,
+/// when a leaf is split and the record still does not fit.
+pub fn insert(
+    root: u32,
+    name: &str,
+    inode_id: u32,
+    alloc: &mut impl FnMut() -> u32,
+    block_device: &Arc<dyn BlockDevice>,
+) -> u32 {
+    let record = DirEntry::new(name, inode_id).to_bytes(true);
+    let hash = hash(name.as_bytes());
+    let root = if root == 0 {
+        let root = alloc();
+        let leaf = alloc();
+        get_block_cache(root as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |index_root: &mut IndexRoot| {
+                index_root.count = 1;
+                index_root.entries[0] = [0, leaf];
+            });
+        root
+    } else {
+        root
+    };
+    let (i, leaf) = find_leaf(root, hash, block_device);
+    let mut block_id = leaf;
+    while block_id != 0 {
+        if try_put(block_id, &record, block_device) {
+            return root;
+        }
+        block_id = get_block_cache(block_id as usize, Arc::clone(block_device))
+            .lock()
+            .read(0, |leaf: &IndexLeaf| leaf.next);
+    }
+    let has_overflow = get_block_cache(leaf as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |leaf: &IndexLeaf| leaf.next != 0);
+    let leaf = if !has_overflow && split_leaf(root, i, leaf, alloc, block_device) {
+        let (_, leaf) = find_leaf(root, hash, block_device);
+        if try_put(leaf, &record, block_device) {
+            return root;
+        }
+        leaf
+    } else {
+        leaf
+    };
+    // chain an overflow block right after the leaf
+    let overflow = alloc();
+    let next = get_block_cache(leaf as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |leaf: &mut IndexLeaf| {
+            core::mem::replace(&mut leaf.next, overflow)
+        });
+    get_block_cache(overflow as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |overflow: &mut IndexLeaf| overflow.next = next);
+    assert!(try_put(overflow, &record, block_device));
+    root
+}
+
+/// Merge leaf `i + 1` under the root into leaf `i` if neither has overflow
+/// blocks and their records fit in one block, return the block of leaf `i + 1`
+fn merge_leaves(root: u32, i: usize, block_device: &Arc<dyn BlockDevice>) -> Option<u32> {
+    let (count, left, right) = get_block_cache(root as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |index_root: &IndexRoot| {
+            let count = index_root.count as usize;
+            let right = index_root.entries.get(i + 1).map_or(0, |entry| entry[1]);
+            (count, index_root.entries[i][1], right)
+        });
+    if i + 1 >= count {
+        return None;
+    }
+    let read_leaf = |block_id: u32| {
+        get_block_cache(block_id as usize, Arc::clone(block_device))
+            .lock()
+            .read(0, |leaf: &IndexLeaf| {
+                let used = (leaf.used as usize).min(LEAF_BYTES);
+                (leaf.next, Vec::from(&leaf.records[..used]))
+            })
+    };
+    let (left_next, left_records) = read_leaf(left);
+    let (right_next, right_records) = read_leaf(right);
+    if left_next != 0 || right_next != 0 || left_records.len() + right_records.len() > LEAF_BYTES {
+        return None;
+    }
+    get_block_cache(left as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |leaf: &mut IndexLeaf| {
+            let used = left_records.len();
+            leaf.records[used..used + right_records.len()].copy_from_slice(&right_records);
+            leaf.used += right_records.len() as u32;
+        });
+    get_block_cache(root as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |index_root: &mut IndexRoot| {
+            index_root.entries.copy_within(i + 2..count, i + 1);
+            index_root.entries[count - 1] = [0, 0];
+            index_root.count -= 1;
+        });
+    Some(right)
+}
+
+/// Remove `name` from the index rooted at block `root`, return the block it
+/// frees, which is unlinked and should be deallocated: an overflow block left
+/// empty, or a leaf merged into the one before it
+pub fn remove(root: u32, name: &str, block_device: &Arc<dyn BlockDevice>) -> Option<u32> {
+    if root == 0 {
+        return None;
+    }
+    let (i, leaf) = find_leaf(root, hash(name.as_bytes()), block_device);
+    let (block_id, offset, dirent, prev) = find_record(leaf, name, block_device)?;
+    let len = dirent.len(true);
+    let (empty, next) = get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |leaf: &mut IndexLeaf| {
+            let used = leaf.used as usize;
+            leaf.records.copy_within(offset + len..used, offset);
+            leaf.records[used - len..used].fill(0);
+            leaf.used -= len as u32;
+            (leaf.used == 0, leaf.next)
+        });
+    if empty && prev != 0 {
+        get_block_cache(prev as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |prev: &mut IndexLeaf| prev.next = next);
+        return Some(block_id);
+    }
+    if empty && next != 0 {
+        // the first overflow block moves into the empty leaf
+        let mut moved = [0u8; BLOCK_SZ];
+        get_block_cache(next as usize, Arc::clone(block_device))
+            .lock()
+            .read(0, |overflow: &[u8; BLOCK_SZ]| {
+                moved.copy_from_slice(overflow)
+            });
+        get_block_cache(leaf as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |leaf: &mut [u8; BLOCK_SZ]| leaf.copy_from_slice(&moved));
+        return Some(next);
+    }
+    if prev != 0 || next != 0 {
+        return None;
+    }
+    // a leaf without overflow blocks may fit in a neighbour
+    i.checked_sub(1)
+        .and_then(|left| merge_leaves(root, left, block_device))
+        .or_else(|| merge_leaves(root, i, block_device))
+}
+
+/// Point `name` to inode `inode_id` in the index rooted at block `root`,
+/// return whether it is found
+pub fn replace(root: u32, name: &str, inode_id: u32, block_device: &Arc<dyn BlockDevice>) -> bool {
+    if root == 0 {
+        return false;
+    }
+    let (_, leaf) = find_leaf(root, hash(name.as_bytes()), block_device);
+    let (block_id, offset, _, _) = match find_record(leaf, name, block_device) {
+        Some(found) => found,
+        None => return false,
+    };
+    get_block_cache(block_id as usize, Arc::clone(block_device))
+        .lock()
+        .modify(0, |leaf: &mut IndexLeaf| {
+            leaf.records[offset..offset + 4].copy_from_slice(&inode_id.to_ne_bytes());
+        });
+    true
+}
+
+/// Collect the blocks of the index rooted at block `root`, and the pointers
+/// that are not `valid`. Blocks that are not valid are not read.
+pub fn blocks(
+    root: u32,
+    block_device: &Arc<dyn BlockDevice>,
+    valid: &impl Fn(u32) -> bool,
+    blocks: &mut Vec<u32>,
+    bad: &mut Vec<u32>,
+) {
+    if root == 0 {
+        return;
+    }
+    if !valid(root) {
+        bad.push(root);
+        return;
+    }
+    blocks.push(root);
+    let leaves: Vec<u32> = get_block_cache(root as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |index_root: &IndexRoot| {
+            let count = (index_root.count as usize).min(ROOT_ENTRIES);
+            index_root.entries[..count]
+                .iter()
+                .map(|entry| entry[1])
+                .collect()
+        });
+    // a corrupted chain may loop or join another one
+    let mut seen = BTreeSet::new();
+    for leaf in leaves {
+        let mut block_id = leaf;
+        while block_id != 0 && seen.insert(block_id) {
+            if !valid(block_id) {
+                bad.push(block_id);
+                break;
+            }
+            blocks.push(block_id);
+            block_id = get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |leaf: &IndexLeaf| leaf.next);
+        }
+    }
+}
+
+/// Get all records of the index rooted at block `root`, `None` if the index
+/// is malformed: a block is not `valid` or used twice, the leaves are not
+/// sorted by hash, or a record is cut short or in the wrong leaf
+pub fn entries(
+    root: u32,
+    block_device: &Arc<dyn BlockDevice>,
+    valid: &impl Fn(u32) -> bool,
+) -> Option<Vec<DirEntry>> {
+    if root == 0 {
+        return Some(Vec::new());
+    }
+    if !valid(root) {
+        return None;
+    }
+    let leaves: Vec<[u32; 2]> = get_block_cache(root as usize, Arc::clone(block_device))
+        .lock()
+        .read(0, |index_root: &IndexRoot| {
+            let count = index_root.count as usize;
+            (1..=ROOT_ENTRIES)
+                .contains(&count)
+                .then(|| Vec::from(&index_root.entries[..count]))
+        })?;
+    if leaves[0][0] != 0 || leaves.windows(2).any(|pair| pair[0][0] >= pair[1][0]) {
+        return None;
+    }
+    let mut seen = BTreeSet::from([root]);
+    let mut dirents = Vec::new();
+    for (i, [start, leaf]) in leaves.iter().copied().enumerate() {
+        let end = leaves.get(i + 1).map(|entry| entry[0]);
+        let in_leaf = |hash: u32| hash >= start && end.is_none_or(|end| hash < end);
+        let mut block_id = leaf;
+        while block_id != 0 {
+            if !valid(block_id) || !seen.insert(block_id) {
+                return None;
+            }
+            block_id = get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |leaf: &IndexLeaf| {
+                    let records = records(leaf);
+                    let end = records
+                        .last()
+                        .map_or(0, |(offset, dirent)| offset + dirent.len(true));
+                    if end != leaf.used as usize {
+                        return None;
+                    }
+                    for (_, dirent) in records {
+                        if !record_hash(&dirent).is_some_and(&in_leaf) {
+                            return None;
+                        }
+                        dirents.push(dirent);
+                    }
+                    Some(leaf.next)
+                })?;
+        }
+    }
+    Some(dirents)
+}
@@ -1,9 +1,14 @@
 use super::{
-    block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DiskInode, DiskInodeType, Inode,
-    SuperBlock,
+    block_cache_release_device, block_cache_sync_all, get_block_cache, register_journal,
+    replay_journal, set_block_cache_capacity, unregister_journal, untracked, Bitmap, BlockDevice,
+    DiskInode, DiskInodeType, Inode, JournalHeader, SuperBlock, EFS_VERSION, FEATURE_BITMAP_EXT,
+    FEATURE_DIR_INDEX, FEATURE_EXTENTS, FEATURE_FREE_COUNTS, FEATURE_LONG_NAMES, JOURNAL_BLOCKS,
+    NAME_LENGTH_LIMIT, SHORT_NAME_LENGTH_LIMIT,
 };
 use crate::BLOCK_SZ;
-use alloc::sync::Arc;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use spin::Mutex;
 ///An easy file system on block
 pub struct EasyFileSystem {
@@ -15,9 +20,43 @@ pub struct EasyFileSystem {
     pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
     data_area_start_block: u32,
+    /// `FEATURE_*` flags of the super block
+    features: u32,
+    /// Number of free data blocks, kept in the super block with `FEATURE_FREE_COUNTS`
+    free_blocks: u32,
+    /// Number of free inodes, kept in the super block with `FEATURE_FREE_COUNTS`
+    free_inodes: u32,
+    /// Current time in seconds for inode timestamps
+    time_source: fn() -> u32,
+    /// Vfs inodes in use by inode id, so that lookups of one inode share it
+    inode_cache: Mutex<BTreeMap<u32, Weak<Inode>>>,
+    /// Number of opens of each open inode, see [`Inode::open`]
+    open_counts: BTreeMap<u32, u32>,
+    /// Open files with no link left, freed when they are last closed
+    orphans: BTreeSet<u32>,
 }
 
 type DataBlock = [u8; BLOCK_SZ];
+
+/// Usage of a filesystem, see [`EasyFileSystem::statfs`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    /// Size of a block in bytes
+    pub block_size: usize,
+    /// Number of blocks in the data area
+    pub total_blocks: u32,
+    /// Number of free blocks in the data area
+    pub free_blocks: u32,
+    /// Number of inodes
+    pub total_inodes: u32,
+    /// Number of free inodes
+    pub free_inodes: u32,
+}
+
+/// Time source of a filesystem that has not been given one
+fn no_time() -> u32 {
+    0
+}
 /// An easy fs over a block device
 impl EasyFileSystem {
     /// A data block of block size
@@ -25,26 +64,52 @@ impl EasyFileSystem {
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
+    ) -> Arc<Mutex<Self>> {
+        Self::create_with_features(
+            block_device,
+            total_blocks,
+            inode_bitmap_blocks,
+            FEATURE_LONG_NAMES | FEATURE_EXTENTS | FEATURE_FREE_COUNTS | FEATURE_DIR_INDEX,
+        )
+    }
+
+    /// Create a filesystem with the given `FEATURE_*` flags,
+    /// `0` for the layout of images made before features existed
+    pub fn create_with_features(
+        block_device: Arc<dyn BlockDevice>,
+        total_blocks: u32,
+        inode_bitmap_blocks: u32,
+        features: u32,
     ) -> Arc<Mutex<Self>> {
         // calculate block size of areas & create bitmaps
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
+        // the journal follows the super block
+        let journal_blocks = JOURNAL_BLOCKS;
+        let inode_bitmap = Bitmap::new(1 + journal_blocks as usize, inode_bitmap_blocks as usize);
         let inode_num = inode_bitmap.maximum();
         let inode_area_blocks =
             ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
+        let data_total_blocks = total_blocks - 1 - journal_blocks - inode_total_blocks;
         let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
-        let data_bitmap = Bitmap::new(
-            (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
+        let data_bitmap = Bitmap::with_bits(
+            (1 + journal_blocks + inode_total_blocks) as usize,
             data_bitmap_blocks as usize,
+            data_area_blocks as usize,
         );
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
             inode_bitmap,
             data_bitmap,
-            inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            inode_area_start_block: 1 + journal_blocks + inode_bitmap_blocks,
+            data_area_start_block: 1 + journal_blocks + inode_total_blocks + data_bitmap_blocks,
+            features,
+            free_blocks: data_area_blocks,
+            free_inodes: inode_num as u32,
+            time_source: no_time,
+            inode_cache: Mutex::new(BTreeMap::new()),
+            open_counts: BTreeMap::new(),
+            orphans: BTreeSet::new(),
         };
         // clear all blocks
         for i in 0..total_blocks {
@@ -62,47 +127,298 @@ impl EasyFileSystem {
             |super_block: &mut SuperBlock| {
                 super_block.initialize(
                     total_blocks,
+                    journal_blocks,
                     inode_bitmap_blocks,
                     inode_area_blocks,
                     data_bitmap_blocks,
                     data_area_blocks,
+                    features,
                 );
             },
         );
         // write back immediately
         // create a inode for root node "/"
-        assert_eq!(efs.alloc_inode(), 0);
+        assert_eq!(efs.alloc_inode(), Some(0));
         let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
         get_block_cache(root_inode_block_id as usize, Arc::clone(&block_device))
             .lock()
             .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-                disk_inode.initialize(DiskInodeType::Directory);
+                disk_inode.initialize(DiskInodeType::Directory, 0, efs.extents());
+                if efs.dir_index() {
+                    disk_inode.set_indexed();
+                }
             });
+        let efs = Arc::new(Mutex::new(efs));
+        // ".." of the root directory is the root itself
+        Self::root_inode(&efs).init_dir(0);
         block_cache_sync_all();
-        Arc::new(Mutex::new(efs))
+        block_device.write_block(1, JournalHeader::empty().as_bytes());
+        register_journal(&block_device, 1, journal_blocks);
+        efs
     }
 
-    /// Open a block device as a filesystem
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
-        // read SuperBlock
+    /// Open a block device as a filesystem, at most `cache_blocks` of its blocks
+    /// are held by the block cache, see `set_block_cache_capacity`.
+    /// A transaction committed but not yet written home is replayed first,
+    /// then the inodes left unlinked but not freed by a crash are freed.
+    pub fn open(block_device: Arc<dyn BlockDevice>, cache_blocks: usize) -> Arc<Mutex<Self>> {
+        set_block_cache_capacity(&block_device, cache_blocks);
+        // the journal is always right after the super block
         get_block_cache(0, Arc::clone(&block_device))
             .lock()
             .read(0, |super_block: &SuperBlock| {
                 assert!(super_block.is_valid(), "Error loading EFS!");
-                let inode_total_blocks =
-                    super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                let efs = Self {
-                    block_device,
-                    inode_bitmap: Bitmap::new(1, super_block.inode_bitmap_blocks as usize),
-                    data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
-                        super_block.data_bitmap_blocks as usize,
-                    ),
-                    inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-                Arc::new(Mutex::new(efs))
+                assert!(
+                    super_block.is_supported(),
+                    "Unsupported EFS version {} with features {:#x}, expect version {}",
+                    super_block.version,
+                    super_block.features,
+                    EFS_VERSION
+                );
+            });
+        replay_journal(&block_device, 1);
+        let efs = {
+            let super_block = get_block_cache(0, Arc::clone(&block_device));
+            let efs = super_block.lock().read(0, |super_block: &SuperBlock| {
+                register_journal(&block_device, 1, super_block.journal_blocks);
+                Self::from_super_block(Arc::clone(&block_device), super_block)
+            });
+            efs
+        };
+        Self::root_inode(&efs).free_orphans();
+        efs
+    }
+
+    /// Build the filesystem described by `super_block`
+    fn from_super_block(
+        block_device: Arc<dyn BlockDevice>,
+        super_block: &SuperBlock,
+    ) -> Arc<Mutex<Self>> {
+        let journal_blocks = super_block.journal_blocks;
+        let inode_total_blocks = super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
+        let mut efs = Self {
+            block_device,
+            inode_bitmap: Bitmap::new(
+                (1 + journal_blocks) as usize,
+                super_block.inode_bitmap_blocks as usize,
+            ),
+            data_bitmap: Bitmap::with_ext(
+                (1 + journal_blocks + inode_total_blocks) as usize,
+                super_block.data_bitmap_blocks as usize,
+                (super_block.total_blocks - super_block.data_bitmap_ext_blocks) as usize,
+                super_block.data_bitmap_ext_blocks as usize,
+                super_block.data_area_blocks as usize,
+            ),
+            inode_area_start_block: 1 + journal_blocks + super_block.inode_bitmap_blocks,
+            data_area_start_block: 1
+                + journal_blocks
+                + inode_total_blocks
+                + super_block.data_bitmap_blocks,
+            features: super_block.features,
+            free_blocks: super_block.free_blocks,
+            free_inodes: super_block.free_inodes,
+            time_source: no_time,
+            inode_cache: Mutex::new(BTreeMap::new()),
+            open_counts: BTreeMap::new(),
+            orphans: BTreeSet::new(),
+        };
+        if efs.features & FEATURE_FREE_COUNTS == 0 {
+            efs.count_free();
+        }
+        Arc::new(Mutex::new(efs))
+    }
+
+    /// Grow the filesystem to `total_blocks` blocks once the device is enlarged.
+    /// The new blocks extend the data area, and the data bitmap blocks they
+    /// need are taken from the end of the device, so nothing is moved.
+    /// Return false if the filesystem has more blocks than `total_blocks`.
+    /// It is not journaled, so the filesystem should not be in use elsewhere.
+    pub fn resize(&mut self, total_blocks: u32) -> bool {
+        let (old_total_blocks, bitmap_blocks, old_ext_blocks, old_area_blocks) =
+            get_block_cache(0, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |super_block: &SuperBlock| {
+                    (
+                        super_block.total_blocks,
+                        super_block.data_bitmap_blocks,
+                        super_block.data_bitmap_ext_blocks,
+                        super_block.data_area_blocks,
+                    )
+                });
+        if total_blocks <= old_total_blocks {
+            return total_blocks == old_total_blocks;
+        }
+        // the data area and the bitmap blocks at the end share the blocks
+        // after the data bitmap blocks before the data area
+        let rest = total_blocks - self.data_area_start_block;
+        let ext_blocks = rest.saturating_sub(bitmap_blocks * 4096).div_ceil(4097);
+        let area_blocks = rest - ext_blocks;
+        // the old bitmap blocks at the end become part of the data area,
+        // and the new ones may overlap them
+        let old_ext_start = old_total_blocks - old_ext_blocks;
+        let ext_start = total_blocks - ext_blocks;
+        let ext: Vec<DataBlock> = (old_ext_start..old_total_blocks)
+            .map(|block_id| {
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| *data_block)
             })
+            .collect();
+        for (i, block_id) in (ext_start..total_blocks).enumerate() {
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    *data_block = ext.get(i).copied().unwrap_or([0; BLOCK_SZ]);
+                });
+        }
+        self.data_bitmap = Bitmap::with_ext(
+            self.data_area_start_block as usize - bitmap_blocks as usize,
+            bitmap_blocks as usize,
+            ext_start as usize,
+            ext_blocks as usize,
+            area_blocks as usize,
+        );
+        self.data_bitmap.clear(
+            &self.block_device,
+            old_area_blocks as usize..area_blocks as usize,
+        );
+        // the bitmap must be on disk before the super block covers it
+        block_cache_sync_all();
+        if ext_blocks > 0 {
+            self.features |= FEATURE_BITMAP_EXT;
+        }
+        self.free_blocks += area_blocks - old_area_blocks;
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.total_blocks = total_blocks;
+                super_block.data_bitmap_ext_blocks = ext_blocks;
+                super_block.data_area_blocks = area_blocks;
+                super_block.features = self.features;
+                // the size the kernel last saw
+                if super_block.resized_from == 0 {
+                    super_block.resized_from = old_total_blocks;
+                }
+            });
+        self.store_free_counts();
+        block_cache_sync_all();
+        true
+    }
+
+    /// Get the number of blocks of the filesystem before it was grown by
+    /// [`Self::resize`], and forget it so that it is reported once
+    pub fn take_resized_from(&mut self) -> Option<u32> {
+        let super_block = get_block_cache(0, Arc::clone(&self.block_device));
+        let resized_from = super_block
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.resized_from);
+        if resized_from == 0 {
+            return None;
+        }
+        super_block
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.resized_from = 0
+            });
+        Some(resized_from)
+    }
+
+    /// Get the number of blocks of the device used by the filesystem
+    pub fn total_blocks(&self) -> u32 {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.total_blocks)
+    }
+
+    /// Set the clock used for inode timestamps, which are 0 without one
+    pub fn set_time_source(&mut self, time_source: fn() -> u32) {
+        self.time_source = time_source;
+    }
+
+    /// Current time in seconds
+    pub fn now(&self) -> u32 {
+        (self.time_source)()
+    }
+
+    /// Whether dirents have variable length, see `FEATURE_LONG_NAMES`
+    pub fn long_names(&self) -> bool {
+        self.features & FEATURE_LONG_NAMES != 0
+    }
+
+    /// Whether new inodes map their data blocks with extents, see `FEATURE_EXTENTS`
+    pub fn extents(&self) -> bool {
+        self.features & FEATURE_EXTENTS != 0
+    }
+
+    /// Whether new directories keep an index of their names, see `FEATURE_DIR_INDEX`,
+    /// which only directories mapped by extents can
+    pub fn dir_index(&self) -> bool {
+        self.features & FEATURE_DIR_INDEX != 0 && self.extents()
+    }
+
+    /// The max length of a name in this filesystem
+    pub fn name_limit(&self) -> usize {
+        if self.long_names() {
+            NAME_LENGTH_LIMIT
+        } else {
+            SHORT_NAME_LENGTH_LIMIT
+        }
+    }
+
+    /// Get the usage of the filesystem
+    pub fn statfs(&self) -> StatFs {
+        StatFs {
+            block_size: BLOCK_SZ,
+            total_blocks: self.data_bitmap.maximum() as u32,
+            free_blocks: self.free_blocks,
+            total_inodes: self.inode_bitmap.maximum() as u32,
+            free_inodes: self.free_inodes,
+        }
+    }
+
+    /// Count the free data blocks and inodes in the bitmaps, for an image that
+    /// does not keep them or whose bitmaps are changed directly such as by fsck
+    pub fn count_free(&mut self) {
+        (self.free_blocks, self.free_inodes) = self.bitmap_free_counts();
+        self.store_free_counts();
+    }
+
+    /// Get the numbers of free data blocks and free inodes in the bitmaps
+    pub(crate) fn bitmap_free_counts(&self) -> (u32, u32) {
+        // bits past the data area may be set in a corrupted bitmap
+        let used_blocks = self.data_bitmap.allocated(&self.block_device).len();
+        let used_inodes = self.inode_bitmap.allocated(&self.block_device).len();
+        (
+            self.data_bitmap.maximum().saturating_sub(used_blocks) as u32,
+            (self.inode_bitmap.maximum() - used_inodes) as u32,
+        )
+    }
+
+    /// Get the free counts kept in the super block, `None` without `FEATURE_FREE_COUNTS`
+    pub fn stored_free_counts(&self) -> Option<(u32, u32)> {
+        if self.features & FEATURE_FREE_COUNTS == 0 {
+            return None;
+        }
+        let counts = get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                (super_block.free_blocks, super_block.free_inodes)
+            });
+        Some(counts)
+    }
+
+    /// Write the free counts to the super block if it keeps them,
+    /// within the transaction of the allocation that changes them
+    fn store_free_counts(&self) {
+        if self.features & FEATURE_FREE_COUNTS == 0 {
+            return;
+        }
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.free_blocks = self.free_blocks;
+                super_block.free_inodes = self.free_inodes;
+            });
     }
 
     /// Get the root inode of the filesystem
@@ -121,37 +437,196 @@ impl EasyFileSystem {
         let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
         (
             // which block the inode locates at
-            block_id, 
+            block_id,
             // the offset bytes in that block
             (inode_id % inodes_per_block) as usize * inode_size,
         )
     }
 
+    /// Get inode id by the position of its disk inode
+    pub fn get_inode_id(&self, block_id: u32, block_offset: usize) -> u32 {
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+        (block_id - self.inode_area_start_block) * inodes_per_block
+            + (block_offset / inode_size) as u32
+    }
+
+    /// Get the vfs inode of `inode_id` in use, or cache the one made by `new`
+    pub(crate) fn cached_inode(&self, inode_id: u32, new: impl FnOnce() -> Inode) -> Arc<Inode> {
+        let mut inode_cache = self.inode_cache.lock();
+        if let Some(inode) = inode_cache.get(&inode_id).and_then(Weak::upgrade) {
+            return inode;
+        }
+        // inodes no longer in use are dropped whenever the cache doubles
+        if inode_cache.len().is_power_of_two() {
+            inode_cache.retain(|_, inode| inode.strong_count() > 0);
+        }
+        let inode = Arc::new(new());
+        inode_cache.insert(inode_id, Arc::downgrade(&inode));
+        inode
+    }
+
+    /// Count an open of inode `inode_id`
+    pub(crate) fn open_inode(&mut self, inode_id: u32) {
+        *self.open_counts.entry(inode_id).or_insert(0) += 1;
+    }
+
+    /// Count a close of inode `inode_id`,
+    /// return whether it was the last one of an orphan, which is to be freed
+    pub(crate) fn close_inode(&mut self, inode_id: u32) -> bool {
+        let count = self
+            .open_counts
+            .get_mut(&inode_id)
+            .expect("An inode is closed as many times as it is opened");
+        *count -= 1;
+        if *count > 0 {
+            return false;
+        }
+        self.open_counts.remove(&inode_id);
+        self.orphans.remove(&inode_id)
+    }
+
+    /// Whether inode `inode_id` is open
+    pub fn is_open(&self, inode_id: u32) -> bool {
+        self.open_counts.contains_key(&inode_id)
+    }
+
+    /// Keep the open inode `inode_id` with no link left until it is last closed
+    pub(crate) fn add_orphan(&mut self, inode_id: u32) {
+        assert!(self.is_open(inode_id));
+        self.orphans.insert(inode_id);
+    }
+
+    /// Get the open inodes with no link left
+    pub(crate) fn orphans(&self) -> &BTreeSet<u32> {
+        &self.orphans
+    }
+
+    /// Record inode `inode_id`, whose last link is removed, in the super block
+    /// until it is freed, so that [`Self::open`] frees it after a crash.
+    /// It is left to fsck if every slot is taken.
+    pub(crate) fn store_orphan(&self, inode_id: u32) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                if let Some(slot) = super_block.orphans.iter_mut().find(|slot| **slot == 0) {
+                    *slot = inode_id;
+                }
+            });
+    }
+
+    /// Forget inode `inode_id` recorded by `store_orphan` as it is freed
+    pub(crate) fn clear_orphan(&self, inode_id: u32) {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                if let Some(slot) = super_block
+                    .orphans
+                    .iter_mut()
+                    .find(|slot| **slot == inode_id)
+                {
+                    *slot = 0;
+                }
+            });
+    }
+
+    /// Get the inodes recorded by `store_orphan`
+    pub(crate) fn stored_orphans(&self) -> Vec<u32> {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| {
+                super_block
+                    .orphans
+                    .iter()
+                    .copied()
+                    .filter(|inode_id| *inode_id != 0)
+                    .collect()
+            })
+    }
+
+    /// Whether `inode_id` is an inode in use other than the root
+    pub(crate) fn is_allocated_inode(&self, inode_id: u32) -> bool {
+        inode_id != 0
+            && (inode_id as usize) < self.inode_bitmap.maximum()
+            && self
+                .inode_bitmap
+                .is_set(&self.block_device, inode_id as usize)
+    }
+
     /// Get data block by id
     pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
         self.data_area_start_block + data_block_id
     }
-    /// Allocate a new inode
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    /// Allocate a new inode, `None` if no inode is free
+    pub fn alloc_inode(&mut self) -> Option<u32> {
+        let inode_id = self.inode_bitmap.alloc(&self.block_device)? as u32;
+        self.free_inodes = self.free_inodes.saturating_sub(1);
+        self.store_free_counts();
+        Some(inode_id)
+    }
+
+    /// Deallocate an inode
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        self.inode_bitmap
+            .dealloc(&self.block_device, inode_id as usize);
+        self.free_inodes += 1;
+        self.store_free_counts();
+    }
+
+    /// Get the number of free data blocks
+    pub fn free_blocks(&self) -> u32 {
+        self.free_blocks
+    }
+
+    /// Get the number of free inodes
+    pub fn free_inodes(&self) -> u32 {
+        self.free_inodes
     }
 
-    /// Allocate a data block
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// Allocate a data block and clear it, `None` if no block is free
+    pub fn alloc_data(&mut self) -> Option<u32> {
+        self.alloc_data_run(1).map(|(block_id, _)| block_id)
+    }
+    /// Allocate a run of at most `count` consecutive data blocks and clear them,
+    /// return the id of the first block and the length of the run, `None` if
+    /// no block is free.
+    /// The blocks are free on disk until the allocation is committed,
+    /// so clearing them is not journaled.
+    pub fn alloc_data_run(&mut self, count: u32) -> Option<(u32, u32)> {
+        let (bit, len) = self
+            .data_bitmap
+            .alloc_run(&self.block_device, count as usize)?;
+        self.free_blocks = self.free_blocks.saturating_sub(len as u32);
+        self.store_free_counts();
+        let start = bit as u32 + self.data_area_start_block;
+        untracked(&self.block_device, || {
+            for block_id in start..start + len as u32 {
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| {
+                        data_block.iter_mut().for_each(|p| {
+                            *p = 0;
+                        })
+                    });
+            }
+        });
+        Some((start, len as u32))
     }
     /// Deallocate a data block
     pub fn dealloc_data(&mut self, block_id: u32) {
-        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                data_block.iter_mut().for_each(|p| {
-                    *p = 0;
-                })
-            });
         self.data_bitmap.dealloc(
             &self.block_device,
             (block_id - self.data_area_start_block) as usize,
-        )
+        );
+        self.free_blocks += 1;
+        self.store_free_counts();
+    }
+}
+
+impl Drop for EasyFileSystem {
+    /// The blocks of a filesystem no longer in use are written back and dropped from the cache
+    fn drop(&mut self) {
+        block_cache_release_device(&self.block_device);
+        unregister_journal(&self.block_device);
     }
 }
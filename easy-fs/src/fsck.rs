@@ -0,0 +1,632 @@
+//! Consistency check of an easy-fs image
+//!
+//! The tree is walked from the root directory and the inodes and blocks it
+//! reaches are compared with the bitmaps. Every directory but the root is
+//! named by exactly one dirent, its "." and ".." refer to itself and to its
+//! parent, and the link count of every inode is the number of dirents referring
+//! to it. The name index of a directory must hold exactly its dirents.
+
+use super::{
+    block_cache_sync_device, dir_index, get_block_cache, DirEntry, DiskInode, DiskInodeType,
+    EasyFileSystem, SuperBlock,
+};
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter, Result};
+
+/// A problem found by [`EasyFileSystem::fsck`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// The root inode is free or not a directory, nothing else is checked
+    BadRoot,
+    /// A block pointer of an inode out of the data area
+    BadBlockPointer {
+        /// The inode holding the pointer
+        inode_id: u32,
+        /// The block pointed to
+        block_id: u32,
+    },
+    /// A block used by two inodes, or twice by one
+    DuplicateBlock {
+        /// The inode found using the block the second time
+        inode_id: u32,
+        /// The block
+        block_id: u32,
+    },
+    /// A directory whose size is not where its last dirent ends
+    BadDirectorySize {
+        /// The directory
+        inode_id: u32,
+        /// Its size in bytes
+        size: u64,
+    },
+    /// A dirent whose name is not terminated or not UTF-8
+    BadDirentName {
+        /// The directory holding the dirent
+        dir_id: u32,
+    },
+    /// A dirent referring to an inode out of range, free or of no known type
+    DanglingDirent {
+        /// The directory holding the dirent
+        dir_id: u32,
+        /// Name of the dirent
+        name: String,
+        /// The inode referred to
+        inode_id: u32,
+    },
+    /// A name found more than once in a directory
+    DuplicateDirent {
+        /// The directory holding the dirents
+        dir_id: u32,
+        /// The name
+        name: String,
+    },
+    /// A dirent naming a directory that is already named by another dirent
+    DirectoryHardLink {
+        /// The directory holding the dirent
+        dir_id: u32,
+        /// Name of the dirent
+        name: String,
+        /// The directory referred to
+        inode_id: u32,
+    },
+    /// "." or ".." of a directory is missing or refers to a wrong inode
+    BadDotEntry {
+        /// The directory
+        dir_id: u32,
+        /// "." or ".."
+        name: &'static str,
+        /// The inode it refers to, `None` if it is missing
+        found: Option<u32>,
+        /// The inode it should refer to
+        expected: u32,
+    },
+    /// A directory whose name index is malformed or does not match its dirents
+    BadDirIndex {
+        /// The directory
+        dir_id: u32,
+    },
+    /// An inode whose link count is not the number of dirents referring to it
+    BadLinkCount {
+        /// The inode
+        inode_id: u32,
+        /// Its link count
+        found: u32,
+        /// The number of dirents referring to it
+        expected: u32,
+    },
+    /// An allocated inode not reachable from the root
+    OrphanInode {
+        /// The inode
+        inode_id: u32,
+    },
+    /// Blocks in use but free in the data bitmap
+    UnmarkedBlocks {
+        /// The first block
+        block_id: u32,
+        /// Number of consecutive blocks
+        count: u32,
+    },
+    /// Blocks allocated in the data bitmap but not in use
+    LeakedBlocks {
+        /// The first block
+        block_id: u32,
+        /// Number of consecutive blocks
+        count: u32,
+    },
+    /// Free counts in the super block that do not match the bitmaps
+    BadFreeCounts {
+        /// Free blocks and free inodes in the super block
+        found: (u32, u32),
+        /// Free blocks and free inodes in the bitmaps
+        expected: (u32, u32),
+    },
+}
+
+impl FsckProblem {
+    /// Whether [`EasyFileSystem::fsck`] repairs the problem when asked to
+    pub fn is_repairable(&self) -> bool {
+        !matches!(
+            self,
+            Self::BadRoot
+                | Self::BadBlockPointer { .. }
+                | Self::DuplicateBlock { .. }
+                | Self::BadDotEntry { found: None, .. }
+        )
+    }
+}
+
+impl Display for FsckProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::BadRoot => write!(f, "root inode is not a directory"),
+            Self::BadBlockPointer { inode_id, block_id } => {
+                write!(
+                    f,
+                    "inode {} points to block {} out of the data area",
+                    inode_id, block_id
+                )
+            }
+            Self::DuplicateBlock { inode_id, block_id } => {
+                write!(
+                    f,
+                    "inode {} uses block {} already in use",
+                    inode_id, block_id
+                )
+            }
+            Self::BadDirectorySize { inode_id, size } => {
+                write!(
+                    f,
+                    "directory {} has size {} not at the end of a dirent",
+                    inode_id, size
+                )
+            }
+            Self::BadDirentName { dir_id } => write!(f, "directory {} has a bad name", dir_id),
+            Self::DanglingDirent {
+                dir_id,
+                name,
+                inode_id,
+            } => write!(
+                f,
+                "directory {} entry {:?} refers to unused inode {}",
+                dir_id, name, inode_id
+            ),
+            Self::DuplicateDirent { dir_id, name } => {
+                write!(
+                    f,
+                    "directory {} has entry {:?} more than once",
+                    dir_id, name
+                )
+            }
+            Self::DirectoryHardLink {
+                dir_id,
+                name,
+                inode_id,
+            } => write!(
+                f,
+                "directory {} entry {:?} is another link to directory {}",
+                dir_id, name, inode_id
+            ),
+            Self::BadDotEntry {
+                dir_id,
+                name,
+                found: Some(found),
+                expected,
+            } => write!(
+                f,
+                "directory {} entry {:?} refers to inode {} instead of {}",
+                dir_id, name, found, expected
+            ),
+            Self::BadDotEntry {
+                dir_id,
+                name,
+                found: None,
+                ..
+            } => write!(f, "directory {} has no entry {:?}", dir_id, name),
+            Self::BadDirIndex { dir_id } => {
+                write!(
+                    f,
+                    "directory {} has an index not matching its entries",
+                    dir_id
+                )
+            }
+            Self::BadLinkCount {
+                inode_id,
+                found,
+                expected,
+            } => write!(
+                f,
+                "inode {} has link count {} but {} links",
+                inode_id, found, expected
+            ),
+            Self::OrphanInode { inode_id } => {
+                write!(f, "inode {} is allocated but unreachable", inode_id)
+            }
+            Self::UnmarkedBlocks { block_id, count } => {
+                write!(
+                    f,
+                    "blocks {}..{} are in use but free",
+                    block_id,
+                    block_id + count
+                )
+            }
+            Self::LeakedBlocks { block_id, count } => {
+                write!(
+                    f,
+                    "blocks {}..{} are allocated but unused",
+                    block_id,
+                    block_id + count
+                )
+            }
+            Self::BadFreeCounts { found, expected } => {
+                write!(
+                    f,
+                    "super block counts {} free blocks and {} free inodes, the bitmaps {} and {}",
+                    found.0, found.1, expected.0, expected.1
+                )
+            }
+        }
+    }
+}
+
+/// Owner of a data block that is not in use
+const NO_OWNER: u32 = u32::MAX;
+
+/// State of a check
+struct Checker<'a> {
+    fs: &'a EasyFileSystem,
+    repair: bool,
+    problems: Vec<FsckProblem>,
+    /// allocated inodes
+    allocated: Vec<bool>,
+    /// inodes reached from the root
+    reachable: Vec<bool>,
+    /// dirents kept referring to each inode
+    links: Vec<u32>,
+    /// inode using each block of the data area
+    owners: Vec<u32>,
+}
+
+impl Checker<'_> {
+    /// Whether `inode_id` refers to an allocated inode of a known type
+    fn is_valid_inode(&self, inode_id: u32) -> bool {
+        if !self
+            .allocated
+            .get(inode_id as usize)
+            .copied()
+            .unwrap_or(false)
+        {
+            return false;
+        }
+        let (block_id, block_offset) = self.fs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, self.fs.block_device.clone())
+            .lock()
+            .read(block_offset + DiskInode::type_offset(), |type_: &u8| {
+                DiskInodeType::is_valid(*type_)
+            })
+    }
+    /// Call a function over a disk inode to read it
+    fn read_disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.fs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, self.fs.block_device.clone())
+            .lock()
+            .read(block_offset, f)
+    }
+    /// Call a function over a disk inode to modify it
+    fn modify_disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.fs.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, self.fs.block_device.clone())
+            .lock()
+            .modify(block_offset, f)
+    }
+    /// Whether `block_id` is in the data area
+    fn is_data_block(&self, block_id: u32) -> bool {
+        let data_start = self.fs.get_data_block_id(0);
+        (data_start..data_start + self.owners.len() as u32).contains(&block_id)
+    }
+    /// Record the blocks used by `inode_id`
+    fn use_blocks(&mut self, inode_id: u32) {
+        let data_start = self.fs.get_data_block_id(0);
+        let (blocks, bad) = self.read_disk_inode(inode_id, |disk_inode| {
+            disk_inode.blocks(&self.fs.block_device, |block_id| {
+                self.is_data_block(block_id)
+            })
+        });
+        for block_id in bad {
+            self.problems
+                .push(FsckProblem::BadBlockPointer { inode_id, block_id });
+        }
+        for block_id in blocks {
+            let owner = &mut self.owners[(block_id - data_start) as usize];
+            if *owner == NO_OWNER {
+                *owner = inode_id;
+            } else {
+                self.problems
+                    .push(FsckProblem::DuplicateBlock { inode_id, block_id });
+            }
+        }
+    }
+    /// Check the dirents of directory `dir_id` whose parent is `parent_id`,
+    /// return the directories found in it
+    fn check_dir(&mut self, dir_id: u32, parent_id: u32) -> Vec<u32> {
+        let block_device = self.fs.block_device.clone();
+        let long_names = self.fs.long_names();
+        let valid = |block_id| self.is_data_block(block_id);
+        let (size, dirents, end, index, readable) = self.read_disk_inode(dir_id, |disk_inode| {
+            // the dirents behind a bad block pointer are not read
+            let readable = disk_inode.blocks(&block_device, valid).1.is_empty();
+            let mut dirents: Vec<DirEntry> = Vec::new();
+            let mut offset = 0;
+            while let Some(dirent) =
+                DirEntry::read_valid(disk_inode, offset, long_names, &block_device, &valid)
+            {
+                offset += dirent.len(long_names);
+                if !dirent.is_deleted() {
+                    dirents.push(dirent);
+                }
+            }
+            let index = disk_inode.index_root().map(|root| {
+                dir_index::entries(root, &block_device, &|block_id| {
+                    self.is_data_block(block_id)
+                })
+            });
+            (disk_inode.size, dirents, offset, index, readable)
+        });
+        // the index is compared with the dirents as they are, bad ones included
+        let indexed = index.is_some();
+        let index_matches = match index {
+            Some(Some(mut entries)) => {
+                let mut expected = dirents.clone();
+                expected.sort();
+                entries.sort();
+                entries == expected
+            }
+            Some(None) => false,
+            None => true,
+        };
+        let mut changed = false;
+        if end as u64 != size {
+            self.problems.push(FsckProblem::BadDirectorySize {
+                inode_id: dir_id,
+                size,
+            });
+            changed = true;
+        }
+        let mut kept: Vec<DirEntry> = Vec::new();
+        let mut names: BTreeSet<String> = BTreeSet::new();
+        let mut subdirs = Vec::new();
+        for dirent in dirents {
+            let name = match dirent.try_name() {
+                Some(name) => String::from(name),
+                None => {
+                    self.problems.push(FsckProblem::BadDirentName { dir_id });
+                    changed = true;
+                    continue;
+                }
+            };
+            let inode_id = dirent.inode_id();
+            if !names.insert(name.clone()) {
+                self.problems
+                    .push(FsckProblem::DuplicateDirent { dir_id, name });
+                changed = true;
+                continue;
+            }
+            if name == "." || name == ".." {
+                let (name, expected) = if name == "." {
+                    (".", dir_id)
+                } else {
+                    ("..", parent_id)
+                };
+                if inode_id != expected {
+                    self.problems.push(FsckProblem::BadDotEntry {
+                        dir_id,
+                        name,
+                        found: Some(inode_id),
+                        expected,
+                    });
+                    changed = true;
+                }
+                kept.push(DirEntry::new(name, expected));
+                continue;
+            }
+            if !self.is_valid_inode(inode_id) {
+                self.problems.push(FsckProblem::DanglingDirent {
+                    dir_id,
+                    name,
+                    inode_id,
+                });
+                changed = true;
+                continue;
+            }
+            let is_dir = self.read_disk_inode(inode_id, |disk_inode| disk_inode.is_dir());
+            let reached = core::mem::replace(&mut self.reachable[inode_id as usize], true);
+            if is_dir && reached {
+                self.problems.push(FsckProblem::DirectoryHardLink {
+                    dir_id,
+                    name,
+                    inode_id,
+                });
+                changed = true;
+                continue;
+            }
+            if is_dir {
+                subdirs.push(inode_id);
+            } else if !reached {
+                self.use_blocks(inode_id);
+            }
+            kept.push(dirent);
+        }
+        for (name, expected) in [(".", dir_id), ("..", parent_id)] {
+            if !names.contains(name) {
+                self.problems.push(FsckProblem::BadDotEntry {
+                    dir_id,
+                    name,
+                    found: None,
+                    expected,
+                });
+            }
+        }
+        if !index_matches {
+            self.problems.push(FsckProblem::BadDirIndex { dir_id });
+        }
+        // a directory with bad block pointers is left as it is
+        let repair = self.repair && readable;
+        if (changed || !index_matches) && indexed && repair {
+            // the directory is then looked up linearly, and the blocks of the
+            // index are freed as leaked blocks
+            self.modify_disk_inode(dir_id, |disk_inode| disk_inode.drop_index());
+        }
+        if changed && repair {
+            // the dirents are written back packed, blocks past the new size are
+            // freed as leaked blocks
+            let bytes: Vec<u8> = kept
+                .iter()
+                .flat_map(|dirent| dirent.to_bytes(long_names))
+                .collect();
+            self.modify_disk_inode(dir_id, |disk_inode| {
+                disk_inode.size = bytes.len() as u64;
+                disk_inode.write_at(0, &bytes, &block_device);
+            });
+        }
+        for dirent in &kept {
+            if let Some(links) = self.links.get_mut(dirent.inode_id() as usize) {
+                *links += 1;
+            }
+        }
+        self.use_blocks(dir_id);
+        subdirs
+    }
+    /// Compare the link counts of the inodes reached with the dirents referring to them
+    fn check_link_counts(&mut self) {
+        for inode_id in 0..self.reachable.len() as u32 {
+            if !self.reachable[inode_id as usize] {
+                continue;
+            }
+            let expected = self.links[inode_id as usize];
+            let found = self.read_disk_inode(inode_id, |disk_inode| disk_inode.nlink);
+            if found != expected {
+                self.problems.push(FsckProblem::BadLinkCount {
+                    inode_id,
+                    found,
+                    expected,
+                });
+                if self.repair {
+                    self.modify_disk_inode(inode_id, |disk_inode| disk_inode.nlink = expected);
+                }
+            }
+        }
+    }
+    /// Compare the inode bitmap with the inodes reached
+    fn check_inode_bitmap(&mut self) {
+        for inode_id in 0..self.allocated.len() as u32 {
+            if self.allocated[inode_id as usize] && !self.reachable[inode_id as usize] {
+                self.problems.push(FsckProblem::OrphanInode { inode_id });
+                if self.repair {
+                    self.fs
+                        .inode_bitmap
+                        .dealloc(&self.fs.block_device, inode_id as usize);
+                    // so that it is not freed again once reused
+                    self.fs.clear_orphan(inode_id);
+                }
+            }
+        }
+    }
+    /// Compare the data bitmap with the blocks in use,
+    /// consecutive blocks with the same problem are reported together
+    fn check_data_bitmap(&mut self) {
+        let bits = self.fs.data_bitmap.allocated(&self.fs.block_device);
+        // bits past the data area may be set in a corrupted bitmap
+        let len = bits.last().map_or(0, |bit| bit + 1);
+        let mut allocated = vec![false; self.fs.data_bitmap.maximum().max(len)];
+        for bit in bits {
+            allocated[bit] = true;
+        }
+        let data_start = self.fs.get_data_block_id(0);
+        // bits past the data area are never in use
+        let in_use = |bit: usize| self.owners.get(bit).is_some_and(|owner| *owner != NO_OWNER);
+        let mut runs: Vec<FsckProblem> = Vec::new();
+        for (bit, allocated) in allocated.into_iter().enumerate() {
+            let block_id = data_start + bit as u32;
+            let problem = match (allocated, in_use(bit)) {
+                (false, true) => FsckProblem::UnmarkedBlocks { block_id, count: 1 },
+                (true, false) => FsckProblem::LeakedBlocks { block_id, count: 1 },
+                _ => continue,
+            };
+            if self.repair {
+                if allocated {
+                    self.fs.data_bitmap.dealloc(&self.fs.block_device, bit);
+                } else {
+                    self.fs.data_bitmap.set(&self.fs.block_device, bit);
+                }
+            }
+            match (runs.last_mut(), problem) {
+                (
+                    Some(FsckProblem::UnmarkedBlocks {
+                        block_id: first,
+                        count,
+                    }),
+                    FsckProblem::UnmarkedBlocks { .. },
+                )
+                | (
+                    Some(FsckProblem::LeakedBlocks {
+                        block_id: first,
+                        count,
+                    }),
+                    FsckProblem::LeakedBlocks { .. },
+                ) if *first + *count == block_id => *count += 1,
+                (_, problem) => runs.push(problem),
+            }
+        }
+        self.problems.append(&mut runs);
+    }
+}
+
+impl EasyFileSystem {
+    /// Check the filesystem and return the problems found. If `repair` is set,
+    /// the problems that are [repairable](FsckProblem::is_repairable) are repaired:
+    /// bad dirents are removed, unreachable inodes are freed, the data
+    /// bitmap is made to match the blocks in use, link counts are set to the
+    /// dirents referring to the inodes, and the free counts are
+    /// counted again from the bitmaps. Open files whose last link is removed
+    /// are in use until they are closed.
+    ///
+    /// The filesystem must not be in use meanwhile, and the repairs are not journaled.
+    pub fn fsck(&mut self, repair: bool) -> Vec<FsckProblem> {
+        let data_area_blocks = get_block_cache(0, self.block_device.clone())
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.data_area_blocks);
+        // the free counts are compared with the bitmaps before those are repaired
+        let stored_free_counts = self.stored_free_counts();
+        let free_counts = self.bitmap_free_counts();
+        let mut allocated = vec![false; self.inode_bitmap.maximum()];
+        for bit in self.inode_bitmap.allocated(&self.block_device) {
+            allocated[bit] = true;
+        }
+        let mut checker = Checker {
+            fs: self,
+            repair,
+            problems: Vec::new(),
+            reachable: vec![false; allocated.len()],
+            links: vec![0; allocated.len()],
+            allocated,
+            owners: vec![NO_OWNER; data_area_blocks as usize],
+        };
+        if !checker.is_valid_inode(0)
+            || !checker.read_disk_inode(0, |disk_inode| disk_inode.is_dir())
+        {
+            return vec![FsckProblem::BadRoot];
+        }
+        // directories are checked once each, so the walk ends even on a corrupted tree
+        checker.reachable[0] = true;
+        let mut queue = VecDeque::from([(0, 0)]);
+        while let Some((dir_id, parent_id)) = queue.pop_front() {
+            for subdir in checker.check_dir(dir_id, parent_id) {
+                queue.push_back((subdir, dir_id));
+            }
+        }
+        // open files with no link left are still in use
+        for inode_id in checker.fs.orphans().clone() {
+            if checker.is_valid_inode(inode_id) {
+                checker.reachable[inode_id as usize] = true;
+                checker.use_blocks(inode_id);
+            }
+        }
+        checker.check_link_counts();
+        checker.check_inode_bitmap();
+        checker.check_data_bitmap();
+        let mut problems = checker.problems;
+        if let Some(found) = stored_free_counts.filter(|found| *found != free_counts) {
+            problems.push(FsckProblem::BadFreeCounts {
+                found,
+                expected: free_counts,
+            });
+        }
+        if repair {
+            self.count_free();
+            block_cache_sync_device(&self.block_device);
+        }
+        problems
+    }
+}
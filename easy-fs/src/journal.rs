@@ -0,0 +1,285 @@
+//! Write-ahead journal of metadata blocks
+//!
+//! Blocks modified inside a [`Transaction`] stay in the block cache until the
+//! outermost transaction ends. They are then written to the journal, the journal
+//! header is written as the commit point, and only after that they are written
+//! to their home locations. File data is not journaled, but it is written back
+//! before the metadata referring to it is committed. A block of the running
+//! transaction evicted from the cache is written to its place in the journal
+//! early, and loaded from there until the transaction is committed.
+
+use super::{block_cache_sync_device, get_block_cache, BlockDevice, JournalHeader, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::*;
+use log::error;
+use spin::Mutex;
+
+/// A data block
+type DataBlock = [u8; BLOCK_SZ];
+
+/// Journal of a block device and its running transaction
+struct Journal {
+    block_device: Arc<dyn BlockDevice>,
+    /// block id of the journal header
+    start_block: usize,
+    /// max number of blocks logged in a commit
+    capacity: usize,
+    /// nesting depth of running transactions
+    depth: usize,
+    /// whether modified blocks join the running transaction
+    tracking: bool,
+    /// blocks modified by the running transaction, the `i`th one is logged
+    /// in the block `i + 1` of the journal
+    modified: Vec<usize>,
+    /// blocks of `modified` already written to the journal and evicted from the cache
+    spilled: Vec<usize>,
+}
+
+lazy_static! {
+    static ref JOURNALS: Mutex<Vec<Journal>> = Mutex::new(Vec::new());
+}
+
+/// Journals are found by the address of their device like block caches
+fn device_key(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
+/// Use `journal_blocks` blocks from `start_block` of `block_device` as its journal
+pub fn register_journal(
+    block_device: &Arc<dyn BlockDevice>,
+    start_block: usize,
+    journal_blocks: u32,
+) {
+    let mut journals = JOURNALS.lock();
+    let key = device_key(block_device);
+    journals.retain(|journal| device_key(&journal.block_device) != key);
+    journals.push(Journal {
+        block_device: Arc::clone(block_device),
+        start_block,
+        capacity: JournalHeader::capacity(journal_blocks),
+        depth: 0,
+        tracking: true,
+        modified: Vec::new(),
+        spilled: Vec::new(),
+    });
+}
+
+/// Forget the journal of `block_device` once it is no longer used
+pub fn unregister_journal(block_device: &Arc<dyn BlockDevice>) {
+    let key = device_key(block_device);
+    JOURNALS
+        .lock()
+        .retain(|journal| device_key(&journal.block_device) != key);
+}
+
+/// Record that `block_id` of the device at `device` is modified,
+/// return whether it joins a running transaction
+pub fn track_block(device: usize, block_id: usize) -> bool {
+    let mut journals = JOURNALS.lock();
+    match journals
+        .iter_mut()
+        .find(|journal| device_key(&journal.block_device) == device)
+    {
+        Some(journal) if journal.depth > 0 && journal.tracking => {
+            journal.modified.push(block_id);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Write `block`, the content of `block_id` of the device at `device` modified by the
+/// running transaction, to its place in the journal so that it can be evicted from
+/// the cache. Return whether it is written, not if the transaction outgrows the journal.
+pub fn spill_block(device: usize, block_id: usize, block: &DataBlock) -> bool {
+    let mut journals = JOURNALS.lock();
+    let journal = match journals
+        .iter_mut()
+        .find(|journal| device_key(&journal.block_device) == device)
+    {
+        Some(journal) => journal,
+        None => return false,
+    };
+    match journal.modified.iter().position(|id| *id == block_id) {
+        Some(i) if i < journal.capacity => {
+            journal
+                .block_device
+                .write_block(journal.start_block + 1 + i, block);
+            journal.spilled.push(block_id);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Get where to load `block_id` of the device at `device` from if it is written to
+/// the journal by `spill_block`, it is then no longer taken as evicted
+pub fn take_spilled(device: usize, block_id: usize) -> Option<usize> {
+    let mut journals = JOURNALS.lock();
+    let journal = journals
+        .iter_mut()
+        .find(|journal| device_key(&journal.block_device) == device)?;
+    let spilled = journal.spilled.iter().position(|id| *id == block_id)?;
+    journal.spilled.swap_remove(spilled);
+    let i = journal.modified.iter().position(|id| *id == block_id)?;
+    Some(journal.start_block + 1 + i)
+}
+
+/// Run `f` with blocks it modifies kept out of the running transaction
+pub fn untracked<V>(block_device: &Arc<dyn BlockDevice>, f: impl FnOnce() -> V) -> V {
+    let key = device_key(block_device);
+    let set_tracking = |tracking: bool| {
+        let mut journals = JOURNALS.lock();
+        journals
+            .iter_mut()
+            .find(|journal| device_key(&journal.block_device) == key)
+            .map(|journal| core::mem::replace(&mut journal.tracking, tracking))
+    };
+    let old = set_tracking(false);
+    let ret = f();
+    if let Some(old) = old {
+        set_tracking(old);
+    }
+    ret
+}
+
+/// Replay the committed transaction left in the journal at `start_block`
+/// and clear it, return the number of blocks replayed
+pub fn replay_journal(block_device: &Arc<dyn BlockDevice>, start_block: usize) -> usize {
+    let mut header = JournalHeader::empty();
+    block_device.read_block(start_block, header.as_bytes_mut());
+    let logged = header.logged_blocks();
+    if logged.is_empty() {
+        return 0;
+    }
+    let mut logged_block = [0u8; BLOCK_SZ];
+    for (i, block_id) in logged.iter().enumerate() {
+        block_device.read_block(start_block + 1 + i, &mut logged_block);
+        // through the cache, so that no stale copy of the block is left in it
+        get_block_cache(*block_id as usize, Arc::clone(block_device))
+            .lock()
+            .modify(0, |block: &mut DataBlock| *block = logged_block);
+    }
+    block_cache_sync_device(block_device);
+    block_device.write_block(start_block, JournalHeader::empty().as_bytes());
+    logged.len()
+}
+
+/// Error of a transaction modifying more blocks than its journal holds
+#[derive(Debug)]
+struct TransactionTooLarge;
+
+/// A group of metadata updates that reach the disk all or none.
+/// Transactions nest, and are committed when the outermost one is dropped.
+pub struct Transaction {
+    device: usize,
+}
+
+impl Transaction {
+    /// Begin a transaction on `block_device`, it does nothing if the device has no journal
+    pub fn begin(block_device: &Arc<dyn BlockDevice>) -> Self {
+        let device = device_key(block_device);
+        let mut journals = JOURNALS.lock();
+        if let Some(journal) = journals
+            .iter_mut()
+            .find(|journal| device_key(&journal.block_device) == device)
+        {
+            journal.depth += 1;
+        }
+        Self { device }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        let mut journals = JOURNALS.lock();
+        let journal = match journals
+            .iter_mut()
+            .find(|journal| device_key(&journal.block_device) == self.device)
+        {
+            Some(journal) => journal,
+            None => return,
+        };
+        journal.depth -= 1;
+        if journal.depth > 0 || journal.modified.is_empty() {
+            return;
+        }
+        // the blocks stay in the journal while committed, as they may be spilled
+        let modified = journal.modified.clone();
+        let block_device = Arc::clone(&journal.block_device);
+        let (start_block, capacity) = (journal.start_block, journal.capacity);
+        // block caches are locked below and they may track blocks
+        drop(journals);
+        if commit(&block_device, start_block, capacity, &modified).is_err() {
+            // callers split their work into transactions that fit, see `Inode::allocate`,
+            // the blocks are written in place as if the device had no journal
+            error!(
+                "A transaction of {} blocks does not fit in the journal of {}",
+                modified.len(),
+                capacity
+            );
+            for block_id in modified.iter() {
+                get_block_cache(*block_id, Arc::clone(&block_device))
+                    .lock()
+                    .checkpoint();
+            }
+        }
+        let mut journals = JOURNALS.lock();
+        if let Some(journal) = journals
+            .iter_mut()
+            .find(|journal| device_key(&journal.block_device) == self.device)
+        {
+            journal.modified.clear();
+            journal.spilled.clear();
+        }
+    }
+}
+
+/// Whether `block_id` of the device at `device` is written to the journal by `spill_block`
+fn is_spilled(device: usize, block_id: usize) -> bool {
+    JOURNALS
+        .lock()
+        .iter()
+        .find(|journal| device_key(&journal.block_device) == device)
+        .is_some_and(|journal| journal.spilled.contains(&block_id))
+}
+
+/// Write `modified` blocks through the journal at `start_block`,
+/// fail with `TransactionTooLarge` if there are more than `capacity` of them
+fn commit(
+    block_device: &Arc<dyn BlockDevice>,
+    start_block: usize,
+    capacity: usize,
+    modified: &[usize],
+) -> Result<(), TransactionTooLarge> {
+    // ordered: data referred to by the new metadata reaches the disk first
+    block_cache_sync_device(block_device);
+    if modified.len() > capacity {
+        return Err(TransactionTooLarge);
+    }
+    // one block is cached at a time, the others may be spilled meanwhile
+    let device = device_key(block_device);
+    for (i, block_id) in modified.iter().enumerate() {
+        if !is_spilled(device, *block_id) {
+            let block = get_block_cache(*block_id, Arc::clone(block_device))
+                .lock()
+                .read(0, |block: &DataBlock| *block);
+            block_device.write_block(start_block + 1 + i, &block);
+        }
+    }
+    block_device.write_block(start_block, JournalHeader::committed(modified).as_bytes());
+    for (i, block_id) in modified.iter().enumerate() {
+        if is_spilled(device, *block_id) {
+            let mut block = [0u8; BLOCK_SZ];
+            block_device.read_block(start_block + 1 + i, &mut block);
+            block_device.write_block(*block_id, &block);
+        } else {
+            get_block_cache(*block_id, Arc::clone(block_device))
+                .lock()
+                .checkpoint();
+        }
+    }
+    block_device.write_block(start_block, JournalHeader::empty().as_bytes());
+    Ok(())
+}
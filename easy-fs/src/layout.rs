@@ -1,319 +1,1326 @@
-use super::{get_block_cache, BlockDevice, BLOCK_SZ};
+use super::{dir_index, get_block_cache, BlockDevice, BLOCK_SZ};
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
 
 /// Magic number for sanity check
-const EFS_MAGIC: u32 = 0x3b800001;
+pub const EFS_MAGIC: u32 = 0x3b800001;
+/// Version of the on-disk layout, bumped on incompatible changes
+/// 2: timestamps, mode and owner in `DiskInode`
+/// 3: metadata journal after the super block
+/// 4: 64-bit sizes and a triple indirect block in `DiskInode`
+/// 5: deleted dirents left in place, see `DirEntry::deleted`
+/// 6: link count in `DiskInode` in place of its last direct block
+pub const EFS_VERSION: u32 = 6;
+/// Magic number of a committed journal header
+const JOURNAL_MAGIC: u32 = 0x4a524e4c;
+/// Number of blocks of the journal, header included
+pub const JOURNAL_BLOCKS: u32 = 64;
+/// The max number of blocks logged by a journal header
+const JOURNAL_HEADER_SLOTS: usize = BLOCK_SZ / 4 - 2;
+/// The max number of orphans recorded in the super block
+pub const ORPHAN_SLOTS: usize = 64;
 /// The max number of direct inodes
-const INODE_DIRECT_COUNT: usize = 28;
+const INODE_DIRECT_COUNT: usize = 20;
 /// The max length of inode name
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 255;
+/// The max length of inode name in a fixed-length dirent
+pub const SHORT_NAME_LENGTH_LIMIT: usize = 27;
+/// Feature of a filesystem whose dirents have variable length,
+/// so that names may have up to `NAME_LENGTH_LIMIT` bytes
+pub const FEATURE_LONG_NAMES: u32 = 1;
+/// Feature of a filesystem whose new inodes map their data blocks with extents
+pub const FEATURE_EXTENTS: u32 = 2;
+/// Feature of a filesystem whose super block keeps the free block and inode counts,
+/// which are counted from the bitmaps on open otherwise
+pub const FEATURE_FREE_COUNTS: u32 = 4;
+/// Feature of a filesystem whose new directories mapped by extents keep
+/// a hashed index of their names, see `dir_index`
+pub const FEATURE_DIR_INDEX: u32 = 8;
+/// Feature of a filesystem grown after it was made, whose data bitmap goes on
+/// in the `data_bitmap_ext_blocks` blocks at the end of the device
+pub const FEATURE_BITMAP_EXT: u32 = 16;
+/// Features understood by this implementation
+const SUPPORTED_FEATURES: u32 = FEATURE_LONG_NAMES
+    | FEATURE_EXTENTS
+    | FEATURE_FREE_COUNTS
+    | FEATURE_DIR_INDEX
+    | FEATURE_BITMAP_EXT;
 /// The max number of indirect1 inodes
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 /// The max number of indirect2 inodes
 const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
+/// The max number of indirect3 inodes
+const INODE_INDIRECT3_COUNT: usize = INODE_INDIRECT2_COUNT * INODE_INDIRECT1_COUNT;
 /// The upper bound of direct inode index
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 /// The upper bound of indirect1 inode index
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 /// The upper bound of indirect2 inode indexs
-#[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// The upper bound of indirect3 inode indexs
+const INDIRECT3_BOUND: usize = INDIRECT2_BOUND + INODE_INDIRECT3_COUNT;
+/// Flag of a disk inode whose data blocks are mapped by extents
+const INODE_EXTENTS: u8 = 1;
+/// Flag of a directory mapped by extents whose names are indexed
+const INODE_INDEXED: u8 = 2;
+/// The number of extents kept in a disk inode in place of its direct blocks
+const INLINE_EXTENTS: usize = INODE_DIRECT_COUNT / 3;
+/// The number of extents in an extent block
+const BLOCK_EXTENTS: usize = BLOCK_SZ / core::mem::size_of::<Extent>();
+/// The max number of extents of a disk inode
+const MAX_EXTENTS: usize = INLINE_EXTENTS + INODE_INDIRECT1_COUNT * BLOCK_EXTENTS;
+/// The number of extents a disk inode grows to. Inserting an extent moves the
+/// ones after it, so that all extent blocks may join one transaction, and
+/// 32 of them leave room in the journal for the bitmap blocks of the step.
+const EXTENT_LIMIT: usize = INLINE_EXTENTS + 32 * BLOCK_EXTENTS;
+/// The max size of a file in bytes, about 1 GiB
+pub const MAX_FILE_SIZE: u64 = (INDIRECT3_BOUND * BLOCK_SZ) as u64;
 /// Super block of a filesystem
 #[repr(C)]
 pub struct SuperBlock {
     magic: u32,
+    /// Version of the layout, `EFS_VERSION`
+    pub version: u32,
+    /// Number of blocks of the filesystem
     pub total_blocks: u32,
+    /// Number of blocks of the journal, right after the super block
+    pub journal_blocks: u32,
+    /// Number of blocks of the inode bitmap
     pub inode_bitmap_blocks: u32,
+    /// Number of blocks of the inode area
     pub inode_area_blocks: u32,
+    /// Number of blocks of the data bitmap
     pub data_bitmap_blocks: u32,
+    /// Number of blocks of the data area
     pub data_area_blocks: u32,
+    /// `FEATURE_*` flags, added after the other fields so that an image
+    /// without it reads as one with no feature
+    pub features: u32,
+    /// Number of free data blocks, kept with `FEATURE_FREE_COUNTS`
+    pub free_blocks: u32,
+    /// Number of free inodes, kept with `FEATURE_FREE_COUNTS`
+    pub free_inodes: u32,
+    /// Number of data bitmap blocks at the end of the device, with `FEATURE_BITMAP_EXT`
+    pub data_bitmap_ext_blocks: u32,
+    /// Number of blocks before the filesystem was last grown offline, 0 if it
+    /// was not or the kernel has already been told
+    pub resized_from: u32,
+    /// Inodes whose last link is removed but which are not freed yet,
+    /// 0 for a free slot, see `EasyFileSystem::open`
+    pub orphans: [u32; ORPHAN_SLOTS],
 }
 
 impl Debug for SuperBlock {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
         f.debug_struct("SuperBlock")
+            .field("version", &self.version)
             .field("total_blocks", &self.total_blocks)
+            .field("journal_blocks", &self.journal_blocks)
             .field("inode_bitmap_blocks", &self.inode_bitmap_blocks)
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("features", &self.features)
+            .field("free_blocks", &self.free_blocks)
+            .field("free_inodes", &self.free_inodes)
+            .field("data_bitmap_ext_blocks", &self.data_bitmap_ext_blocks)
+            .field("resized_from", &self.resized_from)
+            .field("orphans", &self.orphans)
             .finish()
     }
 }
 
 impl SuperBlock {
     /// Initialize a super block
+    #[allow(clippy::too_many_arguments)]
     pub fn initialize(
         &mut self,
         total_blocks: u32,
+        journal_blocks: u32,
         inode_bitmap_blocks: u32,
         inode_area_blocks: u32,
         data_bitmap_blocks: u32,
         data_area_blocks: u32,
+        features: u32,
     ) {
         *self = Self {
             magic: EFS_MAGIC,
+            version: EFS_VERSION,
             total_blocks,
+            journal_blocks,
             inode_bitmap_blocks,
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            features,
+            // nothing is allocated yet
+            free_blocks: data_area_blocks,
+            free_inodes: inode_bitmap_blocks * BLOCK_SZ as u32 * 8,
+            data_bitmap_ext_blocks: 0,
+            resized_from: 0,
+            orphans: [0; ORPHAN_SLOTS],
         }
     }
     /// Check if a super block is valid using efs magic
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+    /// Check if the layout version and the features are the ones we understand
+    pub fn is_supported(&self) -> bool {
+        self.version == EFS_VERSION && self.features & !SUPPORTED_FEATURES == 0
+    }
+}
+
+/// Header of the journal, the first block of it.
+/// Blocks logged by a committed transaction follow the header in order.
+#[repr(C)]
+pub struct JournalHeader {
+    magic: u32,
+    /// number of blocks logged, 0 if there is nothing to replay
+    count: u32,
+    /// home block ids of the logged blocks
+    blocks: [u32; JOURNAL_HEADER_SLOTS],
+}
+
+impl JournalHeader {
+    /// Max number of blocks in a transaction for a journal of `journal_blocks` blocks
+    pub fn capacity(journal_blocks: u32) -> usize {
+        (journal_blocks as usize - 1).min(JOURNAL_HEADER_SLOTS)
+    }
+    /// Create the header of a committed transaction logging `blocks`
+    pub fn committed(blocks: &[usize]) -> Self {
+        let mut header = Self::empty();
+        header.magic = JOURNAL_MAGIC;
+        header.count = blocks.len() as u32;
+        for (slot, block_id) in header.blocks.iter_mut().zip(blocks) {
+            *slot = *block_id as u32;
+        }
+        header
+    }
+    /// Create the header of an empty journal
+    pub fn empty() -> Self {
+        Self {
+            magic: JOURNAL_MAGIC,
+            count: 0,
+            blocks: [0; JOURNAL_HEADER_SLOTS],
+        }
+    }
+    /// Home block ids of the blocks to replay
+    pub fn logged_blocks(&self) -> &[u32] {
+        if self.magic != JOURNAL_MAGIC {
+            return &[];
+        }
+        &self.blocks[..(self.count as usize).min(JOURNAL_HEADER_SLOTS)]
+    }
+    /// Serialize into bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, BLOCK_SZ) }
+    }
+    /// Serialize into mutable bytes
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, BLOCK_SZ) }
+    }
 }
 /// Type of a disk inode
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
 pub enum DiskInodeType {
+    /// Regular file
     File,
+    /// Directory
     Directory,
+    /// Symbolic link, the data is the target path
+    Symlink,
+}
+
+impl DiskInodeType {
+    /// Whether `byte` is the stored form of a type
+    pub fn is_valid(byte: u8) -> bool {
+        byte <= Self::Symlink as u8
+    }
 }
 
 /// A indirect block
 type IndirectBlock = [u32; BLOCK_SZ / 4];
+/// An extent block
+type ExtentBlock = [Extent; BLOCK_EXTENTS];
 /// A data block
 type DataBlock = [u8; BLOCK_SZ];
-/// A disk inode
+/// Default permission bits of a new file
+const FILE_DEFAULT_MODE: u16 = 0o644;
+/// Default permission bits of a new directory
+const DIR_DEFAULT_MODE: u16 = 0o755;
+/// Permission bits of a symbolic link, which are never checked
+const SYMLINK_DEFAULT_MODE: u16 = 0o777;
+/// Permission bits in `DiskInode::mode`
+pub const MODE_MASK: u16 = 0o7777;
+
+/// A run of `len` blocks from block `start`, holding the data blocks of an inode
+/// from data block `logical` on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+struct Extent {
+    logical: u32,
+    start: u32,
+    len: u32,
+}
+
+/// A disk inode, 128 bytes so that 4 of them fit in a block.
+///
+/// The data blocks are either mapped by a tree of `direct`, `indirect1`,
+/// `indirect2` and `indirect3` blocks, or with `INODE_EXTENTS` by sorted extents:
+/// `direct` holds the first `INLINE_EXTENTS` of them, `indirect1` the number of
+/// extents and `indirect2` an index block of the extent blocks holding the rest.
+/// A directory with `INODE_INDEXED` keeps the root block of its name index in
+/// `indirect3`, which extents leave unused, or 0 before the first name is added.
 #[repr(C)]
 pub struct DiskInode {
-    pub size: u32,
+    pub size: u64,
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
+    pub indirect3: u32,
+    /// last access time in seconds
+    pub atime: u32,
+    /// last modification time of the data in seconds
+    pub mtime: u32,
+    /// last change time of the data or metadata in seconds
+    pub ctime: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// number of dirents referring to this inode, "." and ".." included
+    pub nlink: u32,
+    /// permission bits
+    pub mode: u16,
     type_: DiskInodeType,
+    /// `INODE_EXTENTS` and `INODE_INDEXED`, in what used to be padding
+    flags: u8,
 }
 
 impl DiskInode {
     /// Initialize a disk inode, as well as all direct inodes under it
-    /// indirect1 and indirect2 block are allocated only when they are needed
-    pub fn initialize(&mut self, type_: DiskInodeType) {
+    /// indirect1, indirect2 and indirect3 block are allocated only when they are needed.
+    /// With `extents`, the data blocks are mapped by extents.
+    /// A directory starts with two links, its dirent and its ".", other inodes with one.
+    pub fn initialize(&mut self, type_: DiskInodeType, now: u32, extents: bool) {
         self.size = 0;
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        self.indirect3 = 0;
+        self.atime = now;
+        self.mtime = now;
+        self.ctime = now;
+        self.uid = 0;
+        self.gid = 0;
+        self.nlink = if type_ == DiskInodeType::Directory {
+            2
+        } else {
+            1
+        };
+        self.mode = match type_ {
+            DiskInodeType::File => FILE_DEFAULT_MODE,
+            DiskInodeType::Directory => DIR_DEFAULT_MODE,
+            DiskInodeType::Symlink => SYMLINK_DEFAULT_MODE,
+        };
         self.type_ = type_;
+        self.flags = if extents { INODE_EXTENTS } else { 0 };
+    }
+    /// Offset of the type in a disk inode, so that the type of a disk inode
+    /// that may be corrupted can be checked before the disk inode is read
+    pub fn type_offset() -> usize {
+        let disk_inode = core::mem::MaybeUninit::<Self>::uninit();
+        let base = disk_inode.as_ptr();
+        unsafe { core::ptr::addr_of!((*base).type_) as usize - base as usize }
     }
     /// Whether this inode is a directory
     pub fn is_dir(&self) -> bool {
         self.type_ == DiskInodeType::Directory
     }
+    /// Get the type of this inode
+    pub fn type_(&self) -> DiskInodeType {
+        self.type_
+    }
+    /// Whether this inode is a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        self.type_ == DiskInodeType::Symlink
+    }
     /// Whether this inode is a file
-    #[allow(unused)]
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
+    /// Whether the data blocks of this inode are mapped by extents
+    pub fn uses_extents(&self) -> bool {
+        self.flags & INODE_EXTENTS != 0
+    }
+    /// Keep an index of the names of this empty directory mapped by extents
+    pub fn set_indexed(&mut self) {
+        assert!(self.is_dir() && self.uses_extents() && self.size == 0);
+        self.flags |= INODE_INDEXED;
+    }
+    /// Get the root block of the name index of this directory, 0 if it is empty,
+    /// or `None` if the directory keeps no index
+    pub fn index_root(&self) -> Option<u32> {
+        if self.flags & INODE_INDEXED != 0 && self.uses_extents() {
+            Some(self.indirect3)
+        } else {
+            None
+        }
+    }
+    /// Set the root block of the name index of this directory
+    pub fn set_index_root(&mut self, root: u32) {
+        assert!(self.index_root().is_some());
+        self.indirect3 = root;
+    }
+    /// Stop keeping an index of the names of this directory, whose blocks are
+    /// then left to be freed by the caller
+    pub fn drop_index(&mut self) {
+        self.flags &= !INODE_INDEXED;
+        self.indirect3 = 0;
+    }
     /// Return block number correspond to size.
     pub fn data_blocks(&self) -> u32 {
         Self::_data_blocks(self.size)
     }
-    fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+    fn _data_blocks(size: u64) -> u32 {
+        size.div_ceil(BLOCK_SZ as u64) as u32
+    }
+    /// Locate data block `inner_id` out of the direct blocks: the level of its
+    /// top index block, 1 for indirect1 and so on, the index of the data block
+    /// under it, and the number of data blocks under each entry of it
+    fn locate(inner_id: usize) -> (usize, usize, usize) {
+        assert!((DIRECT_BOUND..INDIRECT3_BOUND).contains(&inner_id));
+        if inner_id < INDIRECT1_BOUND {
+            (1, inner_id - DIRECT_BOUND, 1)
+        } else if inner_id < INDIRECT2_BOUND {
+            (2, inner_id - INDIRECT1_BOUND, INODE_INDIRECT1_COUNT)
+        } else {
+            (3, inner_id - INDIRECT2_BOUND, INODE_INDIRECT2_COUNT)
+        }
+    }
+    /// Get the top index block of `level`
+    fn indirect_mut(&mut self, level: usize) -> &mut u32 {
+        match level {
+            1 => &mut self.indirect1,
+            2 => &mut self.indirect2,
+            _ => &mut self.indirect3,
+        }
+    }
+    /// Get the number of extents
+    fn extent_count(&self) -> usize {
+        self.indirect1 as usize
+    }
+    /// Locate extent `i` out of the inline ones: the entry of the index block
+    /// pointing to its extent block, and its index in the extent block
+    fn locate_extent(i: usize) -> (usize, usize) {
+        let i = i - INLINE_EXTENTS;
+        (i / BLOCK_EXTENTS, i % BLOCK_EXTENTS)
+    }
+    /// Whether extent `i` is the first one of its extent block
+    fn starts_extent_block(i: usize) -> bool {
+        i >= INLINE_EXTENTS && Self::locate_extent(i).1 == 0
+    }
+    /// Get the id of the extent block holding extent `i`, which is not inline
+    fn extent_block_id(&self, i: usize, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        let (entry, _) = Self::locate_extent(i);
+        get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+            .lock()
+            .read(0, |index_block: &IndirectBlock| index_block[entry])
+    }
+    /// Get extent `i`
+    fn extent(&self, i: usize, block_device: &Arc<dyn BlockDevice>) -> Extent {
+        if i < INLINE_EXTENTS {
+            return Extent {
+                logical: self.direct[3 * i],
+                start: self.direct[3 * i + 1],
+                len: self.direct[3 * i + 2],
+            };
+        }
+        get_block_cache(
+            self.extent_block_id(i, block_device) as usize,
+            Arc::clone(block_device),
+        )
+        .lock()
+        .read(0, |extent_block: &ExtentBlock| {
+            extent_block[Self::locate_extent(i).1]
+        })
+    }
+    /// Set extent `i`, whose extent block has to be allocated
+    fn set_extent(&mut self, i: usize, extent: Extent, block_device: &Arc<dyn BlockDevice>) {
+        if i < INLINE_EXTENTS {
+            self.direct[3 * i] = extent.logical;
+            self.direct[3 * i + 1] = extent.start;
+            self.direct[3 * i + 2] = extent.len;
+            return;
+        }
+        get_block_cache(
+            self.extent_block_id(i, block_device) as usize,
+            Arc::clone(block_device),
+        )
+        .lock()
+        .modify(0, |extent_block: &mut ExtentBlock| {
+            extent_block[Self::locate_extent(i).1] = extent;
+        });
+    }
+    /// Get the number of extents starting at or before data block `inner_id`
+    fn extent_index(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> usize {
+        // binary search, the extents are sorted
+        let (mut low, mut high) = (0, self.extent_count());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.extent(mid, block_device).logical <= inner_id {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+    /// Find the extent holding data block `inner_id`
+    fn find_extent(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> Option<Extent> {
+        let i = self.extent_index(inner_id, block_device).checked_sub(1)?;
+        let extent = self.extent(i, block_device);
+        (inner_id - extent.logical < extent.len).then_some(extent)
+    }
+    /// Make room for one more extent at the end, `None` if there are `EXTENT_LIMIT`
+    /// already. The index block and the extent blocks are taken from `alloc`
+    /// when they are needed, if it runs out the index block taken is pushed to `freed`.
+    fn grow_extents(
+        &mut self,
+        alloc: &mut impl FnMut(u32) -> Option<(u32, u32)>,
+        freed: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<()> {
+        let count = self.extent_count();
+        if count >= EXTENT_LIMIT {
+            return None;
+        }
+        if Self::starts_extent_block(count) {
+            let index_block = if count == INLINE_EXTENTS {
+                alloc(1)?.0
+            } else {
+                self.indirect2
+            };
+            let Some((extent_block, _)) = alloc(1) else {
+                if count == INLINE_EXTENTS {
+                    freed.push(index_block);
+                }
+                return None;
+            };
+            self.indirect2 = index_block;
+            get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |index_block: &mut IndirectBlock| {
+                    index_block[Self::locate_extent(count).0] = extent_block;
+                });
+        }
+        self.indirect1 += 1;
+        Some(())
+    }
+    /// Drop the last extent, pushing its extent block and the index block
+    /// to `freed` when they are left empty
+    fn shrink_extents(&mut self, freed: &mut Vec<u32>, block_device: &Arc<dyn BlockDevice>) {
+        let last = self.extent_count() - 1;
+        if Self::starts_extent_block(last) {
+            freed.push(self.extent_block_id(last, block_device));
+            if last == INLINE_EXTENTS {
+                freed.push(self.indirect2);
+                self.indirect2 = 0;
+            }
+        }
+        self.indirect1 -= 1;
+    }
+    /// Insert `extent` among the extents, merged into the one before or after it
+    /// if they are consecutive on disk too. Return `None` if there is no room
+    /// for it and it cannot be made, see `grow_extents`.
+    fn insert_extent(
+        &mut self,
+        extent: Extent,
+        alloc: &mut impl FnMut(u32) -> Option<(u32, u32)>,
+        freed: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<()> {
+        let i = self.extent_index(extent.logical, block_device);
+        if let Some(j) = i.checked_sub(1) {
+            let mut prev = self.extent(j, block_device);
+            if prev.logical + prev.len == extent.logical && prev.start + prev.len == extent.start {
+                prev.len += extent.len;
+                self.set_extent(j, prev, block_device);
+                return Some(());
+            }
+        }
+        if i < self.extent_count() {
+            let mut next = self.extent(i, block_device);
+            if extent.logical + extent.len == next.logical
+                && extent.start + extent.len == next.start
+            {
+                next.logical = extent.logical;
+                next.start = extent.start;
+                next.len += extent.len;
+                self.set_extent(i, next, block_device);
+                return Some(());
+            }
+        }
+        self.grow_extents(alloc, freed, block_device)?;
+        for j in (i..self.extent_count() - 1).rev() {
+            let moved = self.extent(j, block_device);
+            self.set_extent(j + 1, moved, block_device);
+        }
+        self.set_extent(i, extent, block_device);
+        Some(())
+    }
+    /// Remove extent `i`, pushing the extent block and the index block left empty to `freed`
+    fn remove_extent(
+        &mut self,
+        i: usize,
+        freed: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        for j in i + 1..self.extent_count() {
+            let moved = self.extent(j, block_device);
+            self.set_extent(j - 1, moved, block_device);
+        }
+        self.shrink_extents(freed, block_device);
     }
-    /// Return number of blocks needed include indirect1/2.
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
-        // indirect1
-        if data_blocks > INODE_DIRECT_COUNT {
-            total += 1;
+    /// Map the holes in data blocks `start..end` with runs from `alloc`. If it
+    /// runs out, the blocks taken but not mapped are pushed to `freed`.
+    fn allocate_extents(
+        &mut self,
+        start: u32,
+        end: u32,
+        alloc: &mut impl FnMut(u32) -> Option<(u32, u32)>,
+        freed: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<()> {
+        let mut logical = start;
+        while logical < end {
+            let i = self.extent_index(logical, block_device);
+            if let Some(j) = i.checked_sub(1) {
+                let prev = self.extent(j, block_device);
+                if logical < prev.logical + prev.len {
+                    logical = prev.logical + prev.len;
+                    continue;
+                }
+            }
+            // the hole ends at the next extent
+            let hole_end = if i < self.extent_count() {
+                self.extent(i, block_device).logical.min(end)
+            } else {
+                end
+            };
+            let (block_id, len) = alloc(hole_end - logical)?;
+            let extent = Extent {
+                logical,
+                start: block_id,
+                len,
+            };
+            if self
+                .insert_extent(extent, alloc, freed, block_device)
+                .is_none()
+            {
+                freed.extend(block_id..block_id + len);
+                return None;
+            }
+            logical += len;
         }
-        // indirect2
-        if data_blocks > INDIRECT1_BOUND {
-            total += 1;
-            // sub indirect1
-            total +=
-                (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+        Some(())
+    }
+    /// Unmap data blocks `start..end`, pushing them to `freed` with the extent
+    /// blocks and the index block left empty. An extent split in two by the
+    /// hole may take an extent block from `alloc`, if it runs out nothing is unmapped.
+    fn punch_extents(
+        &mut self,
+        start: u32,
+        end: u32,
+        alloc: &mut impl FnMut(u32) -> Option<(u32, u32)>,
+        freed: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<()> {
+        let mut i = self.extent_index(start, block_device).saturating_sub(1);
+        while i < self.extent_count() {
+            let extent = self.extent(i, block_device);
+            let extent_end = extent.logical + extent.len;
+            if extent.logical >= end {
+                return Some(());
+            }
+            if extent_end <= start {
+                i += 1;
+                continue;
+            }
+            let cut_start = start.max(extent.logical);
+            let cut_end = end.min(extent_end);
+            let cut = extent.start + (cut_start - extent.logical)
+                ..extent.start + (cut_end - extent.logical);
+            let head = Extent {
+                len: cut_start - extent.logical,
+                ..extent
+            };
+            let tail = Extent {
+                logical: cut_end,
+                start: extent.start + (cut_end - extent.logical),
+                len: extent_end - cut_end,
+            };
+            if head.len > 0 && tail.len > 0 {
+                // the hole is within this extent, the tail goes first as it may not fit
+                self.insert_extent(tail, alloc, freed, block_device)?;
+                self.set_extent(i, head, block_device);
+                freed.extend(cut);
+                return Some(());
+            }
+            freed.extend(cut);
+            match (head.len > 0, tail.len > 0) {
+                (true, _) => {
+                    self.set_extent(i, head, block_device);
+                    i += 1;
+                }
+                (false, true) => {
+                    self.set_extent(i, tail, block_device);
+                    return Some(());
+                }
+                (false, false) => self.remove_extent(i, freed, block_device),
+            }
         }
-        total as u32
+        Some(())
     }
-    /// Get the number of data blocks that have to be allocated given the new size of data
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+    /// Unmap the data blocks from `total_blocks` on, pushing them to `freed`
+    /// with the extent blocks and the index block left empty
+    fn truncate_extents(
+        &mut self,
+        total_blocks: u32,
+        freed: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        while let Some(i) = self.extent_count().checked_sub(1) {
+            let mut last = self.extent(i, block_device);
+            if last.logical + last.len <= total_blocks {
+                return;
+            }
+            let keep = total_blocks.saturating_sub(last.logical);
+            freed.extend(last.start + keep..last.start + last.len);
+            if keep > 0 {
+                last.len = keep;
+                self.set_extent(i, last, block_device);
+                return;
+            }
+            self.shrink_extents(freed, block_device);
+        }
     }
-    /// Get id of block given inner id
+    /// Get id of block given inner id, 0 for a hole
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        if self.uses_extents() {
+            return self
+                .find_extent(inner_id, block_device)
+                .map_or(0, |extent| extent.start + inner_id - extent.logical);
+        }
         let inner_id = inner_id as usize;
         if inner_id < INODE_DIRECT_COUNT {
-            self.direct[inner_id]
-        } else if inner_id < INDIRECT1_BOUND {
-            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-                .lock()
-                .read(0, |indirect_block: &IndirectBlock| {
-                    indirect_block[inner_id - INODE_DIRECT_COUNT]
-                })
-        } else {
-            let last = inner_id - INDIRECT1_BOUND;
-            let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+            return self.direct[inner_id];
+        }
+        let (level, mut last, mut span) = Self::locate(inner_id);
+        let mut block_id = [self.indirect1, self.indirect2, self.indirect3][level - 1];
+        // walk down the index blocks, a missing one is a hole
+        while block_id != 0 {
+            let entry = last / span;
+            block_id = get_block_cache(block_id as usize, Arc::clone(block_device))
                 .lock()
-                .read(0, |indirect2: &IndirectBlock| {
-                    indirect2[last / INODE_INDIRECT1_COUNT]
-                });
-            get_block_cache(indirect1 as usize, Arc::clone(block_device))
+                .read(0, |indirect_block: &IndirectBlock| indirect_block[entry]);
+            if span == 1 {
+                return block_id;
+            }
+            last %= span;
+            span /= INODE_INDIRECT1_COUNT;
+        }
+        0
+    }
+    /// Get id of block given inner id as `get_block_id` does, `None` if
+    /// a pointer on the way to it is not `valid`
+    fn valid_block_id(
+        &self,
+        inner_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+        valid: &impl Fn(u32) -> bool,
+    ) -> Option<u32> {
+        let check = |block_id: u32| (block_id == 0 || valid(block_id)).then_some(block_id);
+        if self.uses_extents() {
+            // linear, the extent blocks are checked in order
+            for i in 0..self.extent_count().min(MAX_EXTENTS) {
+                if i == INLINE_EXTENTS && !valid(self.indirect2) {
+                    return None;
+                }
+                if Self::starts_extent_block(i) && !valid(self.extent_block_id(i, block_device)) {
+                    return None;
+                }
+                let extent = self.extent(i, block_device);
+                if extent.logical > inner_id {
+                    break;
+                }
+                if inner_id - extent.logical < extent.len {
+                    return check(extent.start + (inner_id - extent.logical));
+                }
+            }
+            return Some(0);
+        }
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            return check(self.direct[inner_id]);
+        }
+        let (level, mut last, mut span) = Self::locate(inner_id);
+        let mut block_id = check([self.indirect1, self.indirect2, self.indirect3][level - 1])?;
+        while block_id != 0 {
+            let entry = last / span;
+            block_id = get_block_cache(block_id as usize, Arc::clone(block_device))
                 .lock()
-                .read(0, |indirect1: &IndirectBlock| {
-                    indirect1[last % INODE_INDIRECT1_COUNT]
-                })
+                .read(0, |indirect_block: &IndirectBlock| indirect_block[entry]);
+            block_id = check(block_id)?;
+            if span == 1 {
+                return Some(block_id);
+            }
+            last %= span;
+            span /= INODE_INDIRECT1_COUNT;
         }
+        Some(0)
     }
-    /// Inncrease the size of current disk inode
-    pub fn increase_size(
-        &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,
+    /// Get the ids of all blocks of current disk inode, indirect blocks and the
+    /// blocks of a name index included, and the pointers that are not `valid`.
+    /// Indirect blocks that are not valid are not read.
+    pub fn blocks(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        valid: impl Fn(u32) -> bool,
+    ) -> (Vec<u32>, Vec<u32>) {
+        let mut blocks: Vec<u32> = Vec::new();
+        let mut bad: Vec<u32> = Vec::new();
+        if self.uses_extents() {
+            self.extent_blocks(block_device, &valid, &mut blocks, &mut bad);
+            if let Some(root) = self.index_root() {
+                dir_index::blocks(root, block_device, &valid, &mut blocks, &mut bad);
+            }
+            return (blocks, bad);
+        }
+        for block_id in self.direct.iter().filter(|block_id| **block_id != 0) {
+            Self::check_block(*block_id, &valid, &mut blocks, &mut bad);
+        }
+        // indirect1, indirect2 and indirect3
+        let mut span = 1;
+        for top in [self.indirect1, self.indirect2, self.indirect3] {
+            if top != 0 {
+                Self::tree_blocks(top, span, block_device, &valid, &mut blocks, &mut bad);
+            }
+            span *= INODE_INDIRECT1_COUNT;
+        }
+        (blocks, bad)
+    }
+    /// Collect the blocks of the extents, the extent blocks and the index block.
+    /// An extent out of the `valid` blocks is added to `bad` by its start.
+    fn extent_blocks(
+        &self,
         block_device: &Arc<dyn BlockDevice>,
+        valid: &impl Fn(u32) -> bool,
+        blocks: &mut Vec<u32>,
+        bad: &mut Vec<u32>,
     ) {
-        let mut current_blocks = self.data_blocks();
-        self.size = new_size;
-        let mut total_blocks = self.data_blocks();
-        let mut new_blocks = new_blocks.into_iter();
-        // fill direct
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
-        }
-        // alloc indirect1
-        if total_blocks > INODE_DIRECT_COUNT as u32 {
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                self.indirect1 = new_blocks.next().unwrap();
-            }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
-        } else {
-            return;
+        let mut count = self.extent_count().min(MAX_EXTENTS);
+        if count > INLINE_EXTENTS && !Self::check_block(self.indirect2, valid, blocks, bad) {
+            count = INLINE_EXTENTS;
         }
-        // fill indirect1
-        get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-            .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                    indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                    current_blocks += 1;
+        for i in 0..count {
+            if Self::starts_extent_block(i) {
+                let extent_block = self.extent_block_id(i, block_device);
+                if !Self::check_block(extent_block, valid, blocks, bad) {
+                    return;
                 }
-            });
-        // alloc indirect2
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                self.indirect2 = new_blocks.next().unwrap();
             }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
+            let extent = self.extent(i, block_device);
+            let last = extent
+                .len
+                .checked_sub(1)
+                .and_then(|len| extent.start.checked_add(len));
+            if last.is_some_and(|last| valid(extent.start) && valid(last)) {
+                blocks.extend(extent.start..extent.start + extent.len);
+            } else {
+                bad.push(extent.start);
+            }
+        }
+    }
+    /// Add `block_id` to `blocks` if it is `valid`, or to `bad`
+    fn check_block(
+        block_id: u32,
+        valid: &impl Fn(u32) -> bool,
+        blocks: &mut Vec<u32>,
+        bad: &mut Vec<u32>,
+    ) -> bool {
+        if valid(block_id) {
+            blocks.push(block_id);
+            true
         } else {
+            bad.push(block_id);
+            false
+        }
+    }
+    /// Collect index block `block_id` and the blocks under it, each of its
+    /// entries covering `span` data blocks. An entry of 0 is a hole.
+    fn tree_blocks(
+        block_id: u32,
+        span: usize,
+        block_device: &Arc<dyn BlockDevice>,
+        valid: &impl Fn(u32) -> bool,
+        blocks: &mut Vec<u32>,
+        bad: &mut Vec<u32>,
+    ) {
+        if !Self::check_block(block_id, valid, blocks, bad) {
             return;
         }
-        // fill indirect2 from (a0, b0) -> (a1, b1)
-        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
-        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
-        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
-        // alloc low-level indirect1
-        get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+        let entries = get_block_cache(block_id as usize, Arc::clone(block_device))
             .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-                while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                    if b0 == 0 {
-                        indirect2[a0] = new_blocks.next().unwrap();
-                    }
-                    // fill current
-                    get_block_cache(indirect2[a0] as usize, Arc::clone(block_device))
+            .read(0, |indirect_block: &IndirectBlock| *indirect_block);
+        for entry in entries.into_iter().filter(|entry| *entry != 0) {
+            if span == 1 {
+                Self::check_block(entry, valid, blocks, bad);
+            } else {
+                let sub_span = span / INODE_INDIRECT1_COUNT;
+                Self::tree_blocks(entry, sub_span, block_device, valid, blocks, bad);
+            }
+        }
+    }
+    /// Map data block `inner_id` to `block_id`, taking the index blocks missing
+    /// on the way from `alloc`. With `block_id` 0 only the index blocks are mapped.
+    /// Return `None` if `alloc` runs out, the index blocks taken are kept mapped.
+    fn map_tree_block(
+        &mut self,
+        inner_id: usize,
+        block_id: u32,
+        alloc: &mut impl FnMut(u32) -> Option<(u32, u32)>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<()> {
+        if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id] = block_id;
+            return Some(());
+        }
+        let (level, mut last, mut span) = Self::locate(inner_id);
+        let top = self.indirect_mut(level);
+        if *top == 0 {
+            *top = alloc(1)?.0;
+        }
+        let mut index_block = *top;
+        loop {
+            let entry = last / span;
+            let index_cache = get_block_cache(index_block as usize, Arc::clone(block_device));
+            if span == 1 {
+                if block_id != 0 {
+                    index_cache
                         .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            indirect1[b0] = new_blocks.next().unwrap();
+                        .modify(0, |indirect_block: &mut IndirectBlock| {
+                            indirect_block[entry] = block_id;
                         });
-                    // move to next
-                    b0 += 1;
-                    if b0 == INODE_INDIRECT1_COUNT {
-                        b0 = 0;
-                        a0 += 1;
-                    }
                 }
-            });
+                return Some(());
+            }
+            let mut child = index_cache
+                .lock()
+                .read(0, |indirect_block: &IndirectBlock| indirect_block[entry]);
+            if child == 0 {
+                child = alloc(1)?.0;
+                index_cache
+                    .lock()
+                    .modify(0, |indirect_block: &mut IndirectBlock| {
+                        indirect_block[entry] = child;
+                    });
+            }
+            index_block = child;
+            last %= span;
+            span /= INODE_INDIRECT1_COUNT;
+        }
     }
-
-    /// Clear size to zero and return blocks that should be deallocated.
-    /// We will clear the block contents to zero later.
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+    /// Map the holes in data blocks `start..end` with runs from `alloc`.
+    /// The index blocks are mapped first, so that if `alloc` runs out
+    /// the holes mapped are the first ones and no block taken is left unmapped.
+    fn allocate_tree(
+        &mut self,
+        start: usize,
+        end: usize,
+        alloc: &mut impl FnMut(u32) -> Option<(u32, u32)>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<()> {
+        let holes: Vec<usize> = (start..end)
+            .filter(|inner_id| self.get_block_id(*inner_id as u32, block_device) == 0)
+            .collect();
+        for inner_id in holes.iter() {
+            self.map_tree_block(*inner_id, 0, alloc, block_device)?;
+        }
+        let mut run = 0..0;
+        for (i, inner_id) in holes.iter().enumerate() {
+            if run.is_empty() {
+                let (block_id, len) = alloc((holes.len() - i) as u32)?;
+                run = block_id..block_id + len;
+            }
+            let block_id = run.next().unwrap();
+            self.map_tree_block(*inner_id, block_id, alloc, block_device)?;
+        }
+        Some(())
+    }
+    /// Unmap data blocks `start..end`, pushing them to `freed` with the index
+    /// blocks left empty
+    fn punch_tree(
+        &mut self,
+        start: usize,
+        end: usize,
+        freed: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        for block_id in self.direct.iter_mut().take(end).skip(start) {
+            if *block_id != 0 {
+                freed.push(*block_id);
+                *block_id = 0;
+            }
+        }
+        let (mut base, mut span) = (DIRECT_BOUND, 1);
+        for level in 1..=3 {
+            let count = span * INODE_INDIRECT1_COUNT;
+            let top = *self.indirect_mut(level);
+            if top != 0
+                && start < base + count
+                && end > base
+                && Self::punch_index(
+                    top,
+                    span,
+                    start.saturating_sub(base),
+                    (end - base).min(count),
+                    freed,
+                    block_device,
+                )
+            {
+                *self.indirect_mut(level) = 0;
+            }
+            base += count;
+            span = count;
+        }
+    }
+    /// Unmap the data blocks in `start..end` under index block `block_id`, each of
+    /// whose entries covers `span` data blocks, and push them to `freed`. Return
+    /// whether the index block is left empty, in which case it is pushed too.
+    /// A freed index block is only read so that it does not join the running transaction.
+    fn punch_index(
+        block_id: u32,
+        span: usize,
+        start: usize,
+        end: usize,
+        freed: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> bool {
+        let entries = get_block_cache(block_id as usize, Arc::clone(block_device))
+            .lock()
+            .read(0, |indirect_block: &IndirectBlock| *indirect_block);
+        let mut cleared = [false; INODE_INDIRECT1_COUNT];
+        for entry in start / span..end.div_ceil(span) {
+            let child = entries[entry];
+            if child == 0 {
+                continue;
+            }
+            cleared[entry] = span == 1
+                || Self::punch_index(
+                    child,
+                    span / INODE_INDIRECT1_COUNT,
+                    start.saturating_sub(entry * span),
+                    (end - entry * span).min(span),
+                    freed,
+                    block_device,
+                );
+            if span == 1 {
+                freed.push(child);
+            }
+        }
+        if entries
+            .iter()
+            .zip(cleared)
+            .all(|(child, cleared)| *child == 0 || cleared)
+        {
+            freed.push(block_id);
+            return true;
+        }
+        if cleared.contains(&true) {
+            get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect_block: &mut IndirectBlock| {
+                    for (child, cleared) in indirect_block.iter_mut().zip(cleared) {
+                        if cleared {
+                            *child = 0;
+                        }
+                    }
+                });
+        }
+        false
+    }
+    /// Increase the size of current disk inode, mapping the data blocks it
+    /// grows by with runs from `alloc` as `allocate` does.
+    /// The size is unchanged if `alloc` runs out.
+    pub fn increase_size(
+        &mut self,
+        new_size: u64,
+        alloc: &mut impl FnMut(u32) -> Option<(u32, u32)>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> core::result::Result<(), Vec<u32>> {
+        assert!(new_size <= MAX_FILE_SIZE);
+        let (start, end) = (self.data_blocks(), Self::_data_blocks(new_size));
+        self.allocate(start, end, alloc, block_device)?;
+        self.size = new_size;
+        Ok(())
+    }
+    /// Map the holes in data blocks `start..end`, which may be past the size.
+    /// The new blocks are taken from `alloc`, which gives a run of at most
+    /// the given number of blocks as its first block and its length, or `None`
+    /// when no block is left. The holes are then mapped in order up to some
+    /// point, see `mapped_end`, and the blocks taken but not mapped are
+    /// returned as the error to be deallocated.
+    pub fn allocate(
+        &mut self,
+        start: u32,
+        end: u32,
+        alloc: &mut impl FnMut(u32) -> Option<(u32, u32)>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> core::result::Result<(), Vec<u32>> {
+        assert!(end as usize <= INDIRECT3_BOUND);
         let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
-        let mut current_blocks = 0usize;
-        // direct
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
-            current_blocks += 1;
-        }
-        // indirect1 block
-        if data_blocks > INODE_DIRECT_COUNT {
-            v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
+        let allocated = if self.uses_extents() {
+            self.allocate_extents(start, end, alloc, &mut v, block_device)
         } else {
-            return v;
+            self.allocate_tree(start as usize, end as usize, alloc, block_device)
+        };
+        allocated.ok_or(v)
+    }
+    /// Get the first hole in data blocks `start..end`, or `end` if there is none
+    pub fn mapped_end(&self, start: u32, end: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        (start..end)
+            .find(|inner_id| self.get_block_id(*inner_id, block_device) == 0)
+            .unwrap_or(end)
+    }
+    /// Unmap data blocks `start..end`, which then read as zeros, and return blocks
+    /// that should be deallocated, the index blocks left empty included.
+    /// An extent split in two may take an extent block from `alloc`, if it runs
+    /// out nothing is unmapped and the blocks to deallocate are returned as the error.
+    pub fn punch_hole(
+        &mut self,
+        start: u32,
+        end: u32,
+        alloc: &mut impl FnMut(u32) -> Option<(u32, u32)>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> core::result::Result<Vec<u32>, Vec<u32>> {
+        let mut v: Vec<u32> = Vec::new();
+        if start >= end {
+            return Ok(v);
         }
-        // indirect1
-        get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
-            .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                    v.push(indirect1[current_blocks]);
-                    //indirect1[current_blocks] = 0;
-                    current_blocks += 1;
-                }
-            });
-        self.indirect1 = 0;
-        // indirect2 block
-        if data_blocks > INODE_INDIRECT1_COUNT {
-            v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
+        if !self.uses_extents() {
+            self.punch_tree(start as usize, end as usize, &mut v, block_device);
+            return Ok(v);
+        }
+        match self.punch_extents(start, end, alloc, &mut v, block_device) {
+            Some(()) => Ok(v),
+            None => Err(v),
+        }
+    }
+    /// Decrease the size of current disk inode and return blocks that should be
+    /// deallocated, the blocks allocated past the size included.
+    /// The rest of the last block is cleared so that it reads as zeros if the size grows again.
+    pub fn decrease_size(
+        &mut self,
+        new_size: u64,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u32> {
+        self.cut_size(new_size, block_device);
+        self.unmap_from(self.data_blocks(), block_device)
+    }
+    /// Decrease the size of current disk inode as `decrease_size` does,
+    /// but leave the blocks past it mapped, see `unmap_from`
+    pub fn cut_size(&mut self, new_size: u64, block_device: &Arc<dyn BlockDevice>) {
+        assert!(new_size <= self.size);
+        let block_end = Self::_data_blocks(new_size) as usize * BLOCK_SZ;
+        self.zero_at(new_size as usize, block_end, block_device);
+        self.size = new_size;
+    }
+    /// Unmap the data blocks from `start` on, past the size too, and return
+    /// blocks that should be deallocated, the index blocks left empty included
+    pub fn unmap_from(&mut self, start: u32, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::new();
+        if self.uses_extents() {
+            self.truncate_extents(start, &mut v, block_device);
         } else {
-            return v;
+            self.punch_tree(start as usize, INDIRECT3_BOUND, &mut v, block_device);
         }
-        // indirect2
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
-        get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+        v
+    }
+    /// Get where to unmap from so that the last `count` data blocks mapped from
+    /// `min` on are unmapped, past the size too, or `min` if fewer are mapped
+    pub fn unmap_start(&self, min: u32, count: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        let mut left = count as usize;
+        if self.uses_extents() {
+            for i in (0..self.extent_count()).rev() {
+                let extent = self.extent(i, block_device);
+                let end = extent.logical + extent.len;
+                if end <= min {
+                    break;
+                }
+                let len = (end - extent.logical.max(min)) as usize;
+                if len > left {
+                    return end - left as u32;
+                }
+                left -= len;
+            }
+            return min;
+        }
+        let min = min as usize;
+        let mut start = INDIRECT3_BOUND;
+        // the last data blocks are under the top index blocks of the last levels
+        let (mut base, mut span) = (INDIRECT3_BOUND, INODE_INDIRECT2_COUNT);
+        for top in [self.indirect3, self.indirect2, self.indirect1] {
+            base -= span * INODE_INDIRECT1_COUNT;
+            if top != 0
+                && base + span * INODE_INDIRECT1_COUNT > min
+                && Self::last_tree_blocks(top, span, base, min, &mut left, &mut start, block_device)
+            {
+                return start as u32;
+            }
+            span /= INODE_INDIRECT1_COUNT;
+        }
+        for inner_id in (min..DIRECT_BOUND).rev() {
+            if self.direct[inner_id] != 0 {
+                if left == 0 {
+                    return start as u32;
+                }
+                left -= 1;
+                start = inner_id;
+            }
+        }
+        min as u32
+    }
+    /// Walk the data blocks from `min` on under index block `block_id` from the
+    /// last, each of whose entries covers `span` data blocks from data block `base` on.
+    /// `start` is moved down to each mapped one while `left` counts them down,
+    /// return whether one is found with none left to count.
+    fn last_tree_blocks(
+        block_id: u32,
+        span: usize,
+        base: usize,
+        min: usize,
+        left: &mut usize,
+        start: &mut usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> bool {
+        let entries = get_block_cache(block_id as usize, Arc::clone(block_device))
             .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-                // full indirect1 blocks
-                for entry in indirect2.iter_mut().take(a1) {
-                    v.push(*entry);
-                    get_block_cache(*entry as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            for entry in indirect1.iter() {
-                                v.push(*entry);
-                            }
-                        });
+            .read(0, |indirect_block: &IndirectBlock| *indirect_block);
+        for (entry, child) in entries.into_iter().enumerate().rev() {
+            let child_base = base + entry * span;
+            if child_base + span <= min {
+                return false;
+            }
+            if child == 0 {
+                continue;
+            }
+            if span > 1 {
+                let sub_span = span / INODE_INDIRECT1_COUNT;
+                if Self::last_tree_blocks(
+                    child,
+                    sub_span,
+                    child_base,
+                    min,
+                    left,
+                    start,
+                    block_device,
+                ) {
+                    return true;
                 }
-                // last indirect1 block
-                if b1 > 0 {
-                    v.push(indirect2[a1]);
-                    get_block_cache(indirect2[a1] as usize, Arc::clone(block_device))
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            for entry in indirect1.iter().take(b1) {
-                                v.push(*entry);
-                            }
-                        });
-                    //indirect2[a1] = 0;
+            } else if *left == 0 {
+                return true;
+            } else {
+                *left -= 1;
+                *start = child_base;
+            }
+        }
+        false
+    }
+    /// Get where the first `count` data blocks mapped in `start..end` end,
+    /// or `end` if fewer are mapped
+    pub fn mapped_step_end(
+        &self,
+        start: u32,
+        end: u32,
+        count: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> u32 {
+        let mut left = count;
+        if self.uses_extents() {
+            let first = self.extent_index(start, block_device).saturating_sub(1);
+            for i in first..self.extent_count() {
+                let extent = self.extent(i, block_device);
+                if extent.logical >= end {
+                    break;
                 }
-            });
+                let from = extent.logical.max(start);
+                let to = (extent.logical + extent.len).min(end);
+                if to <= from {
+                    continue;
+                }
+                if to - from >= left {
+                    return from + left;
+                }
+                left -= to - from;
+            }
+            return end;
+        }
+        for inner_id in start..end {
+            if self.get_block_id(inner_id, block_device) != 0 {
+                if left == 0 {
+                    return inner_id;
+                }
+                left -= 1;
+            }
+        }
+        end
+    }
+
+    /// Clear size to zero and return blocks that should be deallocated.
+    /// Blocks are cleared when they are allocated again, and the freed index
+    /// blocks are only read so that they do not join the running transaction.
+    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
+        let (blocks, _) = self.blocks(block_device, |_| true);
+        self.size = 0;
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.indirect1 = 0;
         self.indirect2 = 0;
-        v
+        self.indirect3 = 0;
+        blocks
     }
-    /// Read data from current disk inode
+    /// Read data from current disk inode, a hole reads as zeros
     pub fn read_at(
         &self,
         offset: usize,
         buf: &mut [u8],
         block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        let block_id = |inner_id| Some(self.get_block_id(inner_id, block_device));
+        self.read_mapped(offset, buf, block_device, block_id)
+    }
+    /// Read data from current disk inode as `read_at` does, through block pointers
+    /// that are `valid` only. The read stops at the first block that cannot be reached.
+    pub fn read_valid_at(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+        valid: &impl Fn(u32) -> bool,
+    ) -> usize {
+        let block_id = |inner_id| self.valid_block_id(inner_id, block_device, valid);
+        self.read_mapped(offset, buf, block_device, block_id)
+    }
+    /// Read data from current disk inode, the data blocks being mapped by
+    /// `block_id`. The read stops at the first one it maps to `None`.
+    fn read_mapped(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+        block_id: impl Fn(u32) -> Option<u32>,
     ) -> usize {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
@@ -329,15 +1336,20 @@ impl DiskInode {
             // read and update read size
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            let block_id = match block_id(start_block as u32) {
+                Some(block_id) => block_id,
+                None => break,
+            };
+            if block_id == 0 {
+                dst.fill(0);
+            } else {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |data_block: &DataBlock| {
+                        let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
+                        dst.copy_from_slice(src);
+                    });
+            }
             read_size += block_read_size;
             // move to next block
             if end_current_block == end {
@@ -349,7 +1361,7 @@ impl DiskInode {
         read_size
     }
     /// Write data into current disk inode
-    /// size must be adjusted properly beforehand
+    /// size must be adjusted and the blocks mapped properly beforehand
     pub fn write_at(
         &mut self,
         offset: usize,
@@ -367,16 +1379,16 @@ impl DiskInode {
             end_current_block = end_current_block.min(end);
             // write and update write size
             let block_write_size = end_current_block - start;
-            get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                Arc::clone(block_device),
-            )
-            .lock()
-            .modify(0, |data_block: &mut DataBlock| {
-                let src = &buf[write_size..write_size + block_write_size];
-                let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
-                dst.copy_from_slice(src);
-            });
+            let block_id = self.get_block_id(start_block as u32, block_device);
+            assert_ne!(block_id, 0, "Writing to a hole");
+            get_block_cache(block_id as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| {
+                    let src = &buf[write_size..write_size + block_write_size];
+                    let dst =
+                        &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
+                    dst.copy_from_slice(src);
+                });
             write_size += block_write_size;
             // move to next block
             if end_current_block == end {
@@ -387,48 +1399,185 @@ impl DiskInode {
         }
         write_size
     }
+    /// Overwrite bytes `start..end` with zeros where they are mapped,
+    /// which may be past the size
+    pub fn zero_at(&mut self, start: usize, end: usize, block_device: &Arc<dyn BlockDevice>) {
+        let mut offset = start;
+        while offset < end {
+            let block_end = ((offset / BLOCK_SZ + 1) * BLOCK_SZ).min(end);
+            let block_id = self.get_block_id((offset / BLOCK_SZ) as u32, block_device);
+            if block_id != 0 {
+                get_block_cache(block_id as usize, Arc::clone(block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| {
+                        data_block[offset % BLOCK_SZ..(block_end - 1) % BLOCK_SZ + 1].fill(0);
+                    });
+            }
+            offset = block_end;
+        }
+    }
 }
-/// A directory entry
-#[repr(C)]
+/// A directory entry.
+///
+/// Without `FEATURE_LONG_NAMES` it is stored as a record of `DIRENT_SZ` bytes,
+/// a name of at most `SHORT_NAME_LENGTH_LIMIT` bytes padded with 0 and the inode
+/// number. Otherwise it is stored as the inode number, a byte of the length of
+/// the name and the name, with no padding.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct DirEntry {
-    name: [u8; NAME_LENGTH_LIMIT + 1],
+    name: Vec<u8>,
     inode_id: u32,
 }
-/// Size of a directory entry
+/// Size of a fixed-length directory entry
 pub const DIRENT_SZ: usize = 32;
+/// Size of a variable-length directory entry without its name
+const LONG_DIRENT_HEADER_SZ: usize = 5;
+/// Inode number of a deleted directory entry left in place, see [`DirEntry::deleted`]
+const DELETED_INODE: u32 = u32::MAX;
 
 impl DirEntry {
-    /// Create an empty directory entry
-    pub fn empty() -> Self {
-        Self {
-            name: [0u8; NAME_LENGTH_LIMIT + 1],
-            inode_id: 0,
-        }
-    }
     /// Crate a directory entry from name and inode number
     pub fn new(name: &str, inode_id: u32) -> Self {
-        let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
-        bytes[..name.len()].copy_from_slice(name.as_bytes());
         Self {
-            name: bytes,
+            name: Vec::from(name.as_bytes()),
             inode_id,
         }
     }
+    /// Read the directory entry at byte `offset` of `dir_inode`,
+    /// `None` if there is no complete one there
+    pub fn read(
+        dir_inode: &DiskInode,
+        offset: usize,
+        long_names: bool,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<Self> {
+        let read_at = |offset, buf: &mut [u8]| dir_inode.read_at(offset, buf, block_device);
+        Self::read_by(read_at, offset, long_names)
+    }
+    /// Read the directory entry at byte `offset` of `dir_inode` as `read` does,
+    /// through block pointers that are `valid` only, see [`DiskInode::read_valid_at`]
+    pub fn read_valid(
+        dir_inode: &DiskInode,
+        offset: usize,
+        long_names: bool,
+        block_device: &Arc<dyn BlockDevice>,
+        valid: &impl Fn(u32) -> bool,
+    ) -> Option<Self> {
+        let read_at =
+            |offset, buf: &mut [u8]| dir_inode.read_valid_at(offset, buf, block_device, valid);
+        Self::read_by(read_at, offset, long_names)
+    }
+    /// Read the directory entry at byte `offset` with `read_at`
+    fn read_by(
+        read_at: impl Fn(usize, &mut [u8]) -> usize,
+        offset: usize,
+        long_names: bool,
+    ) -> Option<Self> {
+        if !long_names {
+            let mut record = [0u8; DIRENT_SZ];
+            if read_at(offset, &mut record) < DIRENT_SZ {
+                return None;
+            }
+            let name = &record[..DIRENT_SZ - 4];
+            let len = name
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(name.len());
+            let mut inode_id = [0u8; 4];
+            inode_id.copy_from_slice(&record[DIRENT_SZ - 4..]);
+            return Some(Self {
+                name: Vec::from(&name[..len]),
+                inode_id: u32::from_ne_bytes(inode_id),
+            });
+        }
+        let mut header = [0u8; LONG_DIRENT_HEADER_SZ];
+        if read_at(offset, &mut header) < LONG_DIRENT_HEADER_SZ {
+            return None;
+        }
+        let mut name = vec![0u8; header[4] as usize];
+        if read_at(offset + LONG_DIRENT_HEADER_SZ, &mut name) < name.len() {
+            return None;
+        }
+        let mut inode_id = [0u8; 4];
+        inode_id.copy_from_slice(&header[..4]);
+        Some(Self {
+            name,
+            inode_id: u32::from_ne_bytes(inode_id),
+        })
+    }
+    /// Parse the variable-length entry at the start of `bytes`,
+    /// `None` if there is no complete one
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let header = bytes.get(..LONG_DIRENT_HEADER_SZ)?;
+        let name = bytes.get(LONG_DIRENT_HEADER_SZ..LONG_DIRENT_HEADER_SZ + header[4] as usize)?;
+        let mut inode_id = [0u8; 4];
+        inode_id.copy_from_slice(&header[..4]);
+        Some(Self {
+            name: Vec::from(name),
+            inode_id: u32::from_ne_bytes(inode_id),
+        })
+    }
     /// Serialize into bytes
-    pub fn as_bytes(&self) -> &[u8] {
-        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIRENT_SZ) }
+    pub fn to_bytes(&self, long_names: bool) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len(long_names));
+        if long_names {
+            bytes.extend_from_slice(&self.inode_id.to_ne_bytes());
+            bytes.push(self.name.len() as u8);
+            bytes.extend_from_slice(&self.name);
+        } else {
+            bytes.extend_from_slice(&self.name);
+            bytes.resize(DIRENT_SZ - 4, 0);
+            bytes.extend_from_slice(&self.inode_id.to_ne_bytes());
+        }
+        bytes
     }
-    /// Serialize into mutable bytes
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, DIRENT_SZ) }
+    /// Size of the stored entry in bytes
+    pub fn len(&self, long_names: bool) -> usize {
+        if long_names {
+            LONG_DIRENT_HEADER_SZ + self.name.len()
+        } else {
+            DIRENT_SZ
+        }
     }
     /// Get name of the entry
     pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+        core::str::from_utf8(&self.name).unwrap()
+    }
+    /// Get name of the entry, `None` if it is not a valid name
+    pub fn try_name(&self) -> Option<&str> {
+        let name = core::str::from_utf8(&self.name).ok()?;
+        if name.is_empty() || name.contains(['/', '\0']) {
+            return None;
+        }
+        Some(name)
     }
     /// Get inode number of the entry
     pub fn inode_id(&self) -> u32 {
         self.inode_id
     }
+    /// Create a deleted entry stored in `len` bytes, which readers skip.
+    /// A variable-length one needs at least the bytes of a header.
+    pub fn deleted(len: usize, long_names: bool) -> Self {
+        let name_len = if long_names {
+            len - LONG_DIRENT_HEADER_SZ
+        } else {
+            0
+        };
+        Self {
+            name: vec![0; name_len],
+            inode_id: DELETED_INODE,
+        }
+    }
+    /// Whether the entry is a deleted one, see [`Self::deleted`]
+    pub fn is_deleted(&self) -> bool {
+        self.inode_id == DELETED_INODE
+    }
+    /// Whether `len` bytes can be stored as a deleted entry
+    pub fn fits_deleted(len: usize, long_names: bool) -> bool {
+        if long_names {
+            (LONG_DIRENT_HEADER_SZ..=LONG_DIRENT_HEADER_SZ + NAME_LENGTH_LIMIT).contains(&len)
+        } else {
+            len == DIRENT_SZ
+        }
+    }
 }
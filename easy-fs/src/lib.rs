@@ -11,14 +11,31 @@ extern crate log;
 mod bitmap;
 mod block_cache;
 mod block_dev;
+mod dir_index;
 mod efs;
+mod fsck;
+mod journal;
 mod layout;
 mod vfs;
 
 use bitmap::Bitmap;
-use block_cache::{block_cache_sync_all, get_block_cache};
+use block_cache::{block_cache_release_device, block_cache_sync_device, get_block_cache};
+pub use block_cache::{
+    block_cache_stats, block_cache_sync_all, set_block_cache_capacity, BlockCacheStats,
+    BLOCK_CACHE_SIZE,
+};
 pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
+pub use efs::{EasyFileSystem, StatFs};
+pub use fsck::FsckProblem;
+use journal::{
+    register_journal, replay_journal, spill_block, take_spilled, track_block, unregister_journal,
+    untracked, Transaction,
+};
 use layout::*;
-pub use vfs::Inode;
-pub use log::*;
\ No newline at end of file
+pub use layout::{
+    DiskInodeType, SuperBlock, EFS_MAGIC, EFS_VERSION, FEATURE_BITMAP_EXT, FEATURE_DIR_INDEX,
+    FEATURE_EXTENTS, FEATURE_FREE_COUNTS, FEATURE_LONG_NAMES, JOURNAL_BLOCKS, MAX_FILE_SIZE,
+    MODE_MASK, NAME_LENGTH_LIMIT, SHORT_NAME_LENGTH_LIMIT,
+};
+pub use log::*;
+pub use vfs::{Inode, Metadata, DIR_OP_BLOCKS, SYMLOOP_MAX};
@@ -1,14 +1,54 @@
 use super::{
-    block_cache_sync_all, get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType,
-    EasyFileSystem, DIRENT_SZ,
+    block_cache_sync_device, dir_index, get_block_cache, untracked, BlockDevice, DirEntry,
+    DiskInode, DiskInodeType, EasyFileSystem, StatFs, Transaction, BLOCK_SZ, MAX_FILE_SIZE,
+    MODE_MASK,
 };
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
 use alloc::vec::Vec;
 use spin::{Mutex, MutexGuard};
+
+/// Maximum number of symbolic links followed in one path lookup
+pub const SYMLOOP_MAX: usize = 8;
+/// Bytes of an inode mapped in one transaction, which keeps the bitmap and
+/// indirect blocks it modifies within the journal
+const GROW_STEP: u64 = 64 * BLOCK_SZ as u64;
+/// Data blocks of an inode unmapped in one transaction. Each of them and the
+/// index blocks left empty above it may lie under a bitmap block of its own.
+const FREE_STEP: u32 = 8;
+/// Free blocks needed to start a directory operation, the most it may take:
+/// a dirent over two new blocks with three index blocks above each, two blocks
+/// of the name index, and the first block and the name index of a new directory
+pub const DIR_OP_BLOCKS: u32 = 13;
+
+/// Metadata of an inode, see [`Inode::metadata`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Inode number
+    pub inode_id: u32,
+    /// File, directory, ...
+    pub type_: DiskInodeType,
+    /// Permission bits
+    pub mode: u16,
+    /// Owner user id
+    pub uid: u32,
+    /// Owner group id
+    pub gid: u32,
+    /// Size in bytes
+    pub size: usize,
+    /// Last access time in seconds
+    pub atime: u32,
+    /// Last modification time of the data in seconds
+    pub mtime: u32,
+    /// Last change time of the data or metadata in seconds
+    pub ctime: u32,
+}
+
 /// Virtual filesystem layer over easy-fs
 
 #[allow(missing_docs)]
+#[derive(Clone)]
 pub struct Inode {
     pub block_id: usize,
     pub block_offset: usize,
@@ -43,244 +83,967 @@ impl Inode {
             .lock()
             .modify(self.block_offset, f)
     }
+    /// Read the dirents of a directory disk inode along with their offsets,
+    /// deleted ones included
+    fn read_slots(&self, disk_inode: &DiskInode, fs: &EasyFileSystem) -> Vec<(usize, DirEntry)> {
+        let long_names = fs.long_names();
+        let mut v = Vec::new();
+        let mut offset = 0;
+        while let Some(dirent) = DirEntry::read(disk_inode, offset, long_names, &self.block_device)
+        {
+            let len = dirent.len(long_names);
+            v.push((offset, dirent));
+            offset += len;
+        }
+        v
+    }
+    /// Read the dirents of a directory disk inode along with their offsets
+    fn read_dirents(&self, disk_inode: &DiskInode, fs: &EasyFileSystem) -> Vec<(usize, DirEntry)> {
+        let mut v = self.read_slots(disk_inode, fs);
+        v.retain(|(_, dirent)| !dirent.is_deleted());
+        v
+    }
     /// Find inode under a disk inode by name
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
+    fn find_inode_id(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+        fs: &EasyFileSystem,
+    ) -> Option<u32> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-        let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
-            assert_eq!(
-                disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                DIRENT_SZ,
-            );
-            if dirent.name() == name {
-                return Some(dirent.inode_id() as u32);
-            }
+        if let Some(root) = disk_inode.index_root() {
+            return dir_index::lookup(root, name, &self.block_device);
         }
-        None
+        self.read_dirents(disk_inode, fs)
+            .into_iter()
+            .find(|(_, dirent)| dirent.name() == name)
+            .map(|(_, dirent)| dirent.inode_id())
+    }
+    /// Get a vfs inode by inode id, the same one while it is in use
+    fn get_inode(&self, inode_id: u32, fs: &EasyFileSystem) -> Arc<Inode> {
+        fs.cached_inode(inode_id, || {
+            let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+            Self::new(
+                block_id,
+                block_offset,
+                self.fs.clone(),
+                self.block_device.clone(),
+            )
+        })
+    }
+    /// Read all dirents of current inode
+    fn dirents(&self, fs: &EasyFileSystem) -> Vec<DirEntry> {
+        self.read_disk_inode(|disk_inode| {
+            self.read_dirents(disk_inode, fs)
+                .into_iter()
+                .map(|(_, dirent)| dirent)
+                .collect()
+        })
+    }
+    /// Get the max length of a name under current inode
+    pub fn name_limit(&self) -> usize {
+        self.fs.lock().name_limit()
+    }
+    /// Get the inode id of current inode
+    pub fn inode_id(&self) -> u32 {
+        let fs = self.fs.lock();
+        fs.get_inode_id(self.block_id as u32, self.block_offset)
+    }
+    /// Whether current inode is a directory
+    pub fn is_dir(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+    /// Whether current inode is a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_symlink())
+    }
+    /// Get the target path of current symbolic link, `None` if it is not a link
+    pub fn readlink(&self) -> Option<String> {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_symlink() {
+                return None;
+            }
+            let mut target = vec![0u8; disk_inode.size as usize];
+            disk_inode.read_at(0, &mut target, &self.block_device);
+            String::from_utf8(target).ok()
+        })
     }
     /// Find inode under current inode by name
     pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
         let fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
+            if !disk_inode.is_dir() {
+                return None;
+            }
+            self.find_inode_id(name, disk_inode, &fs)
+                .map(|inode_id| self.get_inode(inode_id, &fs))
         })
     }
+    /// Read the dirent at byte `offset` of current directory, return its name,
+    /// inode id, the type of that inode and the offset of the next dirent.
+    /// Offset 0 is the first dirent, and deleted ones are skipped.
+    pub fn read_dirent(&self, mut offset: usize) -> Option<(String, u32, DiskInodeType, usize)> {
+        let fs = self.fs.lock();
+        let long_names = fs.long_names();
+        let dirent = self.read_disk_inode(|disk_inode| loop {
+            let dirent = DirEntry::read(disk_inode, offset, long_names, &self.block_device)?;
+            if !dirent.is_deleted() {
+                return Some(dirent);
+            }
+            offset += dirent.len(long_names);
+        })?;
+        let type_ = self
+            .get_inode(dirent.inode_id(), &fs)
+            .read_disk_inode(|disk_inode| disk_inode.type_());
+        let next = offset + dirent.len(long_names);
+        Some((String::from(dirent.name()), dirent.inode_id(), type_, next))
+    }
+    /// Find the name of the dirent referring to `inode_id` under current inode,
+    /// "." and ".." are skipped
+    pub fn find_name(&self, inode_id: u32) -> Option<String> {
+        let fs = self.fs.lock();
+        self.dirents(&fs)
+            .iter()
+            .find(|dirent| {
+                dirent.inode_id() == inode_id && dirent.name() != "." && dirent.name() != ".."
+            })
+            .map(|dirent| String::from(dirent.name()))
+    }
+    /// Find inode by path. A path starting with '/' is looked up from the
+    /// root directory, otherwise from current inode.
+    /// Symbolic links are followed, at most `SYMLOOP_MAX` of them.
+    pub fn find_path(&self, path: &str) -> Option<Arc<Inode>> {
+        let mut links = 0;
+        self.walk(path, &mut links)
+    }
+    /// Look up `path` from current directory, `links` counts the symbolic links
+    /// followed so far
+    fn walk(&self, path: &str, links: &mut usize) -> Option<Arc<Inode>> {
+        if path.is_empty() {
+            return None;
+        }
+        let mut inode = if path.starts_with('/') {
+            Arc::new(EasyFileSystem::root_inode(&self.fs))
+        } else {
+            Arc::new(self.clone())
+        };
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let mut next = inode.find(name)?;
+            if next.is_symlink() {
+                *links += 1;
+                if *links > SYMLOOP_MAX {
+                    return None;
+                }
+                // a relative target is looked up from the directory holding the link
+                next = inode.walk(next.readlink()?.as_str(), links)?;
+            }
+            inode = next;
+        }
+        Some(inode)
+    }
+    /// Split path into the inode of its parent directory and its last component,
+    /// e.g. "/usr/bin/" gives the inode of "/usr" and "bin".
+    /// Return `None` if the parent is not found or the path has no last component.
+    pub fn find_parent<'a>(&self, path: &'a str) -> Option<(Arc<Inode>, &'a str)> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = match path.rfind('/') {
+            Some(pos) => (&path[..pos + 1], &path[pos + 1..]),
+            None => ("", path),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        let parent = if dir.is_empty() {
+            Arc::new(self.clone())
+        } else {
+            self.find_path(dir)?
+        };
+        Some((parent, name))
+    }
     /// Increase the size of a disk inode
     fn increase_size(
         &self,
-        new_size: u32,
+        new_size: u64,
         disk_inode: &mut DiskInode,
         fs: &mut MutexGuard<EasyFileSystem>,
     ) {
         if new_size < disk_inode.size {
             return;
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        let mut alloc = |count| fs.alloc_data_run(count);
+        disk_inode
+            .increase_size(new_size, &mut alloc, &self.block_device)
+            .expect("Free blocks are checked before a directory grows");
+    }
+
+    /// Map the holes in bytes `start..end` of current inode, `GROW_STEP` bytes
+    /// per transaction, and grow it to cover the bytes mapped in the last one if `grow`.
+    /// Return where the bytes mapped from `start` on end, which is before `end`
+    /// if the filesystem runs out of blocks or current inode out of extents.
+    fn allocate(
+        &self,
+        start: u64,
+        end: u64,
+        grow: bool,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> u64 {
+        let step = (GROW_STEP / BLOCK_SZ as u64) as u32;
+        let end_block = end.div_ceil(BLOCK_SZ as u64) as u32;
+        let mut block = (start / BLOCK_SZ as u64) as u32;
+        loop {
+            let _txn = Transaction::begin(&self.block_device);
+            let next = end_block.min(block + step);
+            let mapped = self.modify_disk_inode(|disk_inode| {
+                let mut alloc = |count| fs.alloc_data_run(count);
+                let mapped = match disk_inode.allocate(block, next, &mut alloc, &self.block_device)
+                {
+                    Ok(()) if next < end_block => return None,
+                    Ok(()) => end,
+                    Err(data_blocks_dealloc) => {
+                        for data_block in data_blocks_dealloc.into_iter() {
+                            fs.dealloc_data(data_block);
+                        }
+                        let mapped_end = disk_inode.mapped_end(block, next, &self.block_device);
+                        end.min(mapped_end as u64 * BLOCK_SZ as u64)
+                    }
+                };
+                if grow && mapped > disk_inode.size.max(start) {
+                    disk_inode.size = mapped;
+                    disk_inode.mtime = fs.now();
+                    disk_inode.ctime = disk_inode.mtime;
+                }
+                Some(mapped)
+            });
+            if let Some(mapped) = mapped {
+                return mapped;
+            }
+            block = next;
+        }
+    }
+
+    /// Append a dirent to current directory, in the place of a deleted one if it fits
+    fn append_dirent(&self, name: &str, inode_id: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        let long_names = fs.long_names();
+        let mut dirent = DirEntry::new(name, inode_id).to_bytes(long_names);
+        self.modify_disk_inode(|dir_inode| {
+            // the rest of a deleted dirent is left as a deleted one
+            let reused = self
+                .read_slots(dir_inode, fs)
+                .into_iter()
+                .find(|(_, slot)| {
+                    slot.is_deleted()
+                        && slot
+                            .len(long_names)
+                            .checked_sub(dirent.len())
+                            .is_some_and(|rest| {
+                                rest == 0 || DirEntry::fits_deleted(rest, long_names)
+                            })
+                });
+            let offset = match reused {
+                Some((offset, slot)) => {
+                    let rest = slot.len(long_names) - dirent.len();
+                    if rest > 0 {
+                        dirent.extend(DirEntry::deleted(rest, long_names).to_bytes(long_names));
+                    }
+                    offset
+                }
+                None => {
+                    // append file in the dirent
+                    let offset = dir_inode.size as usize;
+                    // increase size
+                    self.increase_size((offset + dirent.len()) as u64, dir_inode, fs);
+                    offset
+                }
+            };
+            // write dirent
+            dir_inode.write_at(offset, &dirent, &self.block_device);
+            self.index_insert(name, inode_id, dir_inode, fs);
+            dir_inode.mtime = fs.now();
+            dir_inode.ctime = dir_inode.mtime;
+        });
+    }
+
+    /// Add `name` of inode `inode_id` to the name index of a directory disk inode if it keeps one
+    fn index_insert(
+        &self,
+        name: &str,
+        inode_id: u32,
+        dir_inode: &mut DiskInode,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) {
+        if let Some(root) = dir_inode.index_root() {
+            let mut alloc = || {
+                fs.alloc_data()
+                    .expect("Free blocks are checked before a dirent is added")
+            };
+            let root = dir_index::insert(root, name, inode_id, &mut alloc, &self.block_device);
+            dir_inode.set_index_root(root);
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
     }
 
-    /// Create inode under current inode by name
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+    /// Remove `name` from the name index of a directory disk inode if it keeps one
+    fn index_remove(&self, name: &str, dir_inode: &DiskInode, fs: &mut MutexGuard<EasyFileSystem>) {
+        if let Some(root) = dir_inode.index_root() {
+            if let Some(block_id) = dir_index::remove(root, name, &self.block_device) {
+                fs.dealloc_data(block_id);
+            }
+        }
+    }
+
+    /// Add "." and ".." to an empty directory
+    pub(crate) fn init_dir(&self, parent_id: u32) {
+        let mut fs = self.fs.lock();
+        self.write_dot_entries(parent_id, &mut fs);
+    }
+
+    fn write_dot_entries(&self, parent_id: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        let inode_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        self.append_dirent(".", inode_id, fs);
+        self.append_dirent("..", parent_id, fs);
+    }
+
+    /// Create inode of `type_` under current inode by name
+    fn create_inode(&self, name: &str, type_: DiskInodeType) -> Option<Arc<Inode>> {
         let mut fs = self.fs.lock();
+        if name.len() > fs.name_limit() || fs.free_blocks() < DIR_OP_BLOCKS {
+            return None;
+        }
+        let _txn = Transaction::begin(&self.block_device);
         let op = |root_inode: &DiskInode| {
             // assert it is a directory
             assert!(root_inode.is_dir());
             // has the file been created?
-            self.find_inode_id(name, root_inode)
+            self.find_inode_id(name, root_inode, &fs)
         };
-        if self.read_disk_inode(op).is_some() {
+        if self.is_removed() || self.read_disk_inode(op).is_some() {
             return None;
         }
         // create a new file
         // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
+        let new_inode_id = fs.alloc_inode()?;
         // initialize inode
         let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
+        let is_dir = type_ == DiskInodeType::Directory;
         get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(DiskInodeType::File);
+                new_inode.initialize(type_, fs.now(), fs.extents());
+                if is_dir && fs.dir_index() {
+                    new_inode.set_indexed();
+                }
             });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
-        });
+        self.append_dirent(name, new_inode_id, &mut fs);
 
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        block_cache_sync_all();
+        let new_inode = self.get_inode(new_inode_id, &fs);
+        if is_dir {
+            let parent_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+            new_inode.write_dot_entries(parent_id, &mut fs);
+            // ".." of the new directory
+            self.add_links(1);
+        }
         // return inode
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
+        Some(new_inode)
         // release efs lock automatically by compiler
     }
 
-    /// Create hard link, only ROOT_NODE can call it
+    /// Create inode under current inode by name.
+    /// `None` if the name exists, is too long, or no inode or block is left for it.
+    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::File)
+    }
+
+    /// Create a directory under current inode by name
+    pub fn mkdir(&self, name: &str) -> Option<Arc<Inode>> {
+        self.create_inode(name, DiskInodeType::Directory)
+    }
+
+    /// Create symbolic link `name` under current inode pointing to `target`.
+    /// The target is stored as is and need not exist.
+    pub fn symlink(&self, name: &str, target: &str) -> Option<Arc<Inode>> {
+        if target.is_empty() {
+            return None;
+        }
+        // the link and its target are committed together
+        let _txn = Transaction::begin(&self.block_device);
+        let inode = self.create_inode(name, DiskInodeType::Symlink)?;
+        if inode.write_at(0, target.as_bytes()) < target.len() {
+            // out of blocks for the target
+            self.remove(name);
+            return None;
+        }
+        Some(inode)
+    }
+
+    /// Create hard link `new` to `old`, both under current inode
     pub fn link(&self, old: &str, new: &str) -> Option<Arc<Inode>> {
+        let old_inode = self.find(old)?;
+        self.link_inode(new, &old_inode)
+    }
+
+    /// Create hard link `name` under current inode to `inode`
+    pub fn link_inode(&self, name: &str, inode: &Inode) -> Option<Arc<Inode>> {
         let mut fs = self.fs.lock();
+        if name.len() > fs.name_limit() || fs.free_blocks() < DIR_OP_BLOCKS {
+            return None;
+        }
+        let _txn = Transaction::begin(&self.block_device);
         let op = |root_inode: &DiskInode| {
             // assert it is a directory
             assert!(root_inode.is_dir());
             // has the file been created?
-            self.find_inode_id(old, root_inode)
+            self.find_inode_id(name, root_inode, &fs)
         };
-        if let Some(old_inode_id) =  self.read_disk_inode(op) {
-            // We need to keep old inode and new inode has the same 'block_id' and 'block_offset'.
-            // Thus we can create a hard link.
-            let new_inode_id = old_inode_id;
-            let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
-            self.modify_disk_inode(|root_inode| {
-                // append file in the dirent
-                let file_count = (root_inode.size as usize) / DIRENT_SZ;
-                let new_size = (file_count + 1) * DIRENT_SZ;
-                // increase size
-                self.increase_size(new_size as u32, root_inode, &mut fs);
-                // write dirent
-                let dirent = DirEntry::new(new, new_inode_id);
-                root_inode.write_at(
-                    file_count * DIRENT_SZ,
-                    dirent.as_bytes(),
-                    &self.block_device,
-                );
-            });
-            Some(Arc::new(Self::new(
-                new_inode_block_id,
-                new_inode_block_offset,
-                self.fs.clone(),
-                self.block_device.clone(),
-            )))
-        } else {
-            // the old dir doesn't exist
-            None
+        if self.is_removed() || self.read_disk_inode(op).is_some() {
+            return None;
         }
+        // We need to keep old inode and new inode has the same 'block_id' and 'block_offset'.
+        // Thus we can create a hard link.
+        let inode_id = fs.get_inode_id(inode.block_id as u32, inode.block_offset);
+        self.append_dirent(name, inode_id, &mut fs);
+        inode.modify_disk_inode(|disk_inode| {
+            disk_inode.ctime = fs.now();
+            disk_inode.nlink += 1;
+        });
+        Some(self.get_inode(inode_id, &fs))
     }
 
-    /// Unlink
-    pub fn unlink(&self, name: &str) -> isize {
-        let _fs = self.fs.lock();
-        let op = |root_inode: &DiskInode| {
+    /// Remove the dirent named `name` from current directory, return whether it is found.
+    /// The last dirent is moved into its place if it is as long, otherwise it is left
+    /// as a deleted one, so that only a few blocks are modified. Removing the last
+    /// dirent drops the deleted ones before it too, `FREE_STEP` blocks of them at most.
+    fn remove_dirent(&self, name: &str, fs: &mut MutexGuard<EasyFileSystem>) -> bool {
+        let long_names = fs.long_names();
+        self.modify_disk_inode(|root_inode| {
             // assert it is a directory
             assert!(root_inode.is_dir());
-            // has the file been created?
-            self.find_inode_id(name, root_inode)
+            let slots = self.read_slots(root_inode, fs);
+            let found = slots
+                .iter()
+                .position(|(_, dirent)| !dirent.is_deleted() && dirent.name() == name);
+            let (offset, len) = match found {
+                Some(i) => (slots[i].0, slots[i].1.len(long_names)),
+                None => return false,
+            };
+            let (last_offset, last) = slots.last().unwrap();
+            let new_size = if *last_offset == offset {
+                let bound = offset.saturating_sub(FREE_STEP as usize * BLOCK_SZ);
+                slots
+                    .iter()
+                    .rev()
+                    .skip(1)
+                    .take_while(|(offset, dirent)| dirent.is_deleted() && *offset >= bound)
+                    .last()
+                    .map_or(offset, |(offset, _)| *offset)
+            } else if last.len(long_names) == len {
+                root_inode.write_at(offset, &last.to_bytes(long_names), &self.block_device);
+                *last_offset
+            } else {
+                let deleted = DirEntry::deleted(len, long_names).to_bytes(long_names);
+                root_inode.write_at(offset, &deleted, &self.block_device);
+                root_inode.size as usize
+            };
+            for data_block in root_inode.decrease_size(new_size as u64, &self.block_device) {
+                fs.dealloc_data(data_block);
+            }
+            self.index_remove(name, root_inode, fs);
+            root_inode.mtime = fs.now();
+            root_inode.ctime = root_inode.mtime;
+            // unlink one per call
+            true
+        })
+    }
+
+    /// Point the dirent named `name` in current directory to `inode_id`
+    /// and rename it to `new_name`, return whether it is found
+    fn replace_dirent(
+        &self,
+        name: &str,
+        new_name: &str,
+        inode_id: u32,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> bool {
+        let long_names = fs.long_names();
+        let found = self.read_disk_inode(|dir_inode| {
+            self.read_dirents(dir_inode, fs)
+                .into_iter()
+                .find(|(_, dirent)| dirent.name() == name)
+        });
+        let (offset, dirent) = match found {
+            Some(found) => found,
+            None => return false,
         };
-        // Only when we find the path name, can we unlink it 
-        if let Some(_) = self.read_disk_inode(op) {
-            self.modify_disk_inode(|root_inode| {
-                let mut buf = DirEntry::empty();
-                let mut swap = DirEntry::empty();
-                let file_count = (root_inode.size as usize) / DIRENT_SZ;
-                for i in 0..file_count {
-                    if root_inode.read_at(DIRENT_SZ * i, buf.as_bytes_mut(), &self.block_device) == DIRENT_SZ {
-                        if buf.name() == name {
-                            // we are asked not to delete the node so we overwrite the node
-                            root_inode.read_at(DIRENT_SZ *(file_count - 1), swap.as_bytes_mut(), &self.block_device);
-                            root_inode.write_at(DIRENT_SZ * i, swap.as_bytes_mut(), &self.block_device);
-                            root_inode.size -= DIRENT_SZ as u32;
-                            // unlink one per call
-                            break;
-                        }
-                    }
-                }
-            });
-            0
-        } else {
-            // cannot find the file
-            -1
+        let new_dirent = DirEntry::new(new_name, inode_id);
+        if new_dirent.len(long_names) != dirent.len(long_names) {
+            // a dirent of another length does not fit in place
+            self.remove_dirent(name, fs);
+            self.append_dirent(new_name, inode_id, fs);
+            return true;
         }
+        self.modify_disk_inode(|dir_inode| {
+            dir_inode.write_at(offset, &new_dirent.to_bytes(long_names), &self.block_device);
+            match dir_inode.index_root() {
+                Some(root) if name == new_name => {
+                    dir_index::replace(root, name, inode_id, &self.block_device);
+                }
+                Some(_) => {
+                    self.index_remove(name, dir_inode, fs);
+                    self.index_insert(new_name, inode_id, dir_inode, fs);
+                }
+                None => {}
+            }
+            dir_inode.mtime = fs.now();
+            dir_inode.ctime = dir_inode.mtime;
+        });
+        true
     }
 
-    /// get link number of thn given file
-    pub fn get_link_num(&self, block_id: usize, block_offset: usize) -> u32 {
-        let fs = self.fs.lock();
-        let mut count = 0;
-        self.read_disk_inode(|root_inode| {
-            let mut buf = DirEntry::empty();
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            for i in 0..file_count {
-                assert_eq!(
-                    root_inode.read_at(DIRENT_SZ * i, buf.as_bytes_mut(), &self.block_device),
-                    DIRENT_SZ,
-                );
-                let (this_inode_block_id, this_inode_block_offset) = fs.get_disk_inode_pos(buf.inode_id());
-                if this_inode_block_id as usize == block_id && this_inode_block_offset == block_offset {
-                    count += 1;
+    /// Rename `old_name` under current inode to `new_name` under `new_dir`.
+    ///
+    /// An existing `new_name` is replaced by pointing its dirent to the renamed
+    /// inode, so it never disappears. The replaced inode is freed when no other
+    /// link is left, a directory can only replace an empty directory and a file
+    /// can only replace a file. Renaming within a directory rewrites the dirent in place.
+    pub fn rename(&self, old_name: &str, new_dir: &Inode, new_name: &str) -> isize {
+        let is_dot = |name: &str| name == "." || name == "..";
+        if is_dot(old_name) || is_dot(new_name) {
+            return -1;
+        }
+        let mut fs = self.fs.lock();
+        if new_name.len() > fs.name_limit() || fs.free_blocks() < DIR_OP_BLOCKS {
+            return -1;
+        }
+        let txn = Transaction::begin(&self.block_device);
+        let inode_id =
+            match self.read_disk_inode(|dir_inode| self.find_inode_id(old_name, dir_inode, &fs)) {
+                Some(inode_id) => inode_id,
+                None => return -1,
+            };
+        let inode = self.get_inode(inode_id, &fs);
+        let is_dir = inode.read_disk_inode(|disk_inode| disk_inode.is_dir());
+        let dir_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        let new_dir_id = fs.get_inode_id(new_dir.block_id as u32, new_dir.block_offset);
+        if !new_dir.read_disk_inode(|disk_inode| disk_inode.is_dir()) || new_dir.is_removed() {
+            return -1;
+        }
+        if is_dir && dir_id != new_dir_id {
+            // a directory cannot be moved into itself
+            let mut id = new_dir_id;
+            while id != 0 {
+                if id == inode_id {
+                    return -1;
+                }
+                let dir = self.get_inode(id, &fs);
+                id = match dir
+                    .read_disk_inode(|disk_inode| dir.find_inode_id("..", disk_inode, &fs))
+                {
+                    Some(parent_id) => parent_id,
+                    // a corrupted directory without ".."
+                    None => return -1,
+                };
+            }
+        }
+        let target_id =
+            new_dir.read_disk_inode(|disk_inode| new_dir.find_inode_id(new_name, disk_inode, &fs));
+        let mut freed = None;
+        match target_id {
+            // both names refer to the same inode
+            Some(target_id) if target_id == inode_id => return 0,
+            Some(target_id) => {
+                let target = self.get_inode(target_id, &fs);
+                let target_is_dir = target.read_disk_inode(|disk_inode| disk_inode.is_dir());
+                // an empty directory only has "." and ".."
+                if target_is_dir != is_dir || (target_is_dir && target.dirents(&fs).len() != 2) {
+                    return -1;
+                }
+                new_dir.replace_dirent(new_name, new_name, inode_id, &mut fs);
+                self.remove_dirent(old_name, &mut fs);
+                let links = if target_is_dir {
+                    // ".." of the replaced directory
+                    new_dir.add_links(-1);
+                    target.modify_disk_inode(|disk_inode| disk_inode.nlink = 0);
+                    0
+                } else {
+                    target.add_links(-1)
+                };
+                if links == 0 && Self::release(target_id, &mut fs) {
+                    freed = Some((target, target_id));
                 }
             }
+            None if dir_id == new_dir_id => {
+                self.replace_dirent(old_name, new_name, inode_id, &mut fs);
+            }
+            None => {
+                new_dir.append_dirent(new_name, inode_id, &mut fs);
+                self.remove_dirent(old_name, &mut fs);
+            }
+        }
+        if is_dir && dir_id != new_dir_id {
+            inode.replace_dirent("..", "..", new_dir_id, &mut fs);
+            self.add_links(-1);
+            new_dir.add_links(1);
+        }
+        // the replaced inode is freed in transactions of its own
+        drop(txn);
+        if let Some((target, target_id)) = freed {
+            target.free(target_id, &mut fs);
+        }
+        0
+    }
+
+    /// Unlink the file `name` under current inode. It is freed once no link to it
+    /// is left, and not before it is last closed if it is open, see [`Inode::open`].
+    pub fn unlink(&self, name: &str) -> isize {
+        let mut fs = self.fs.lock();
+        let txn = Transaction::begin(&self.block_device);
+        let inode_id =
+            match self.read_disk_inode(|dir_inode| self.find_inode_id(name, dir_inode, &fs)) {
+                Some(inode_id) => inode_id,
+                None => return -1,
+            };
+        let inode = self.get_inode(inode_id, &fs);
+        // directories are removed by `rmdir`
+        if inode.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return -1;
+        }
+        self.remove_dirent(name, &mut fs);
+        let free = inode.add_links(-1) == 0 && Self::release(inode_id, &mut fs);
+        // the inode is freed in transactions of its own
+        drop(txn);
+        if free {
+            inode.free(inode_id, &mut fs);
+        }
+        0
+    }
+
+    /// Remove the empty directory `name` under current inode and free it,
+    /// not before it is last closed if it is open, like [`Inode::unlink`]
+    pub fn rmdir(&self, name: &str) -> isize {
+        if name == "." || name == ".." {
+            return -1;
+        }
+        let mut fs = self.fs.lock();
+        let txn = Transaction::begin(&self.block_device);
+        let inode_id =
+            match self.read_disk_inode(|root_inode| self.find_inode_id(name, root_inode, &fs)) {
+                Some(inode_id) => inode_id,
+                None => return -1,
+            };
+        let dir = self.get_inode(inode_id, &fs);
+        // an empty directory only has "." and ".."
+        if !dir.read_disk_inode(|disk_inode| disk_inode.is_dir()) || dir.dirents(&fs).len() != 2 {
+            return -1;
+        }
+        self.remove_dirent(name, &mut fs);
+        // ".." of the removed directory
+        self.add_links(-1);
+        dir.modify_disk_inode(|disk_inode| disk_inode.nlink = 0);
+        let free = Self::release(inode_id, &mut fs);
+        drop(txn);
+        if free {
+            dir.free(inode_id, &mut fs);
+        }
+        0
+    }
+
+    /// Unlink the file `name` under current inode, the same as [`Inode::unlink`]
+    pub fn remove(&self, name: &str) -> isize {
+        self.unlink(name)
+    }
+
+    /// Count an open of current inode. A file open when its last link is
+    /// removed is kept until it is closed as many times by [`Inode::close`].
+    pub fn open(&self) {
+        let mut fs = self.fs.lock();
+        let inode_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        fs.open_inode(inode_id);
+    }
+
+    /// Count a close of current inode, which is freed if it is the last one
+    /// and no link to it is left
+    pub fn close(&self) {
+        let mut fs = self.fs.lock();
+        let inode_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        if fs.close_inode(inode_id) {
+            self.free(inode_id, &mut fs);
+        }
+    }
+
+    /// Record the inode `inode_id`, whose last link is removed, as an orphan until
+    /// it is freed, see [`EasyFileSystem::open`], and leave it to the last
+    /// [`Inode::close`] if it is open. Return whether to free it now.
+    fn release(inode_id: u32, fs: &mut MutexGuard<EasyFileSystem>) -> bool {
+        fs.store_orphan(inode_id);
+        if fs.is_open(inode_id) {
+            fs.add_orphan(inode_id);
+            return false;
+        }
+        true
+    }
+
+    /// Free the data blocks of current inode and the inode itself, whose id is `inode_id`.
+    /// The data blocks are unmapped first, see [`Inode::free_from`].
+    fn free(&self, inode_id: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        self.free_from(0, fs);
+        let _txn = Transaction::begin(&self.block_device);
+        self.modify_disk_inode(|disk_inode| {
+            // only the name index of a directory is left
+            for data_block in disk_inode.clear_size(&self.block_device) {
+                fs.dealloc_data(data_block);
+            }
         });
-        count
+        fs.clear_orphan(inode_id);
+        fs.dealloc_inode(inode_id);
+    }
+
+    /// Free the orphans recorded in the super block, which a crash left
+    /// unlinked but not freed, see [`EasyFileSystem::open`]
+    pub(crate) fn free_orphans(&self) {
+        let mut fs = self.fs.lock();
+        for inode_id in fs.stored_orphans() {
+            if fs.is_allocated_inode(inode_id) {
+                self.get_inode(inode_id, &fs).free(inode_id, &mut fs);
+            } else {
+                let _txn = Transaction::begin(&self.block_device);
+                fs.clear_orphan(inode_id);
+            }
+        }
+    }
+
+    /// Unmap the data blocks of current inode from `start` on, past the size too,
+    /// `FREE_STEP` blocks per transaction from the last one
+    fn free_from(&self, start: u32, fs: &mut MutexGuard<EasyFileSystem>) {
+        loop {
+            let _txn = Transaction::begin(&self.block_device);
+            let done = self.modify_disk_inode(|disk_inode| {
+                let from = disk_inode.unmap_start(start, FREE_STEP, &self.block_device);
+                for data_block in disk_inode.unmap_from(from, &self.block_device) {
+                    fs.dealloc_data(data_block);
+                }
+                from == start
+            });
+            if done {
+                return;
+            }
+        }
+    }
+
+    /// Get the number of hard links to current inode, that is, the number of
+    /// dirents referring to it in the whole filesystem, "." and ".." included
+    pub fn link_count(&self) -> u32 {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.nlink)
+    }
+
+    /// Whether current directory is removed but still open, so that nothing
+    /// may be added to it
+    fn is_removed(&self) -> bool {
+        self.read_disk_inode(|disk_inode| disk_inode.nlink == 0)
+    }
+
+    /// Add `delta` to the link count of current inode, return the new count.
+    /// The count of a corrupted inode stops at 0.
+    fn add_links(&self, delta: i32) -> u32 {
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.nlink = disk_inode.nlink.saturating_add_signed(delta);
+            disk_inode.nlink
+        })
     }
 
     /// List inodes under current inode
     pub fn ls(&self) -> Vec<String> {
+        let fs = self.fs.lock();
+        self.dirents(&fs)
+            .iter()
+            .map(|dirent| String::from(dirent.name()))
+            .collect()
+    }
+    /// Get the size of current inode in bytes
+    pub fn size(&self) -> usize {
         let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
-            }
-            v
+        self.read_disk_inode(|disk_inode| disk_inode.size as usize)
+    }
+    /// Get the metadata of current inode
+    pub fn metadata(&self) -> Metadata {
+        let fs = self.fs.lock();
+        let inode_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        self.read_disk_inode(|disk_inode| Metadata {
+            inode_id,
+            type_: disk_inode.type_(),
+            mode: disk_inode.mode,
+            uid: disk_inode.uid,
+            gid: disk_inode.gid,
+            size: disk_inode.size as usize,
+            atime: disk_inode.atime,
+            mtime: disk_inode.mtime,
+            ctime: disk_inode.ctime,
         })
     }
+    /// Get the usage of the filesystem holding current inode
+    pub fn statfs(&self) -> StatFs {
+        self.fs.lock().statfs()
+    }
+    /// Change the permission bits of current inode
+    pub fn chmod(&self, mode: u16) {
+        let fs = self.fs.lock();
+        let _txn = Transaction::begin(&self.block_device);
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.mode = mode & MODE_MASK;
+            disk_inode.ctime = fs.now();
+        });
+    }
     /// Read data from current inode
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+        let fs = self.fs.lock();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.atime = fs.now();
+            disk_inode.read_at(offset, buf, &self.block_device)
+        })
     }
-    /// Write data to current inode
+    /// Write data to current inode, the part past `MAX_FILE_SIZE` is not written,
+    /// nor the part past the blocks left, so fewer bytes are written on a full filesystem,
+    /// or on a file so fragmented that it has all the extents it can.
+    /// Only the blocks written are mapped, `GROW_STEP` bytes per transaction,
+    /// so writing past the end leaves a hole. The data of a file is not journaled.
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
         let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
+        if offset as u64 >= MAX_FILE_SIZE || buf.is_empty() {
+            return 0;
+        }
+        let buf = &buf[..buf.len().min((MAX_FILE_SIZE - offset as u64) as usize)];
+        let end = self.allocate(offset as u64, (offset + buf.len()) as u64, true, &mut fs);
+        if end as usize <= offset {
+            return 0;
+        }
+        let buf = &buf[..end as usize - offset];
+        self.modify_disk_inode(|disk_inode| {
+            let write = |disk_inode: &mut DiskInode| {
+                disk_inode.mtime = fs.now();
+                disk_inode.ctime = disk_inode.mtime;
+                disk_inode.write_at(offset, buf, &self.block_device)
+            };
+            if disk_inode.is_file() {
+                untracked(&self.block_device, || write(disk_inode))
+            } else {
+                write(disk_inode)
+            }
+        })
+    }
+    /// Allocate bytes `offset..offset + len` of current inode so that writing
+    /// them needs no more blocks, and grow it to cover them unless `keep_size`.
+    /// Return false if they go past `MAX_FILE_SIZE`, or if the filesystem runs
+    /// out of blocks or current inode out of extents, in which case the blocks
+    /// mapped are kept, and covered by the size unless `keep_size`.
+    pub fn fallocate(&self, offset: u64, len: u64, keep_size: bool) -> bool {
+        let mut fs = self.fs.lock();
+        let end = match offset.checked_add(len) {
+            Some(end) if end <= MAX_FILE_SIZE => end,
+            _ => return false,
+        };
+        self.allocate(offset, end, !keep_size, &mut fs) == end
+    }
+    /// Deallocate bytes `offset..offset + len` of current inode, which then read
+    /// as zeros. The blocks partly in the range are zeroed instead, and the size
+    /// does not change. Return false if an extent split in two needs a block
+    /// and none is left, or current inode has all the extents it can,
+    /// nothing is changed then.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> bool {
+        let mut fs = self.fs.lock();
+        let end = offset.saturating_add(len).min(MAX_FILE_SIZE);
+        if offset >= end {
+            return true;
+        }
+        let block_sz = BLOCK_SZ as u64;
+        let (start_block, end_block) = (offset.div_ceil(block_sz) as u32, (end / block_sz) as u32);
+        // `FREE_STEP` mapped blocks per transaction, only the first one may split an extent
+        let mut block = start_block;
+        while block < end_block {
+            let _txn = Transaction::begin(&self.block_device);
+            let punched = self.modify_disk_inode(|disk_inode| {
+                let next =
+                    disk_inode.mapped_step_end(block, end_block, FREE_STEP, &self.block_device);
+                let mut alloc = |count| fs.alloc_data_run(count);
+                let punched = disk_inode.punch_hole(block, next, &mut alloc, &self.block_device);
+                let (data_blocks_dealloc, punched) = match punched {
+                    Ok(data_blocks_dealloc) => (data_blocks_dealloc, Some(next)),
+                    Err(data_blocks_dealloc) => (data_blocks_dealloc, None),
+                };
+                for data_block in data_blocks_dealloc.into_iter() {
+                    fs.dealloc_data(data_block);
+                }
+                punched
+            });
+            match punched {
+                Some(next) => block = next,
+                None => return false,
+            }
+        }
+        let _txn = Transaction::begin(&self.block_device);
+        self.modify_disk_inode(|disk_inode| {
+            let zero = |disk_inode: &mut DiskInode| {
+                let head_end = (start_block as u64 * block_sz).min(end);
+                let tail_start = (end_block as u64 * block_sz).max(offset);
+                disk_inode.zero_at(offset as usize, head_end as usize, &self.block_device);
+                disk_inode.zero_at(tail_start as usize, end as usize, &self.block_device);
+            };
+            if disk_inode.is_file() {
+                untracked(&self.block_device, || zero(disk_inode));
+            } else {
+                zero(disk_inode);
+            }
+            disk_inode.mtime = fs.now();
+            disk_inode.ctime = disk_inode.mtime;
+        });
+        true
+    }
+    /// Write the dirty blocks of the device holding current inode back to it.
+    /// Blocks are not tracked per inode, so this syncs the whole filesystem.
+    pub fn sync(&self) {
+        let _fs = self.fs.lock();
+        block_cache_sync_device(&self.block_device);
+    }
+    /// Set the size of current inode to `new_size`. The blocks past a smaller size
+    /// are freed, and a larger size leaves a hole that reads as zeros, as writing
+    /// past the end does. Return false if `new_size` is past `MAX_FILE_SIZE`
+    /// or current inode is a directory.
+    /// The blocks are freed after the size is set, see [`Inode::free_from`], so a crash
+    /// in between may leave some of them past the size, as [`Inode::fallocate`] does.
+    pub fn truncate(&self, new_size: u64) -> bool {
+        let mut fs = self.fs.lock();
+        if new_size > MAX_FILE_SIZE || self.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return false;
+        }
+        let txn = Transaction::begin(&self.block_device);
+        let shrink = self.modify_disk_inode(|disk_inode| {
+            let shrink = new_size < disk_inode.size;
+            if shrink {
+                disk_inode.cut_size(new_size, &self.block_device);
+            } else {
+                disk_inode.size = new_size;
+            }
+            disk_inode.mtime = fs.now();
+            disk_inode.ctime = disk_inode.mtime;
+            shrink
         });
-        block_cache_sync_all();
-        size
+        drop(txn);
+        if shrink {
+            self.free_from(new_size.div_ceil(BLOCK_SZ as u64) as u32, &mut fs);
+        }
+        true
     }
-    /// Clear the data in current inode
+    /// Clear the data in current inode, see [`Inode::truncate`]
     pub fn clear(&self) {
         let mut fs = self.fs.lock();
+        let txn = Transaction::begin(&self.block_device);
         self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
-            }
+            disk_inode.cut_size(0, &self.block_device);
+            disk_inode.mtime = fs.now();
+            disk_inode.ctime = disk_inode.mtime;
         });
-        block_cache_sync_all();
+        drop(txn);
+        self.free_from(0, &mut fs);
     }
 }
@@ -16,6 +16,12 @@ pub const MEMORY_END: usize = 0x88000000;
 
 /// the max number of syscall
 pub const MAX_SYSCALL_NUM: usize = 500;
+/// the max number of file descriptors a process can hold
+pub const MAX_FD_NUM: usize = 1024;
+/// the number of blocks held by the block cache of easy-fs
+pub const BLOCK_CACHE_BLOCKS: usize = 256;
+/// the interval in milliseconds of writing dirty blocks back to the disk
+pub const FS_SYNC_INTERVAL_MS: usize = 5000;
 
 // virtual memory space settings
 pub const MAXVA: usize = usize::MAX;
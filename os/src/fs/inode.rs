@@ -5,13 +5,17 @@
 //! `UPSafeCell<OSInodeInner>` -> `OSInode`: for static `ROOT_INODE`,we
 //! need to wrap `OSInodeInner` into `UPSafeCell`
 use super::File;
+use crate::config::{BLOCK_CACHE_BLOCKS, FS_SYNC_INTERVAL_MS};
 use crate::drivers::BLOCK_DEVICE;
 use crate::mm::UserBuffer;
 use crate::sync::UPSafeCell;
+use crate::syscall::Errno;
+use crate::timer::get_time_ms;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bitflags::*;
-use easy_fs::{EasyFileSystem, Inode};
+use easy_fs::{block_cache_sync_all, DiskInodeType, EasyFileSystem, Inode, Metadata};
 use lazy_static::*;
 
 /// inode in memory
@@ -20,6 +24,8 @@ use lazy_static::*;
 pub struct OSInode {
     readable: bool,
     writable: bool,
+    /// every write goes to the end of the file
+    append: bool,
     inner: UPSafeCell<OSInodeInner>,
 }
 /// The OS inode inner in 'UPSafeCell'
@@ -29,12 +35,14 @@ pub struct OSInodeInner {
 }
 
 impl OSInode {
-    /// create a new inode in memory
+    /// create a new inode in memory, which keeps the inode open until it is dropped
     pub fn new(readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
         trace!("kernel: OSInode::new");
+        inode.open();
         Self {
             readable,
             writable,
+            append: false,
             inner: unsafe { UPSafeCell::new(OSInodeInner { offset: 0, inode }) },
         }
     }
@@ -57,27 +65,190 @@ impl OSInode {
     /// get current node id
     pub fn get_inode_id(&self) -> u64 {
         let inner = self.inner.exclusive_access();
-        inner.inode.block_id as u64
+        inner.inode.inode_id() as u64
     }
-    /// get inode 'block_id' and 'block_offset'
-    pub fn get_inode_pos(&self) -> (usize, usize) {
+    /// get the filesystem inode
+    pub fn inode(&self) -> Arc<Inode> {
         let inner = self.inner.exclusive_access();
-        (inner.inode.block_id, inner.inode.block_offset)
+        Arc::clone(&inner.inode)
+    }
+    /// whether the inode is a directory
+    pub fn is_dir(&self) -> bool {
+        let inner = self.inner.exclusive_access();
+        inner.inode.is_dir()
+    }
+    /// write the dirty blocks of the filesystem holding the inode to the disk
+    pub fn sync(&self) {
+        let inner = self.inner.exclusive_access();
+        inner.inode.sync();
+    }
+    /// get the metadata of the inode
+    pub fn metadata(&self) -> Metadata {
+        let inner = self.inner.exclusive_access();
+        inner.inode.metadata()
+    }
+    /// get the number of hard links to the inode
+    pub fn link_count(&self) -> u32 {
+        let inner = self.inner.exclusive_access();
+        inner.inode.link_count()
+    }
+    /// get the file offset
+    pub fn offset(&self) -> usize {
+        self.inner.exclusive_access().offset
+    }
+    /// reposition the file offset, return the new offset
+    /// or `None` if `whence` is unknown or the result is negative
+    pub fn seek(&self, offset: isize, whence: usize) -> Option<usize> {
+        let mut inner = self.inner.exclusive_access();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => inner.offset as isize,
+            SEEK_END => inner.inode.size() as isize,
+            _ => return None,
+        };
+        let new_offset = base.checked_add(offset)?;
+        if new_offset < 0 {
+            return None;
+        }
+        inner.offset = new_offset as usize;
+        Some(inner.offset)
+    }
+    /// read dirents from the file offset as `linux_dirent64` records that
+    /// fit in `len` bytes, the offset is the byte offset of the next dirent
+    /// in the directory and is moved past them.
+    /// Return `None` if `len` is too small for the next record.
+    pub fn read_dirents(&self, len: usize) -> Option<Vec<u8>> {
+        let mut inner = self.inner.exclusive_access();
+        let mut records: Vec<u8> = Vec::new();
+        while let Some((name, inode_id, type_, next)) = inner.inode.read_dirent(inner.offset) {
+            // ino, off, reclen, type, name with '\0', aligned to 8 bytes
+            let reclen = (8 + 8 + 2 + 1 + name.len() + 1 + 7) & !7;
+            if records.len() + reclen > len {
+                if records.is_empty() {
+                    return None;
+                }
+                break;
+            }
+            let start = records.len();
+            inner.offset = next;
+            records.extend_from_slice(&(inode_id as u64).to_ne_bytes());
+            records.extend_from_slice(&(inner.offset as i64).to_ne_bytes());
+            records.extend_from_slice(&(reclen as u16).to_ne_bytes());
+            records.push(match type_ {
+                DiskInodeType::File => DT_REG,
+                DiskInodeType::Directory => DT_DIR,
+                DiskInodeType::Symlink => DT_LNK,
+            });
+            records.extend_from_slice(name.as_bytes());
+            records.resize(start + reclen, 0);
+        }
+        Some(records)
+    }
+    /// read from `offset` without touching the file offset
+    pub fn read_at(&self, offset: usize, mut buf: UserBuffer) -> usize {
+        let inner = self.inner.exclusive_access();
+        let mut total_read_size = 0usize;
+        for slice in buf.buffers.iter_mut() {
+            let read_size = inner.inode.read_at(offset + total_read_size, *slice);
+            total_read_size += read_size;
+            if read_size < slice.len() {
+                break;
+            }
+        }
+        total_read_size
+    }
+    /// write at `offset` without touching the file offset
+    pub fn write_at(&self, offset: usize, buf: UserBuffer) -> usize {
+        let inner = self.inner.exclusive_access();
+        let mut total_write_size = 0usize;
+        for slice in buf.buffers.iter() {
+            let write_size = inner.inode.write_at(offset + total_write_size, *slice);
+            total_write_size += write_size;
+            // a short write means the disk is full
+            if write_size < slice.len() {
+                break;
+            }
+        }
+        total_write_size
+    }
+}
+
+impl Drop for OSInode {
+    /// an unlinked file is freed when the last file opening it is dropped
+    fn drop(&mut self) {
+        self.inner.exclusive_access().inode.close();
     }
 }
 
+/// `whence` of lseek: the offset is set to `offset` bytes
+pub const SEEK_SET: usize = 0;
+/// `whence` of lseek: the offset is set to its current location plus `offset` bytes
+pub const SEEK_CUR: usize = 1;
+/// `whence` of lseek: the offset is set to the size of the file plus `offset` bytes
+pub const SEEK_END: usize = 2;
+
+/// `d_type` of a directory in records returned by getdents64
+pub const DT_DIR: u8 = 4;
+/// `d_type` of a regular file in records returned by getdents64
+pub const DT_REG: u8 = 8;
+/// `d_type` of a symbolic link in records returned by getdents64
+pub const DT_LNK: u8 = 10;
+
+/// Inode timestamps are seconds since boot as there is no RTC driver
+fn fs_time() -> u32 {
+    (get_time_ms() / 1000) as u32
+}
+
 lazy_static! {
     pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
+        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone(), BLOCK_CACHE_BLOCKS);
+        efs.lock().set_time_source(fs_time);
+        // the image may have been grown by `easy-fs-fuse resize` since it was last mounted
+        // the guard is dropped before the filesystem is locked again below
+        let resized = efs.lock().take_resized_from();
+        if let Some(old_blocks) = resized {
+            println!(
+                "[kernel] fs image resized offline from {} to {} blocks",
+                old_blocks,
+                efs.lock().total_blocks()
+            );
+        }
         Arc::new(EasyFileSystem::root_inode(&efs))
     };
+    /// the root directory kept open as the cwd of processes that have not changed it
+    pub static ref ROOT_DIR: Arc<OSInode> =
+        Arc::new(OSInode::new(false, false, ROOT_INODE.clone()));
+}
+
+lazy_static! {
+    /// time in milliseconds of the last periodic sync
+    static ref LAST_SYNC_MS: UPSafeCell<usize> = unsafe { UPSafeCell::new(0) };
+}
+
+/// Write all dirty blocks in the block cache back to the disk
+pub fn sync_all() {
+    block_cache_sync_all();
+}
+
+/// Write dirty blocks back once `FS_SYNC_INTERVAL_MS` has passed since the
+/// last time, called on timer interrupts
+pub fn periodic_sync() {
+    let now = get_time_ms();
+    let mut last_sync_ms = LAST_SYNC_MS.exclusive_access();
+    if now - *last_sync_ms >= FS_SYNC_INTERVAL_MS {
+        *last_sync_ms = now;
+        drop(last_sync_ms);
+        sync_all();
+    }
 }
 
 /// List all apps in the root directory
 pub fn list_apps() {
     println!("/**** APPS ****");
     for app in ROOT_INODE.ls() {
-        println!("{}", app);
+        if app != "." && app != ".." {
+            println!("{}", app);
+        }
     }
     println!("**************/");
 }
@@ -91,10 +262,16 @@ bitflags! {
         const WRONLY = 1 << 0;
         /// read and write
         const RDWR = 1 << 1;
-        /// create new file
+        /// with `CREATE`, fail if the file exists
+        const EXCL = 1 << 7;
+        /// create new file if it does not exist
         const CREATE = 1 << 9;
         /// truncate file size to 0
         const TRUNC = 1 << 10;
+        /// write to the end of file
+        const APPEND = 1 << 11;
+        /// close the file descriptor on exec
+        const CLOEXEC = 1 << 19;
     }
 }
 
@@ -102,9 +279,10 @@ impl OpenFlags {
     /// Do not check validity for simplicity
     /// Return (readable, writable)
     pub fn read_write(&self) -> (bool, bool) {
-        if self.is_empty() {
+        let flags = *self - Self::CLOEXEC - Self::APPEND - Self::EXCL;
+        if flags.is_empty() {
             (true, false)
-        } else if self.contains(Self::WRONLY) {
+        } else if flags.contains(Self::WRONLY) {
             (false, true)
         } else {
             (true, true)
@@ -112,29 +290,79 @@ impl OpenFlags {
     }
 }
 
-/// Open a file
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
-    trace!("kernel: open_file: name = {}, flags = {:?}", name, flags);
+/// Open a file by path, relative paths are looked up from the root directory
+pub fn open_file(path: &str, flags: OpenFlags) -> Result<Arc<OSInode>, Errno> {
+    open_file_at(&ROOT_INODE, path, flags)
+}
+
+/// Open a file by path, relative paths are looked up from `dir`
+pub fn open_file_at(dir: &Inode, path: &str, flags: OpenFlags) -> Result<Arc<OSInode>, Errno> {
+    trace!("kernel: open_file_at: path = {}, flags = {:?}", path, flags);
     let (readable, writable) = flags.read_write();
-    if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
-            // clear size
-            inode.clear();
-            Some(Arc::new(OSInode::new(readable, writable, inode)))
-        } else {
-            // create file
-            ROOT_INODE
-                .create(name)
-                .map(|inode| Arc::new(OSInode::new(readable, writable, inode)))
+    let inode = match dir.find_path(path) {
+        Some(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCL) => {
+            return Err(Errno::EEXIST);
         }
-    } else {
-        ROOT_INODE.find(name).map(|inode| {
-            if flags.contains(OpenFlags::TRUNC) {
+        Some(inode) => {
+            if inode.is_dir() {
+                if writable || flags.contains(OpenFlags::TRUNC) {
+                    return Err(Errno::EISDIR);
+                }
+            } else if flags.contains(OpenFlags::TRUNC) {
+                // clear size, an existing file is kept as is by `CREATE` alone
                 inode.clear();
             }
-            Arc::new(OSInode::new(readable, writable, inode))
-        })
+            inode
+        }
+        None if flags.contains(OpenFlags::CREATE) => {
+            // create file
+            let (parent, name) = lookup_parent(dir, path)?;
+            match parent.create(name) {
+                Some(inode) => inode,
+                // a dangling symbolic link holds the name
+                None if parent.find(name).is_some() => return Err(Errno::EEXIST),
+                None => return Err(Errno::ENOSPC),
+            }
+        }
+        None => return Err(Errno::ENOENT),
+    };
+    let mut os_inode = OSInode::new(readable, writable, inode);
+    os_inode.append = flags.contains(OpenFlags::APPEND);
+    Ok(Arc::new(os_inode))
+}
+
+/// Find the directory that holds the last component of `path` relative to `dir`,
+/// and check that the component can be a name in it
+pub fn lookup_parent<'a>(dir: &Inode, path: &'a str) -> Result<(Arc<Inode>, &'a str), Errno> {
+    let (parent, name) = dir.find_parent(path).ok_or(Errno::ENOENT)?;
+    if !parent.is_dir() {
+        return Err(Errno::ENOTDIR);
     }
+    if name.len() > parent.name_limit() {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    Ok((parent, name))
+}
+
+/// Get the absolute path of directory `dir` by walking up through ".."
+pub fn absolute_path(dir: &Inode) -> Result<String, Errno> {
+    let mut names: Vec<String> = Vec::new();
+    let mut inode = Arc::new(dir.clone());
+    while inode.inode_id() != 0 {
+        // a removed directory has no ".."
+        let parent = inode.find("..").ok_or(Errno::ENOENT)?;
+        names.push(parent.find_name(inode.inode_id()).ok_or(Errno::ENOENT)?);
+        inode = parent;
+    }
+    if names.is_empty() {
+        return Ok(String::from("/"));
+    }
+    let mut path = String::new();
+    for name in names.iter().rev() {
+        path.push('/');
+        path.push_str(name);
+    }
+    Ok(path)
 }
 
 impl File for OSInode {
@@ -161,12 +389,17 @@ impl File for OSInode {
     fn write(&self, buf: UserBuffer) -> usize {
         trace!("kernel: OSInode::write");
         let mut inner = self.inner.exclusive_access();
+        if self.append {
+            inner.offset = inner.inode.size();
+        }
         let mut total_write_size = 0usize;
         for slice in buf.buffers.iter() {
             let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
             inner.offset += write_size;
             total_write_size += write_size;
+            if write_size < slice.len() {
+                break;
+            }
         }
         total_write_size
     }
@@ -43,8 +43,50 @@ pub struct Stat {
     pub mode: StatMode,
     /// number of hard links
     pub nlink: u32,
+    /// user id of owner
+    pub uid: u32,
+    /// group id of owner
+    pub gid: u32,
+    /// total size in bytes
+    pub size: u64,
+    /// time of last access in seconds
+    pub atime: u64,
+    /// time of last modification in seconds
+    pub mtime: u64,
+    /// time of last status change in seconds
+    pub ctime: u64,
     /// unused pad
-    pub pad: [u64; 7],
+    pub pad: [u64; 2],
+}
+
+/// The usage of a filesystem, laid out as `struct statfs` of Linux
+#[repr(C)]
+#[derive(Debug)]
+pub struct Statfs {
+    /// type of filesystem
+    pub type_: u64,
+    /// size of a block in bytes
+    pub bsize: u64,
+    /// total data blocks
+    pub blocks: u64,
+    /// free data blocks
+    pub bfree: u64,
+    /// free data blocks for unprivileged users
+    pub bavail: u64,
+    /// total inodes
+    pub files: u64,
+    /// free inodes
+    pub ffree: u64,
+    /// filesystem id
+    pub fsid: [i32; 2],
+    /// maximum length of names
+    pub namelen: u64,
+    /// fragment size in bytes
+    pub frsize: u64,
+    /// mount flags
+    pub flags: u64,
+    /// unused pad
+    pub spare: [u64; 4],
 }
 
 bitflags! {
@@ -57,10 +99,39 @@ bitflags! {
         const DIR   = 0o040000;
         /// ordinary regular file
         const FILE  = 0o100000;
+        /// symbolic link
+        const LNK   = 0o120000;
+        /// set-user-ID
+        const SUID  = 0o4000;
+        /// set-group-ID
+        const SGID  = 0o2000;
+        /// sticky bit
+        const SVTX  = 0o1000;
+        /// owner has read permission
+        const RUSR  = 0o400;
+        /// owner has write permission
+        const WUSR  = 0o200;
+        /// owner has execute permission
+        const XUSR  = 0o100;
+        /// group has read permission
+        const RGRP  = 0o040;
+        /// group has write permission
+        const WGRP  = 0o020;
+        /// group has execute permission
+        const XGRP  = 0o010;
+        /// others have read permission
+        const ROTH  = 0o004;
+        /// others have write permission
+        const WOTH  = 0o002;
+        /// others have execute permission
+        const XOTH  = 0o001;
     }
 }
 
-pub use inode::{list_apps, open_file, OSInode, OpenFlags, ROOT_INODE};
+pub use inode::{
+    absolute_path, list_apps, lookup_parent, open_file, open_file_at, periodic_sync, sync_all,
+    OSInode, OpenFlags, DT_DIR, DT_REG, ROOT_DIR, ROOT_INODE, SEEK_CUR, SEEK_END, SEEK_SET,
+};
 pub use pipe::{make_pipe, Pipe};
 pub use stdio::{Stdin, Stdout};
 pub use mailbox::{Mail, MailBox, MailBoxInner, MailBoxStatus};
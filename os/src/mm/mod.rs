@@ -20,7 +20,8 @@ pub use frame_allocator::{frame_alloc, frame_dealloc, FrameTracker};
 pub use memory_set::remap_test;
 pub use memory_set::{MapPermission, MemorySet, KERNEL_SPACE, kernel_token};
 pub use page_table::{
-    translated_byte_buffer, translated_ref, translated_str, translated_refmut, 
+    copy_from_user, copy_obj_from_user, copy_obj_to_user, copy_to_user, translated_user_buffer,
+    translated_user_str, translated_byte_buffer, translated_ref, translated_str, translated_refmut, 
     PageTableEntry, PTEFlags, PageTable, UserBuffer, UserBufferIterator
 };
 
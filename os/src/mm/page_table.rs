@@ -5,6 +5,9 @@ use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 use super::{frame_alloc, FrameTracker, PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use crate::config::PAGE_SIZE;
+use crate::syscall::Errno;
+use core::mem::{size_of, MaybeUninit};
 
 bitflags! {
     pub struct PTEFlags: u8 {
@@ -215,6 +218,95 @@ pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> &'static mut T {
         .get_mut()
 }
 
+/// Translate the user buffer `[ptr, ptr + len)` through page table, checking that
+/// every page it covers is valid, accessible from U-mode and has `flags` (R and/or W).
+/// Buffers crossing page boundaries are split into one slice per page.
+pub fn translated_user_buffer(
+    token: usize,
+    ptr: *const u8,
+    len: usize,
+    flags: PTEFlags,
+) -> Result<Vec<&'static mut [u8]>, Errno> {
+    let page_table = PageTable::from_token(token);
+    let mut start = ptr as usize;
+    let end = start.checked_add(len).ok_or(Errno::EFAULT)?;
+    // reject non-canonical addresses, they would alias other pages after truncation
+    if usize::from(VirtAddr::from(start)) != start
+        || (len > 0 && usize::from(VirtAddr::from(end - 1)) != end - 1)
+    {
+        return Err(Errno::EFAULT);
+    }
+    let mut v = Vec::new();
+    while start < end {
+        let start_va = VirtAddr::from(start);
+        let pte = page_table.translate(start_va.floor()).ok_or(Errno::EFAULT)?;
+        if !pte.is_valid() || !pte.flags().contains(flags | PTEFlags::U) {
+            return Err(Errno::EFAULT);
+        }
+        let offset = start_va.page_offset();
+        // stop at the end of current page
+        let end_current = end.min(start.saturating_add(PAGE_SIZE - offset));
+        v.push(&mut pte.ppn().get_bytes_array()[offset..offset + (end_current - start)]);
+        start = end_current;
+    }
+    Ok(v)
+}
+
+/// Copy `dst.len()` bytes from user space `src` into kernel buffer `dst`
+pub fn copy_from_user(token: usize, src: *const u8, dst: &mut [u8]) -> Result<(), Errno> {
+    let mut copied = 0usize;
+    for slice in translated_user_buffer(token, src, dst.len(), PTEFlags::R)? {
+        dst[copied..copied + slice.len()].copy_from_slice(slice);
+        copied += slice.len();
+    }
+    Ok(())
+}
+
+/// Copy kernel buffer `src` to user space `dst`
+pub fn copy_to_user(token: usize, dst: *mut u8, src: &[u8]) -> Result<(), Errno> {
+    let mut copied = 0usize;
+    for slice in translated_user_buffer(token, dst, src.len(), PTEFlags::W)? {
+        let len = slice.len();
+        slice.copy_from_slice(&src[copied..copied + len]);
+        copied += len;
+    }
+    Ok(())
+}
+
+/// Read a value of `T` from user space
+pub fn copy_obj_from_user<T: Copy>(token: usize, src: *const T) -> Result<T, Errno> {
+    let mut obj = MaybeUninit::<T>::uninit();
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(obj.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_user(token, src as *const u8, bytes)?;
+    Ok(unsafe { obj.assume_init() })
+}
+
+/// Write `obj` to user space
+pub fn copy_obj_to_user<T>(token: usize, dst: *mut T, obj: &T) -> Result<(), Errno> {
+    let bytes = unsafe { core::slice::from_raw_parts(obj as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(token, dst as *mut u8, bytes)
+}
+
+/// Copy a `\0`-terminated string from user space, checking every page it touches
+pub fn translated_user_str(token: usize, ptr: *const u8) -> Result<String, Errno> {
+    let mut string = String::new();
+    let mut va = ptr as usize;
+    loop {
+        // read the rest of the current page at most
+        let page_left = PAGE_SIZE - VirtAddr::from(va).page_offset();
+        let slice = translated_user_buffer(token, va as *const u8, page_left, PTEFlags::R)?
+            .pop()
+            .unwrap();
+        if let Some(len) = slice.iter().position(|ch| *ch == 0) {
+            string.extend(slice[..len].iter().map(|ch| *ch as char));
+            return Ok(string);
+        }
+        string.extend(slice.iter().map(|ch| *ch as char));
+        va = va.checked_add(page_left).ok_or(Errno::EFAULT)?;
+    }
+}
+
 /// An abstraction over a buffer passed from user space to kernel space
 pub struct UserBuffer {
     /// A list of buffers
@@ -0,0 +1,83 @@
+//! POSIX error numbers returned by syscalls
+//!
+//! Every `sys_` function returns a [`SyscallResult`]. The dispatcher in
+//! [`super::syscall()`] turns `Err(errno)` into `-errno` for user space.
+
+/// Error numbers, with the same values as Linux
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused, clippy::upper_case_acronyms)]
+pub enum Errno {
+    /// Operation not permitted
+    EPERM = 1,
+    /// No such file or directory
+    ENOENT = 2,
+    /// No such process
+    ESRCH = 3,
+    /// I/O error
+    EIO = 5,
+    /// Argument list too long
+    E2BIG = 7,
+    /// Exec format error
+    ENOEXEC = 8,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// No child processes
+    ECHILD = 10,
+    /// Try again
+    EAGAIN = 11,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Permission denied
+    EACCES = 13,
+    /// Bad address
+    EFAULT = 14,
+    /// Device or resource busy
+    EBUSY = 16,
+    /// File exists
+    EEXIST = 17,
+    /// Cross-device link
+    EXDEV = 18,
+    /// Not a directory
+    ENOTDIR = 20,
+    /// Is a directory
+    EISDIR = 21,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Too many open files
+    EMFILE = 24,
+    /// File too large
+    EFBIG = 27,
+    /// No space left on device
+    ENOSPC = 28,
+    /// Illegal seek
+    ESPIPE = 29,
+    /// Too many links
+    EMLINK = 31,
+    /// Broken pipe
+    EPIPE = 32,
+    /// Math result not representable
+    ERANGE = 34,
+    /// Resource deadlock would occur
+    EDEADLK = 35,
+    /// File name too long
+    ENAMETOOLONG = 36,
+    /// Function not implemented
+    ENOSYS = 38,
+    /// Directory not empty
+    ENOTEMPTY = 39,
+    /// Too many symbolic links encountered
+    ELOOP = 40,
+    /// Operation not supported
+    EOPNOTSUPP = 95,
+}
+
+/// Result of a syscall, `Ok` holds the non-negative return value
+pub type SyscallResult = Result<usize, Errno>;
+
+impl Errno {
+    /// The value written back to `a0`
+    pub fn as_ret(self) -> isize {
+        -(self as isize)
+    }
+}
@@ -1,209 +1,771 @@
 //! File and filesystem-related syscalls
 
-use crate::mm::{translated_byte_buffer, translated_refmut, translated_str, UserBuffer};
+use super::errno::{Errno, SyscallResult};
+use crate::mm::{copy_obj_to_user, copy_to_user, translated_user_buffer, translated_user_str, PTEFlags, UserBuffer};
 use crate::task::{current_process, current_task, current_user_token};
 #[allow(unused)]
-use crate::fs::{make_pipe, open_file, OpenFlags, Stat, ROOT_INODE, OSInode, StatMode, MailBoxStatus};
+use crate::fs::{absolute_path, make_pipe, lookup_parent, open_file_at, sync_all, File, OpenFlags, Stat, Statfs, OSInode, StatMode, MailBoxStatus};
 #[allow(unused)]
 use crate::config::{MAX_MAIL_LENGTH, MAX_MESSAGE_NUM};
+use crate::config::MAX_FD_NUM;
 use core::any::Any;
 use alloc::sync::Arc;
+use easy_fs::{DiskInodeType, Inode};
 
-pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
+/// Special `dirfd` that makes `*at` syscalls look up relative paths from the cwd
+pub const AT_FDCWD: usize = -100isize as usize;
+
+/// Get the file opened at `fd` of current process
+fn get_file(fd: usize) -> Result<Arc<dyn File + Send + Sync>, Errno> {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    match inner.fd_table.get(fd) {
+        Some(Some(file)) => Ok(Arc::clone(file)),
+        _ => Err(Errno::EBADF),
+    }
+}
+
+/// Get the directory that relative paths are looked up from,
+/// that is the cwd for `AT_FDCWD`, or else the directory opened at `dirfd`
+fn get_dir(dirfd: usize) -> Result<Arc<Inode>, Errno> {
+    if dirfd == AT_FDCWD {
+        let process = current_process();
+        let inner = process.inner_exclusive_access();
+        return Ok(inner.cwd.inode());
+    }
+    let file = get_file(dirfd)?;
+    let os_inode = file
+        .as_any()
+        .downcast_ref::<OSInode>()
+        .ok_or(Errno::ENOTDIR)?;
+    if !os_inode.is_dir() {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok(os_inode.inode())
+}
+
+pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] sys_write",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
     let token = current_user_token();
-    let process = current_process();
-    let inner = process.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
+    let file = get_file(fd)?;
+    if !file.writable() {
+        return Err(Errno::EBADF);
     }
-    if let Some(file) = &inner.fd_table[fd] {
-        if !file.writable() {
-            return -1;
-        }
-        let file = file.clone();
-        // release current task TCB manually to avoid multi-borrow
-        drop(inner);
-        file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
-    } else {
-        -1
+    let buffers = translated_user_buffer(token, buf, len, PTEFlags::R)?;
+    match file.write(UserBuffer::new(buffers)) {
+        0 if len > 0 => match file.as_any().downcast_ref::<OSInode>() {
+            Some(os_inode) if os_inode.offset() as u64 >= easy_fs::MAX_FILE_SIZE => {
+                Err(Errno::EFBIG)
+            }
+            // nothing is written to a regular file only when the disk is full,
+            // or the file has all the extents it can
+            Some(_) => Err(Errno::ENOSPC),
+            None => Ok(0),
+        },
+        written => Ok(written),
     }
 }
 
-pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
+pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] sys_read",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
     let token = current_user_token();
-    let process = current_process();
-    let inner = process.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if let Some(file) = &inner.fd_table[fd] {
-        let file = file.clone();
-        if !file.readable() {
-            return -1;
-        }
-        // release current task TCB manually to avoid multi-borrow
-        drop(inner);
-        trace!("kernel: sys_read .. file.read");
-        file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
-    } else {
-        -1
+    let file = get_file(fd)?;
+    if !file.readable() {
+        return Err(Errno::EBADF);
     }
+    trace!("kernel: sys_read .. file.read");
+    let buffers = translated_user_buffer(token, buf, len, PTEFlags::W)?;
+    Ok(file.read(UserBuffer::new(buffers)))
 }
 
-pub fn sys_open(path: *const u8, flags: u32) -> isize {
+pub fn sys_open(dirfd: usize, path: *const u8, flags: u32) -> SyscallResult {
     trace!(
         "kernel:pid[{}] sys_open",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
     let process = current_process();
     let token = current_user_token();
-    let path = translated_str(token, path);
-    if let Some(inode) = open_file(path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
-        let mut inner = process.inner_exclusive_access();
-        let fd = inner.alloc_fd();
-        inner.fd_table[fd] = Some(inode);
-        fd as isize
-    } else {
-        -1
+    let path = translated_user_str(token, path)?;
+    let flags = OpenFlags::from_bits(flags).ok_or(Errno::EINVAL)?;
+    let dir = get_dir(dirfd)?;
+    // checked before opening, which may create or truncate the file
+    if process.inner_exclusive_access().lowest_free_fd() >= MAX_FD_NUM {
+        return Err(Errno::EMFILE);
     }
+    let inode = open_file_at(&dir, path.as_str(), flags)?;
+    let mut inner = process.inner_exclusive_access();
+    let fd = inner.lowest_free_fd();
+    if fd >= MAX_FD_NUM {
+        return Err(Errno::EMFILE);
+    }
+    inner.reserve_fd(fd);
+    inner.fd_table[fd] = Some(inode);
+    inner.fd_cloexec[fd] = flags.contains(OpenFlags::CLOEXEC);
+    Ok(fd)
 }
 
-pub fn sys_close(fd: usize) -> isize {
+pub fn sys_close(fd: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] sys_close",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
     let process = current_process();
     let mut inner = process.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if inner.fd_table[fd].is_none() {
-        return -1;
+    if fd >= inner.fd_table.len() || inner.fd_table[fd].is_none() {
+        return Err(Errno::EBADF);
     }
-    inner.fd_table[fd].take();
-    0
+    inner.close_fd(fd);
+    Ok(0)
 }
 
-pub fn sys_pipe(pipe: *mut usize) -> isize {
+/// `flags` may contain `OpenFlags::CLOEXEC`, which is applied to both ends
+pub fn sys_pipe(pipe: *mut usize, flags: u32) -> SyscallResult {
     trace!(
         "kernel:pid[{}] sys_pipe",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
+    let flags = match OpenFlags::from_bits(flags) {
+        Some(flags) if (flags - OpenFlags::CLOEXEC).is_empty() => flags,
+        _ => return Err(Errno::EINVAL),
+    };
+    let cloexec = flags.contains(OpenFlags::CLOEXEC);
     let process = current_process();
     let token = current_user_token();
     let mut inner = process.inner_exclusive_access();
     let (pipe_read, pipe_write) = make_pipe();
     let read_fd = inner.alloc_fd();
     inner.fd_table[read_fd] = Some(pipe_read);
+    inner.fd_cloexec[read_fd] = cloexec;
     let write_fd = inner.alloc_fd();
     inner.fd_table[write_fd] = Some(pipe_write);
-    *translated_refmut(token, pipe) = read_fd;
-    *translated_refmut(token, unsafe { pipe.add(1) }) = write_fd;
-    0
+    inner.fd_cloexec[write_fd] = cloexec;
+    if let Err(errno) = copy_obj_to_user(token, pipe as *mut [usize; 2], &[read_fd, write_fd]) {
+        inner.close_fd(read_fd);
+        inner.close_fd(write_fd);
+        return Err(errno);
+    }
+    Ok(0)
 }
 
-pub fn sys_dup(fd: usize) -> isize {
+pub fn sys_dup(fd: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] sys_dup",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
+    let file = get_file(fd)?;
     let process = current_process();
     let mut inner = process.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
+    let new_fd = inner.lowest_free_fd();
+    if new_fd >= MAX_FD_NUM {
+        return Err(Errno::EMFILE);
     }
-    if inner.fd_table[fd].is_none() {
-        return -1;
+    inner.reserve_fd(new_fd);
+    inner.fd_table[new_fd] = Some(file);
+    Ok(new_fd)
+}
+
+/// Duplicate `old_fd` onto `new_fd`, closing whatever `new_fd` referred to.
+/// Duplicating a valid fd onto itself does nothing and returns it.
+pub fn sys_dup2(old_fd: usize, new_fd: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_dup2",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    if old_fd == new_fd {
+        get_file(old_fd)?;
+        return Ok(new_fd);
     }
-    let new_fd = inner.alloc_fd();
-    inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
-    new_fd as isize
+    dup_to(old_fd, new_fd, false)
 }
 
-/// YOUR JOB: Implement fstat.
-pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
+/// Same as `sys_dup2`, but `old_fd` must differ from `new_fd` and
+/// `flags` may contain `OpenFlags::CLOEXEC` for the new descriptor.
+pub fn sys_dup3(old_fd: usize, new_fd: usize, flags: u32) -> SyscallResult {
     trace!(
-        "kernel:pid[{}] sys_fstat NOT IMPLEMENTED",
+        "kernel:pid[{}] sys_dup3",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
+    let flags = match OpenFlags::from_bits(flags) {
+        Some(flags) if (flags - OpenFlags::CLOEXEC).is_empty() => flags,
+        _ => return Err(Errno::EINVAL),
+    };
+    if old_fd == new_fd {
+        return Err(Errno::EINVAL);
+    }
+    dup_to(old_fd, new_fd, flags.contains(OpenFlags::CLOEXEC))
+}
+
+/// Make `new_fd` refer to the same file as `old_fd`
+fn dup_to(old_fd: usize, new_fd: usize, cloexec: bool) -> SyscallResult {
+    let file = get_file(old_fd)?;
+    if new_fd >= MAX_FD_NUM {
+        return Err(Errno::EBADF);
+    }
     let process = current_process();
-    let inner = process.inner_exclusive_access();
+    let mut inner = process.inner_exclusive_access();
+    inner.reserve_fd(new_fd);
+    // the replaced file is released after the PCB borrow ends
+    let replaced = inner.close_fd(new_fd);
+    inner.fd_table[new_fd] = Some(file);
+    inner.fd_cloexec[new_fd] = cloexec;
+    drop(inner);
+    drop(replaced);
+    Ok(new_fd)
+}
 
-    // check legality
-    if fd >= inner.fd_table.len() {
-        return -1;
+/// Reposition the offset of an opened regular file
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_lseek",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let file = get_file(fd)?;
+    // pipes and stdio are not seekable
+    let os_inode = file
+        .as_any()
+        .downcast_ref::<OSInode>()
+        .ok_or(Errno::ESPIPE)?;
+    os_inode.seek(offset, whence).ok_or(Errno::EINVAL)
+}
+
+/// Read dirents of the directory opened at `fd` into `buf` as `linux_dirent64`
+/// records, return the number of bytes read or 0 at the end of the directory
+pub fn sys_getdents64(fd: usize, buf: *mut u8, len: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_getdents64",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let file = get_file(fd)?;
+    let os_inode = file
+        .as_any()
+        .downcast_ref::<OSInode>()
+        .ok_or(Errno::ENOTDIR)?;
+    if !os_inode.is_dir() {
+        return Err(Errno::ENOTDIR);
     }
-    if inner.fd_table[fd].is_none() {
-        return -1;
+    // check the buffer before moving the offset
+    translated_user_buffer(current_user_token(), buf, len, PTEFlags::W)?;
+    let records = os_inode.read_dirents(len).ok_or(Errno::EINVAL)?;
+    copy_to_user(current_user_token(), buf, records.as_slice())?;
+    Ok(records.len())
+}
+
+/// Read from `offset` of an opened regular file, the file offset is unchanged
+pub fn sys_pread(fd: usize, buf: *const u8, len: usize, offset: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_pread",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let file = get_file(fd)?;
+    if !file.readable() {
+        return Err(Errno::EBADF);
     }
+    let os_inode = file
+        .as_any()
+        .downcast_ref::<OSInode>()
+        .ok_or(Errno::ESPIPE)?;
+    let buffers = translated_user_buffer(token, buf, len, PTEFlags::W)?;
+    Ok(os_inode.read_at(offset, UserBuffer::new(buffers)))
+}
 
-    let ino: u64;
-    let nlink: u32;
-    if let Some(file_node) = &inner.fd_table[fd] {
-        let any: &dyn Any = file_node.as_any();
-        let os_node = any.downcast_ref::<OSInode>().unwrap();
-        ino = os_node.get_inode_id();
-        let (block_id, block_offset) = os_node.get_inode_pos();
-        nlink = ROOT_INODE.get_link_num(block_id, block_offset);
-    } else {
-        return -1;
+/// Write at `offset` of an opened regular file, the file offset is unchanged
+pub fn sys_pwrite(fd: usize, buf: *const u8, len: usize, offset: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_pwrite",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let file = get_file(fd)?;
+    if !file.writable() {
+        return Err(Errno::EBADF);
     }
+    let os_inode = file
+        .as_any()
+        .downcast_ref::<OSInode>()
+        .ok_or(Errno::ESPIPE)?;
+    let buffers = translated_user_buffer(token, buf, len, PTEFlags::R)?;
+    match os_inode.write_at(offset, UserBuffer::new(buffers)) {
+        0 if len > 0 && offset as u64 >= easy_fs::MAX_FILE_SIZE => Err(Errno::EFBIG),
+        0 if len > 0 => Err(Errno::ENOSPC),
+        written => Ok(written),
+    }
+}
 
-    let stat = &Stat {
-        dev: 0,
-        ino: ino,
-        mode: StatMode::FILE,
-        nlink: nlink,
-        pad: [0;7],
+/// Set the size of the regular file at `path` to `len` bytes, freeing the
+/// blocks past a smaller size or leaving a hole up to a larger one
+pub fn sys_truncate(path: *const u8, len: isize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_truncate",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let path = translated_user_str(token, path)?;
+    if len < 0 {
+        return Err(Errno::EINVAL);
+    }
+    let dir = get_dir(AT_FDCWD)?;
+    let inode = dir.find_path(path.as_str()).ok_or(Errno::ENOENT)?;
+    if inode.is_dir() {
+        return Err(Errno::EISDIR);
+    }
+    if !inode.truncate(len as u64) {
+        return Err(Errno::EFBIG);
+    }
+    Ok(0)
+}
+
+/// Set the size of the regular file opened for writing at `fd` to `len` bytes,
+/// the file offset is not changed
+pub fn sys_ftruncate(fd: usize, len: isize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_ftruncate",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    if len < 0 {
+        return Err(Errno::EINVAL);
+    }
+    let file = get_file(fd)?;
+    let os_inode = file
+        .as_any()
+        .downcast_ref::<OSInode>()
+        .ok_or(Errno::EINVAL)?;
+    if !file.writable() || os_inode.is_dir() {
+        return Err(Errno::EINVAL);
+    }
+    if !os_inode.inode().truncate(len as u64) {
+        return Err(Errno::EFBIG);
+    }
+    Ok(0)
+}
+
+/// Allocate the range but keep the file size in `sys_fallocate`
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+/// Deallocate the range instead in `sys_fallocate`, must be used with `FALLOC_FL_KEEP_SIZE`
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+
+/// Allocate the blocks of `len` bytes from `offset` of an opened regular file,
+/// or punch a hole there with `FALLOC_FL_PUNCH_HOLE`
+pub fn sys_fallocate(fd: usize, mode: u32, offset: usize, len: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_fallocate",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let (offset, len) = (offset as isize, len as isize);
+    if offset < 0 || len <= 0 {
+        return Err(Errno::EINVAL);
+    }
+    let keep_size = mode & FALLOC_FL_KEEP_SIZE != 0;
+    let punch_hole = mode & FALLOC_FL_PUNCH_HOLE != 0;
+    if mode & !(FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE) != 0 || (punch_hole && !keep_size) {
+        return Err(Errno::EOPNOTSUPP);
+    }
+    let file = get_file(fd)?;
+    if !file.writable() {
+        return Err(Errno::EBADF);
+    }
+    let os_inode = file
+        .as_any()
+        .downcast_ref::<OSInode>()
+        .ok_or(Errno::ESPIPE)?;
+    if os_inode.is_dir() {
+        return Err(Errno::EISDIR);
+    }
+    let (offset, len) = (offset as u64, len as u64);
+    if !offset
+        .checked_add(len)
+        .is_some_and(|end| end <= easy_fs::MAX_FILE_SIZE)
+    {
+        return Err(Errno::EFBIG);
+    }
+    let done = match punch_hole {
+        true => os_inode.inode().punch_hole(offset, len),
+        false => os_inode.inode().fallocate(offset, len, keep_size),
     };
+    match done {
+        true => Ok(0),
+        false => Err(Errno::ENOSPC),
+    }
+}
 
+/// YOUR JOB: Implement fstat.
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_fstat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let file = get_file(fd)?;
+    let any: &dyn Any = file.as_any();
+    let os_node = any.downcast_ref::<OSInode>().ok_or(Errno::EINVAL)?;
+    let meta = os_node.metadata();
+    let nlink = os_node.link_count();
+    let type_mode = match meta.type_ {
+        DiskInodeType::File => StatMode::FILE,
+        DiskInodeType::Directory => StatMode::DIR,
+        DiskInodeType::Symlink => StatMode::LNK,
+    };
+    let mode = type_mode | StatMode::from_bits_truncate(meta.mode as u32);
+
+    let stat = Stat {
+        dev: 0,
+        ino: meta.inode_id as u64,
+        mode,
+        nlink,
+        uid: meta.uid,
+        gid: meta.gid,
+        size: meta.size as u64,
+        atime: meta.atime as u64,
+        mtime: meta.mtime as u64,
+        ctime: meta.ctime as u64,
+        pad: [0; 2],
+    };
     // copy data from kernel space to user space
-    let token = inner.get_user_token();
-    let st = translated_byte_buffer(token, st as *const u8, core::mem::size_of::<Stat>());
-    let stat_ptr = stat as *const _ as *const u8;
-    for (idx, byte) in st.into_iter().enumerate() {
-        unsafe {
-            byte.copy_from_slice(core::slice::from_raw_parts(stat_ptr.wrapping_byte_add(idx), byte.len()));
-        }
+    copy_obj_to_user(current_user_token(), st, &stat)?;
+    Ok(0)
+}
+
+/// Fill a `Statfs` with the usage of the filesystem holding `inode`
+fn inode_statfs(inode: &Inode) -> Statfs {
+    let statfs = inode.statfs();
+    Statfs {
+        type_: easy_fs::EFS_MAGIC as u64,
+        bsize: statfs.block_size as u64,
+        blocks: statfs.total_blocks as u64,
+        bfree: statfs.free_blocks as u64,
+        bavail: statfs.free_blocks as u64,
+        files: statfs.total_inodes as u64,
+        ffree: statfs.free_inodes as u64,
+        fsid: [0; 2],
+        namelen: inode.name_limit() as u64,
+        frsize: statfs.block_size as u64,
+        flags: 0,
+        spare: [0; 4],
     }
-    0
+}
+
+/// Get the usage of the filesystem holding the file at `path`
+pub fn sys_statfs(path: *const u8, buf: *mut Statfs) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_statfs",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let path = translated_user_str(token, path)?;
+    let dir = get_dir(AT_FDCWD)?;
+    let inode = dir.find_path(path.as_str()).ok_or(Errno::ENOENT)?;
+    copy_obj_to_user(token, buf, &inode_statfs(&inode))?;
+    Ok(0)
+}
+
+/// Get the usage of the filesystem holding the file opened at `fd`
+pub fn sys_fstatfs(fd: usize, buf: *mut Statfs) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_fstatfs",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let file = get_file(fd)?;
+    let os_inode = file
+        .as_any()
+        .downcast_ref::<OSInode>()
+        .ok_or(Errno::EINVAL)?;
+    copy_obj_to_user(current_user_token(), buf, &inode_statfs(&os_inode.inode()))?;
+    Ok(0)
+}
+
+/// Write all dirty blocks back to the disk
+pub fn sys_sync() -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_sync",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    sync_all();
+    Ok(0)
+}
+
+/// Write the data and metadata of the file opened at `fd` back to the disk
+pub fn sys_fsync(fd: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_fsync",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let file = get_file(fd)?;
+    let any: &dyn Any = file.as_any();
+    let os_node = any.downcast_ref::<OSInode>().ok_or(Errno::EINVAL)?;
+    os_node.sync();
+    Ok(0)
+}
+
+/// Write the data of the file opened at `fd` back to the disk.
+/// Its metadata shares blocks with other inodes, so this is the same as fsync.
+pub fn sys_fdatasync(fd: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_fdatasync",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    sys_fsync(fd)
 }
 
 /// YOUR JOB: Implement linkat.
-pub fn sys_linkat(old_name: *const u8, new_name: *const u8) -> isize {
+pub fn sys_linkat(
+    old_dirfd: usize,
+    old_name: *const u8,
+    new_dirfd: usize,
+    new_name: *const u8,
+) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_linkat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
     let token = current_user_token();
-    let old = translated_str(token, old_name);
-    let new = translated_str(token, new_name);
+    let old = translated_user_str(token, old_name)?;
+    let new = translated_user_str(token, new_name)?;
     println!("link {} to {}", new , old);
-    if old.as_str() != new.as_str() {
-        if let Some(_) = ROOT_INODE.link(old.as_str(), new.as_str()) {
-            return 0;
-        }
+    let old_dir = get_dir(old_dirfd)?;
+    let new_dir = get_dir(new_dirfd)?;
+    let inode = old_dir.find_path(old.as_str()).ok_or(Errno::ENOENT)?;
+    // hard links to directories are not allowed
+    if inode.is_dir() {
+        return Err(Errno::EPERM);
     }
-    -1
+    if new_dir.find_path(new.as_str()).is_some() {
+        return Err(Errno::EEXIST);
+    }
+    let (parent, name) = lookup_parent(&new_dir, new.as_str())?;
+    parent
+        .link_inode(name, &inode)
+        .map(|_| 0)
+        .ok_or(Errno::ENOSPC)
 }
 
+/// Remove a directory instead of a file in `sys_unlinkat`
+pub const AT_REMOVEDIR: u32 = 0x200;
+
 /// YOUR JOB: Implement unlinkat.
-pub fn sys_unlinkat(name: *const u8) -> isize {
+pub fn sys_unlinkat(dirfd: usize, name: *const u8, flags: u32) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_unlinkat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    if flags & !AT_REMOVEDIR != 0 {
+        return Err(Errno::EINVAL);
+    }
     let token = current_user_token();
-    let name = translated_str(token, name);
-    if let Some(inode) = ROOT_INODE.find(name.as_str()) {
-        if ROOT_INODE.get_link_num(inode.block_id, inode.block_offset) == 1 {
-            // clear data if only one link exists
-            inode.clear();
+    let path = translated_user_str(token, name)?;
+    let dir = get_dir(dirfd)?;
+    let (parent, name) = lookup_parent(&dir, path.as_str())?;
+    match name {
+        "." => return Err(Errno::EINVAL),
+        ".." => return Err(Errno::ENOTEMPTY),
+        _ => {}
+    }
+    let inode = parent.find(name).ok_or(Errno::ENOENT)?;
+    if flags & AT_REMOVEDIR != 0 {
+        if !inode.is_dir() {
+            return Err(Errno::ENOTDIR);
         }
-        return ROOT_INODE.unlink(name.as_str());
+        // only "." and ".." are left in an empty directory
+        if inode.ls().len() > 2 {
+            return Err(Errno::ENOTEMPTY);
+        }
+        return match parent.rmdir(name) {
+            0 => Ok(0),
+            _ => Err(Errno::ENOENT),
+        };
+    }
+    if inode.is_dir() {
+        return Err(Errno::EISDIR);
+    }
+    // the file is freed once it is no longer open
+    match parent.unlink(name) {
+        0 => Ok(0),
+        _ => Err(Errno::ENOENT),
+    }
+}
+
+/// Rename `old_name` relative to `old_dirfd` to `new_name` relative to `new_dirfd`,
+/// an existing `new_name` is replaced
+pub fn sys_renameat(
+    old_dirfd: usize,
+    old_name: *const u8,
+    new_dirfd: usize,
+    new_name: *const u8,
+) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_renameat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let old = translated_user_str(token, old_name)?;
+    let new = translated_user_str(token, new_name)?;
+    let old_dir = get_dir(old_dirfd)?;
+    let new_dir = get_dir(new_dirfd)?;
+    let (old_parent, old_name) = lookup_parent(&old_dir, old.as_str())?;
+    let (new_parent, new_name) = lookup_parent(&new_dir, new.as_str())?;
+    for name in [old_name, new_name] {
+        if name == "." || name == ".." {
+            return Err(Errno::EINVAL);
+        }
+    }
+    let inode = old_parent.find(old_name).ok_or(Errno::ENOENT)?;
+    if let Some(target) = new_parent.find(new_name) {
+        if target.inode_id() == inode.inode_id() {
+            return Ok(0);
+        }
+        match (inode.is_dir(), target.is_dir()) {
+            (true, false) => return Err(Errno::ENOTDIR),
+            (false, true) => return Err(Errno::EISDIR),
+            // only "." and ".." are left in an empty directory
+            (true, true) if target.ls().len() > 2 => return Err(Errno::ENOTEMPTY),
+            _ => {}
+        }
+    }
+    if old_parent.statfs().free_blocks < easy_fs::DIR_OP_BLOCKS {
+        return Err(Errno::ENOSPC);
+    }
+    // what is left is moving a directory into itself
+    match old_parent.rename(old_name, &new_parent, new_name) {
+        0 => Ok(0),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// Create a directory, `mode` is ignored as easy-fs has no permissions
+pub fn sys_mkdirat(dirfd: usize, path: *const u8, _mode: u32) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_mkdirat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let path = translated_user_str(token, path)?;
+    let dir = get_dir(dirfd)?;
+    if dir.find_path(path.as_str()).is_some() {
+        return Err(Errno::EEXIST);
+    }
+    let (parent, name) = lookup_parent(&dir, path.as_str())?;
+    parent.mkdir(name).map(|_| 0).ok_or(Errno::ENOSPC)
+}
+
+/// Create a symbolic link at `path` relative to `dirfd` pointing to `target`
+pub fn sys_symlinkat(target: *const u8, dirfd: usize, path: *const u8) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_symlinkat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let target = translated_user_str(token, target)?;
+    let path = translated_user_str(token, path)?;
+    if target.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    let dir = get_dir(dirfd)?;
+    if dir.find_path(path.as_str()).is_some() {
+        return Err(Errno::EEXIST);
+    }
+    let (parent, name) = lookup_parent(&dir, path.as_str())?;
+    parent.symlink(name, target.as_str()).ok_or(Errno::ENOSPC)?;
+    Ok(0)
+}
+
+/// Copy the target of the symbolic link at `path` relative to `dirfd` into `buf`
+/// without a trailing '\0', return the number of bytes copied
+pub fn sys_readlinkat(dirfd: usize, path: *const u8, buf: *mut u8, size: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_readlinkat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let path = translated_user_str(token, path)?;
+    if size == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let dir = get_dir(dirfd)?;
+    let (parent, name) = lookup_parent(&dir, path.as_str())?;
+    let inode = parent.find(name).ok_or(Errno::ENOENT)?;
+    let target = inode.readlink().ok_or(Errno::EINVAL)?;
+    let len = target.len().min(size);
+    copy_to_user(token, buf, &target.as_bytes()[..len])?;
+    Ok(len)
+}
+
+/// Change the cwd of current process to the directory at `path`
+pub fn sys_chdir(path: *const u8) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_chdir",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let path = translated_user_str(token, path)?;
+    let dir = get_dir(AT_FDCWD)?;
+    let inode = dir.find_path(path.as_str()).ok_or(Errno::ENOENT)?;
+    if !inode.is_dir() {
+        return Err(Errno::ENOTDIR);
+    }
+    current_process().inner_exclusive_access().cwd = Arc::new(OSInode::new(false, false, inode));
+    Ok(0)
+}
+
+/// Change the cwd of current process to the directory opened at `fd`
+pub fn sys_fchdir(fd: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_fchdir",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    if fd == AT_FDCWD {
+        return Err(Errno::EBADF);
+    }
+    let inode = get_dir(fd)?;
+    current_process().inner_exclusive_access().cwd = Arc::new(OSInode::new(false, false, inode));
+    Ok(0)
+}
+
+/// Change the permission bits of the file opened at `fd`
+pub fn sys_fchmod(fd: usize, mode: u32) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_fchmod",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let file = get_file(fd)?;
+    let any: &dyn Any = file.as_any();
+    let os_node = any.downcast_ref::<OSInode>().ok_or(Errno::EINVAL)?;
+    os_node.inode().chmod(mode as u16);
+    Ok(0)
+}
+
+/// Change the permission bits of the file at `path` relative to `dirfd`
+pub fn sys_fchmodat(dirfd: usize, path: *const u8, mode: u32) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_fchmodat",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let token = current_user_token();
+    let path = translated_user_str(token, path)?;
+    let dir = get_dir(dirfd)?;
+    let inode = dir.find_path(path.as_str()).ok_or(Errno::ENOENT)?;
+    inode.chmod(mode as u16);
+    Ok(0)
+}
+
+/// Copy the absolute path of the cwd into `buf` with a trailing '\0',
+/// return the length written including the '\0'
+pub fn sys_getcwd(buf: *mut u8, size: usize) -> SyscallResult {
+    trace!(
+        "kernel:pid[{}] sys_getcwd",
+        current_task().unwrap().process.upgrade().unwrap().getpid()
+    );
+    let cwd = get_dir(AT_FDCWD)?;
+    let mut path = absolute_path(&cwd)?;
+    path.push('\0');
+    if path.len() > size {
+        return Err(Errno::ERANGE);
     }
-    -1
+    copy_to_user(current_user_token(), buf, path.as_bytes())?;
+    Ok(path.len())
 }
 
 // #[allow(unused)]
@@ -38,15 +38,18 @@ use crate::config::{MAX_SYSCALL_NUM, PAGE_SIZE, TRAP_CONTEXT_BASE, MAXVA};
 use crate::timer::{get_time_us, get_time_ms};
 #[allow(unused)]
 use crate::mm::{translated_byte_buffer,  translated_ref, translated_str, translated_refmut};
+use crate::mm::{copy_obj_from_user, copy_obj_to_user, translated_user_str};
 #[allow(unused)]
 use crate::mm::{VPNRange, VirtAddr, MapPermission, MemorySet, KERNEL_SPACE};
 use alloc::string::String;
 
 #[allow(unused)]
-use crate::fs::{open_file, OpenFlags, File, MailBox};
+use crate::fs::{open_file, open_file_at, OpenFlags, File, MailBox};
 
 use log::*;
 
+use super::errno::{Errno, SyscallResult};
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct TimeVal {
@@ -76,21 +79,21 @@ pub fn sys_exit(exit_code: i32) -> ! {
 }
 
 /// current task gives up resources for other tasks
-pub fn sys_yield() -> isize {
+pub fn sys_yield() -> SyscallResult {
     suspend_current_and_run_next();
-    0
+    Ok(0)
 }
 
 /// getpid syscall
-pub fn sys_getpid() -> isize {
+pub fn sys_getpid() -> SyscallResult {
     trace!(
         "kernel: sys_getpid pid:{}",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
-    current_task().unwrap().process.upgrade().unwrap().getpid() as isize
+    Ok(current_task().unwrap().process.upgrade().unwrap().getpid())
 }
 
-pub fn sys_fork() -> isize {
+pub fn sys_fork() -> SyscallResult {
     trace!(
         "kernel:pid[{}] sys_fork",
         current_task().unwrap().process.upgrade().unwrap().getpid()
@@ -105,42 +108,46 @@ pub fn sys_fork() -> isize {
     // we do not have to move to next instruction since we have done it before
     // for child process, fork returns 0
     trap_cx.x[10] = 0;
-    new_pid as isize
+    Ok(new_pid)
 }
 
-pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
+pub fn sys_exec(path: *const u8, mut args: *const usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] sys_exec",
         current_task().unwrap().process.upgrade().unwrap().getpid()
     );
     let token = current_user_token();
-    let path = translated_str(token, path);
+    let path = translated_user_str(token, path)?;
     let mut args_vec: Vec<String> = Vec::new();
     loop {
-        let arg_str_ptr = *translated_ref(token, args);
+        let arg_str_ptr = copy_obj_from_user(token, args)?;
         if arg_str_ptr == 0 {
             break;
         }
-        args_vec.push(translated_str(token, arg_str_ptr as *const u8));
+        args_vec.push(translated_user_str(token, arg_str_ptr as *const u8)?);
         unsafe {
             args = args.add(1);
         }
     }
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
-        let all_data = app_inode.read_all();
-        let process = current_process();
-        let argc = args_vec.len();
-        process.exec(all_data.as_slice(), args_vec);
-        // return argc because cx.x[10] will be covered with it later
-        argc as isize
-    } else {
-        -1
+    let cwd = current_process().inner_exclusive_access().cwd.inode();
+    let app_inode = open_file_at(&cwd, path.as_str(), OpenFlags::RDONLY)?;
+    if app_inode.is_dir() {
+        return Err(Errno::EACCES);
     }
+    let all_data = app_inode.read_all();
+    if !all_data.starts_with(&[0x7f, 0x45, 0x4c, 0x46]) {
+        return Err(Errno::ENOEXEC);
+    }
+    let process = current_process();
+    let argc = args_vec.len();
+    process.exec(all_data.as_slice(), args_vec);
+    // return argc because cx.x[10] will be covered with it later
+    Ok(argc)
 }
 
-/// If there is not a child process whose pid is same as given, return -1.
-/// Else if there is a child process but it is still running, return -2.
-pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
+/// If there is not a child process whose pid is same as given, return ECHILD.
+/// Else if there is a child process but it is still running, return EAGAIN.
+pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> SyscallResult {
     let process = current_process();
     // find a child process
 
@@ -151,7 +158,7 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
         .iter()
         .any(|p| pid == -1 || pid as usize == p.getpid())
     {
-        return -1;
+        return Err(Errno::ECHILD);
         // ---- release current PCB
     }
     let pair = inner.children.iter().enumerate().find(|(_, p)| {
@@ -159,44 +166,34 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
         p.inner_exclusive_access().is_zombie && (pid == -1 || pid as usize == p.getpid())
         // ++++ release child PCB
     });
-    if let Some((idx, _)) = pair {
+    if let Some((idx, child)) = pair {
+        // ++++ temporarily access child PCB exclusively
+        let exit_code = child.inner_exclusive_access().exit_code;
+        // ++++ release child PCB
+        // a bad pointer leaves the child unreaped
+        if !exit_code_ptr.is_null() {
+            copy_obj_to_user(inner.memory_set.token(), exit_code_ptr, &exit_code)?;
+        }
         let child = inner.children.remove(idx);
         // confirm that child will be deallocated after being removed from children list
         assert_eq!(Arc::strong_count(&child), 1);
         let found_pid = child.getpid();
-        // ++++ temporarily access child PCB exclusively
-        let exit_code = child.inner_exclusive_access().exit_code;
-        // ++++ release child PCB
-        *translated_refmut(inner.memory_set.token(), exit_code_ptr) = exit_code;
-        found_pid as isize
+        Ok(found_pid)
     } else {
-        -2
+        Err(Errno::EAGAIN)
     }
     // ---- release current PCB automatically
 }
 
 
-pub fn sys_get_time(ts: *mut TimeVal, _tz: usize) -> isize {
+pub fn sys_get_time(ts: *mut TimeVal, _tz: usize) -> SyscallResult {
     let us = get_time_us();
-    let dst_vec = translated_byte_buffer(
-        current_user_token(),
-        ts as *const u8, core::mem::size_of::<TimeVal>()
-    );
-    let ref time_val = TimeVal {
-            sec: us / 1_000_000,
-            usec: us % 1_000_000,
+    let time_val = TimeVal {
+        sec: us / 1_000_000,
+        usec: us % 1_000_000,
     };
-    let src_ptr = time_val as *const TimeVal;
-    for (idx, dst) in dst_vec.into_iter().enumerate() {
-        let unit_len = dst.len();
-        unsafe {
-            dst.copy_from_slice(core::slice::from_raw_parts(
-                src_ptr.wrapping_byte_add(idx * unit_len) as *const u8,
-                unit_len)
-            );
-        }
-    }
-    0
+    copy_obj_to_user(current_user_token(), ts, &time_val)?;
+    Ok(0)
 }
 
 // pub fn sys_task_info(ti: *mut TaskInfo) -> isize {
@@ -3,9 +3,11 @@ use crate::task::{block_current_and_run_next, current_process, current_task};
 use crate::timer::{add_timer, get_time_ms};
 use alloc::sync::Arc;
 
+use super::errno::{Errno, SyscallResult};
+
 #[allow(unused)]
 /// sleep syscall
-pub fn sys_sleep(ms: usize) -> isize {
+pub fn sys_sleep(ms: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_sleep",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -21,10 +23,10 @@ pub fn sys_sleep(ms: usize) -> isize {
     let task = current_task().unwrap();
     add_timer(expire_ms, task);
     block_current_and_run_next();
-    0
+    Ok(0)
 }
 /// mutex create syscall
-pub fn sys_mutex_create(blocking: bool) -> isize {
+pub fn sys_mutex_create(blocking: bool) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_mutex_create",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -51,14 +53,14 @@ pub fn sys_mutex_create(blocking: bool) -> isize {
         .map(|(id, _)| id)
     {
         process_inner.mutex_list[id] = mutex;
-        id as isize
+        Ok(id)
     } else {
         process_inner.mutex_list.push(mutex);
-        process_inner.mutex_list.len() as isize - 1
+        Ok(process_inner.mutex_list.len() - 1)
     }
 }
 /// mutex lock syscall
-pub fn sys_mutex_lock(mutex_id: usize) -> isize {
+pub fn sys_mutex_lock(mutex_id: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_mutex_lock",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -72,14 +74,20 @@ pub fn sys_mutex_lock(mutex_id: usize) -> isize {
     );
     let process = current_process();
     let process_inner = process.inner_exclusive_access();
-    let mutex = Arc::clone(process_inner.mutex_list[mutex_id].as_ref().unwrap());
+    let mutex = Arc::clone(
+        process_inner
+            .mutex_list
+            .get(mutex_id)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EINVAL)?,
+    );
     drop(process_inner);
     drop(process);
     mutex.lock();
-    0
+    Ok(0)
 }
 /// mutex unlock syscall
-pub fn sys_mutex_unlock(mutex_id: usize) -> isize {
+pub fn sys_mutex_unlock(mutex_id: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_mutex_unlock",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -93,14 +101,20 @@ pub fn sys_mutex_unlock(mutex_id: usize) -> isize {
     );
     let process = current_process();
     let process_inner = process.inner_exclusive_access();
-    let mutex = Arc::clone(process_inner.mutex_list[mutex_id].as_ref().unwrap());
+    let mutex = Arc::clone(
+        process_inner
+            .mutex_list
+            .get(mutex_id)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EINVAL)?,
+    );
     drop(process_inner);
     drop(process);
     mutex.unlock();
-    0
+    Ok(0)
 }
 /// semaphore create syscall
-pub fn sys_semaphore_create(res_count: usize) -> isize {
+pub fn sys_semaphore_create(res_count: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_semaphore_create",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -129,10 +143,10 @@ pub fn sys_semaphore_create(res_count: usize) -> isize {
             .push(Some(Arc::new(Semaphore::new(res_count))));
         process_inner.semaphore_list.len() - 1
     };
-    id as isize
+    Ok(id)
 }
 /// semaphore up syscall
-pub fn sys_semaphore_up(sem_id: usize) -> isize {
+pub fn sys_semaphore_up(sem_id: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_semaphore_up",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -146,13 +160,19 @@ pub fn sys_semaphore_up(sem_id: usize) -> isize {
     );
     let process = current_process();
     let process_inner = process.inner_exclusive_access();
-    let sem = Arc::clone(process_inner.semaphore_list[sem_id].as_ref().unwrap());
+    let sem = Arc::clone(
+        process_inner
+            .semaphore_list
+            .get(sem_id)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EINVAL)?,
+    );
     drop(process_inner);
     sem.up();
-    0
+    Ok(0)
 }
 /// semaphore down syscall
-pub fn sys_semaphore_down(sem_id: usize) -> isize {
+pub fn sys_semaphore_down(sem_id: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_semaphore_down",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -166,13 +186,19 @@ pub fn sys_semaphore_down(sem_id: usize) -> isize {
     );
     let process = current_process();
     let process_inner = process.inner_exclusive_access();
-    let sem = Arc::clone(process_inner.semaphore_list[sem_id].as_ref().unwrap());
+    let sem = Arc::clone(
+        process_inner
+            .semaphore_list
+            .get(sem_id)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EINVAL)?,
+    );
     drop(process_inner);
     sem.down();
-    0
+    Ok(0)
 }
 /// condvar create syscall
-pub fn sys_condvar_create() -> isize {
+pub fn sys_condvar_create() -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_condvar_create",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -201,10 +227,10 @@ pub fn sys_condvar_create() -> isize {
             .push(Some(Arc::new(Condvar::new())));
         process_inner.condvar_list.len() - 1
     };
-    id as isize
+    Ok(id)
 }
 /// condvar signal syscall
-pub fn sys_condvar_signal(condvar_id: usize) -> isize {
+pub fn sys_condvar_signal(condvar_id: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_condvar_signal",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -218,13 +244,19 @@ pub fn sys_condvar_signal(condvar_id: usize) -> isize {
     );
     let process = current_process();
     let process_inner = process.inner_exclusive_access();
-    let condvar = Arc::clone(process_inner.condvar_list[condvar_id].as_ref().unwrap());
+    let condvar = Arc::clone(
+        process_inner
+            .condvar_list
+            .get(condvar_id)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EINVAL)?,
+    );
     drop(process_inner);
     condvar.signal();
-    0
+    Ok(0)
 }
 /// condvar wait syscall
-pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> isize {
+pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_condvar_wait",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -238,16 +270,28 @@ pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> isize {
     );
     let process = current_process();
     let process_inner = process.inner_exclusive_access();
-    let condvar = Arc::clone(process_inner.condvar_list[condvar_id].as_ref().unwrap());
-    let mutex = Arc::clone(process_inner.mutex_list[mutex_id].as_ref().unwrap());
+    let condvar = Arc::clone(
+        process_inner
+            .condvar_list
+            .get(condvar_id)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EINVAL)?,
+    );
+    let mutex = Arc::clone(
+        process_inner
+            .mutex_list
+            .get(mutex_id)
+            .and_then(Option::as_ref)
+            .ok_or(Errno::EINVAL)?,
+    );
     drop(process_inner);
     condvar.wait(mutex);
-    0
+    Ok(0)
 }
 /// enable deadlock detection syscall
 ///
 /// YOUR JOB: Implement deadlock detection, but might not all in this syscall
-pub fn sys_enable_deadlock_detect(_enabled: usize) -> isize {
+pub fn sys_enable_deadlock_detect(_enabled: usize) -> SyscallResult {
     trace!("kernel: sys_enable_deadlock_detect NOT IMPLEMENTED");
-    -1
+    Err(Errno::ENOSYS)
 }
@@ -1,13 +1,15 @@
 use crate::{
-    mm::kernel_token,
+    mm::{copy_obj_to_user, kernel_token},
     task::{add_task, current_task, TaskControlBlock},
     trap::{trap_handler, TrapContext},
 };
 use alloc::sync::Arc;
 use log::trace;
 
+use super::errno::{Errno, SyscallResult};
+
 /// thread create syscall
-pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
+pub fn sys_thread_create(entry: usize, arg: usize) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_thread_create",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -52,12 +54,12 @@ pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
         trap_handler as usize,
     );
     (*new_task_trap_cx).x[10] = arg;
-    new_task_tid as isize
+    Ok(new_task_tid)
 }
 
 #[allow(unused)]
 /// get current thread id syscall
-pub fn sys_gettid() -> isize {
+pub fn sys_gettid() -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_gettid",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -69,21 +71,22 @@ pub fn sys_gettid() -> isize {
             .unwrap()
             .tid
     );
-    current_task()
+    Ok(current_task()
         .unwrap()
         .inner_exclusive_access()
         .res
         .as_ref()
         .unwrap()
-        .tid as isize
+        .tid)
 }
 
 /// wait for a thread to exit syscall
 ///
-/// thread does not exist, return -1
-/// thread has not exited yet, return -2
-/// otherwise, return thread's exit code
-pub fn sys_waittid(tid: usize) -> i32 {
+/// thread does not exist, return ESRCH
+/// thread waits for itself, return EDEADLK
+/// thread has not exited yet, return EAGAIN
+/// otherwise, store thread's exit code at `exit_code_ptr` and return `tid`
+pub fn sys_waittid(tid: usize, exit_code_ptr: *mut i32) -> SyscallResult {
     trace!(
         "kernel:pid[{}] tid[{}] sys_waittid",
         current_task().unwrap().process.upgrade().unwrap().getpid(),
@@ -101,24 +104,28 @@ pub fn sys_waittid(tid: usize) -> i32 {
     let mut process_inner = process.inner_exclusive_access();
     // a thread cannot wait for itself
     if task_inner.res.as_ref().unwrap().tid == tid {
-        return -1;
+        return Err(Errno::EDEADLK);
     }
     let mut exit_code: Option<i32> = None;
-    let waited_task = process_inner.tasks[tid].as_ref();
+    let waited_task = process_inner.tasks.get(tid).and_then(|task| task.as_ref());
     if let Some(waited_task) = waited_task {
         if let Some(waited_exit_code) = waited_task.inner_exclusive_access().exit_code {
             exit_code = Some(waited_exit_code);
         }
     } else {
         // waited thread does not exist
-        return -1;
+        return Err(Errno::ESRCH);
     }
     if let Some(exit_code) = exit_code {
+        // a bad pointer leaves the thread unreaped
+        if !exit_code_ptr.is_null() {
+            copy_obj_to_user(process_inner.memory_set.token(), exit_code_ptr, &exit_code)?;
+        }
         // dealloc the exited thread
         process_inner.tasks[tid] = None;
-        exit_code
+        Ok(tid)
     } else {
         // waited thread has not exited
-        -2
+        Err(Errno::EAGAIN)
     }
 }
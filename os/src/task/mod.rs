@@ -24,7 +24,7 @@ mod signal;
 mod task;
 
 use self::id::TaskUserRes;
-use crate::fs::{open_file, OpenFlags};
+use crate::fs::{open_file, sync_all, OpenFlags, ROOT_DIR};
 use crate::task::manager::add_stopping_task;
 use crate::timer::remove_timer;
 use alloc::{sync::Arc, vec::Vec};
@@ -108,6 +108,8 @@ pub fn exit_current_and_run_next(exit_code: i32) {
                 "[kernel] Idle process exit with exit_code {} ...",
                 exit_code
             );
+            // nothing is left to write back dirty blocks after shutdown
+            sync_all();
             if exit_code != 0 {
                 //crate::sbi::shutdown(255); //255 == -1 for err hint
                 crate::board::QEMU_EXIT_HANDLE.exit_failure();
@@ -163,6 +165,9 @@ pub fn exit_current_and_run_next(exit_code: i32) {
         process_inner.memory_set.recycle_data_pages();
         // drop file descriptors
         process_inner.fd_table.clear();
+        process_inner.fd_cloexec.clear();
+        // close the cwd, which may be a removed directory
+        process_inner.cwd = Arc::clone(&ROOT_DIR);
         // remove all tasks
         process_inner.tasks.clear();
     }
@@ -5,7 +5,7 @@ use super::manager::insert_into_pid2process;
 use super::TaskControlBlock;
 use super::{add_task, SignalFlags};
 use super::{pid_alloc, PidHandle};
-use crate::fs::{File, Stdin, Stdout};
+use crate::fs::{File, OSInode, Stdin, Stdout, ROOT_DIR};
 use crate::mm::{translated_refmut, MemorySet, KERNEL_SPACE};
 use crate::sync::{Condvar, Mutex, Semaphore, UPSafeCell};
 use crate::trap::{trap_handler, TrapContext};
@@ -36,6 +36,11 @@ pub struct ProcessControlBlockInner {
     pub exit_code: i32,
 
     pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    /// close-on-exec flag of each slot in `fd_table`
+    pub fd_cloexec: Vec<bool>,
+    /// current working directory, the start of relative path lookups,
+    /// kept open so that it is not freed while in use once removed
+    pub cwd: Arc<OSInode>,
 
     pub signals: SignalFlags,
 
@@ -57,11 +62,34 @@ impl ProcessControlBlockInner {
     }
     /// allocate a new file descriptor
     pub fn alloc_fd(&mut self) -> usize {
-        if let Some(fd) = (0..self.fd_table.len()).find(|fd| self.fd_table[*fd].is_none()) {
-            fd
-        } else {
+        let fd = self.lowest_free_fd();
+        self.reserve_fd(fd);
+        fd
+    }
+    /// the file descriptor `alloc_fd` would return, without growing the fd table
+    pub fn lowest_free_fd(&self) -> usize {
+        (0..self.fd_table.len())
+            .find(|fd| self.fd_table[*fd].is_none())
+            .unwrap_or(self.fd_table.len())
+    }
+    /// grow the fd table so that `fd` becomes a valid slot
+    pub fn reserve_fd(&mut self, fd: usize) {
+        while self.fd_table.len() <= fd {
             self.fd_table.push(None);
-            self.fd_table.len() - 1
+            self.fd_cloexec.push(false);
+        }
+    }
+    /// close the file at `fd` and clear its close-on-exec flag
+    pub fn close_fd(&mut self, fd: usize) -> Option<Arc<dyn File + Send + Sync>> {
+        self.fd_cloexec[fd] = false;
+        self.fd_table[fd].take()
+    }
+    /// close every file descriptor marked close-on-exec
+    pub fn close_on_exec(&mut self) {
+        for fd in 0..self.fd_table.len() {
+            if self.fd_cloexec[fd] {
+                self.close_fd(fd);
+            }
         }
     }
     /// allocate a new task id
@@ -111,6 +139,8 @@ impl ProcessControlBlock {
                         // 2 -> stderr
                         Some(Arc::new(Stdout)),
                     ],
+                    fd_cloexec: vec![false; 3],
+                    cwd: Arc::clone(&ROOT_DIR),
                     signals: SignalFlags::empty(),
                     tasks: Vec::new(),
                     task_res_allocator: RecycleAllocator::new(),
@@ -160,6 +190,8 @@ impl ProcessControlBlock {
         // substitute memory_set
         debug!("kernel: exec .. substitute memory_set");
         self.inner_exclusive_access().memory_set = memory_set;
+        // files marked close-on-exec must not be seen by the new program
+        self.inner_exclusive_access().close_on_exec();
         // then we alloc user resource for main thread again
         // since memory_set has been changed
         trace!("kernel: exec .. alloc user resource for main thread again");
@@ -237,6 +269,8 @@ impl ProcessControlBlock {
                     children: Vec::new(),
                     exit_code: 0,
                     fd_table: new_fd_table,
+                    fd_cloexec: parent.fd_cloexec.clone(),
+                    cwd: Arc::clone(&parent.cwd),
                     signals: SignalFlags::empty(),
                     tasks: Vec::new(),
                     task_res_allocator: RecycleAllocator::new(),
@@ -15,6 +15,7 @@
 mod context;
 
 use crate::config::TRAMPOLINE;
+use crate::fs::periodic_sync;
 use crate::syscall::syscall;
 use crate::task::{
     current_trap_cx,
@@ -110,6 +111,7 @@ pub fn trap_handler() -> ! {
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             set_next_trigger();
             check_timer();
+            periodic_sync();
             suspend_current_and_run_next();
         }
         _ => {
@@ -20,7 +20,7 @@ use alloc::string::String;
 #[no_mangle]
 pub fn main() -> i32 {
     let fd = open("filea\0", OpenFlags::RDONLY);
-    if fd == -1 {
+    if fd < 0 {
         panic!("Error occured when opening file");
     }
     let fd = fd as usize;
@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+#![reexport_test_harness_main = "test_main"]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_runner)]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::string::String;
+use user_lib::{statfs, to_result, Statfs};
+
+/// Report the usage of the filesystems holding the paths given as arguments,
+/// or the root without arguments
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    println!(
+        "{:<16} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8}",
+        "Path", "Blocks", "Used", "Free", "Inodes", "IUsed", "IFree"
+    );
+    if argc <= 1 {
+        return report("/");
+    }
+    let mut exit_code = 0;
+    for path in argv.iter().skip(1) {
+        if report(path) != 0 {
+            exit_code = -1;
+        }
+    }
+    exit_code
+}
+
+fn report(path: &str) -> i32 {
+    let mut path_z = String::from(path);
+    path_z.push('\0');
+    let buf = Statfs::default();
+    if let Err(errno) = to_result(statfs(path_z.as_str(), &buf)) {
+        println!("df: {}: {}", path, errno);
+        return -1;
+    }
+    println!(
+        "{:<16} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8}",
+        path,
+        buf.blocks,
+        buf.blocks - buf.bfree,
+        buf.bfree,
+        buf.files,
+        buf.files - buf.ffree,
+        buf.ffree
+    );
+    0
+}
@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![allow(clippy::println_empty_string)]
+#![reexport_test_harness_main = "test_main"]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_runner)]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::string::String;
+use user_lib::read_dir;
+
+/// List the directories given as arguments, or the cwd without arguments
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    let mut exit_code = 0;
+    if argc <= 1 {
+        return list(".");
+    }
+    for (i, path) in argv.iter().enumerate().skip(1) {
+        if argc > 2 {
+            if i > 1 {
+                println!("");
+            }
+            println!("{}:", path);
+        }
+        if list(path) != 0 {
+            exit_code = -1;
+        }
+    }
+    exit_code
+}
+
+fn list(path: &str) -> i32 {
+    let mut path_z = String::from(path);
+    path_z.push('\0');
+    let dir = match read_dir(path_z.as_str()) {
+        Ok(dir) => dir,
+        Err(errno) => {
+            println!("ls: {}: {}", path, errno);
+            return -1;
+        }
+    };
+    for entry in dir {
+        match entry {
+            Ok(entry) if entry.name == "." || entry.name == ".." => {}
+            Ok(entry) if entry.is_dir() => println!("{}/", entry.name),
+            Ok(entry) if entry.is_symlink() => println!("{}@", entry.name),
+            Ok(entry) => println!("{}", entry.name),
+            Err(errno) => {
+                println!("ls: {}: {}", path, errno);
+                return -1;
+            }
+        }
+    }
+    0
+}
+
+pub fn test_runner(_test: &[&dyn Fn()]) {
+    loop {}
+}
@@ -69,7 +69,7 @@ pub fn main() -> i32 {
     threads.push(thread_create(consumer as usize, 0));
     // wait for all threads to complete
     for thread in threads.iter() {
-        waittid(*thread as usize);
+        waittid(*thread as usize, &mut 0);
     }
     println!("mpsc_sem passed!");
     0
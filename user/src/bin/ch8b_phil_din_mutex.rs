@@ -80,7 +80,7 @@ pub fn main() -> i32 {
         ));
     }
     for tid in v.iter() {
-        waittid(*tid as usize);
+        waittid(*tid as usize, &mut 0);
     }
     let time_cost = get_time_u() - start;
     println!("time cost = {}", time_cost);
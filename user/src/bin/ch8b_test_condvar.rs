@@ -55,7 +55,7 @@ pub fn main() -> i32 {
     ];
     // wait for all threads to complete
     for thread in threads.iter() {
-        waittid(*thread as usize);
+        waittid(*thread as usize, &mut 0);
     }
     println!("test_condvar passed!");
     0
@@ -55,9 +55,10 @@ pub fn main() -> i32 {
         thread_create(thread_c as usize, 0),
     ];
     for tid in v.iter() {
-        let exit_code = waittid(*tid as usize);
+        let mut exit_code = 0;
+        waittid(*tid as usize, &mut exit_code);
         println!("thread#{} exited with code {}", tid, exit_code);
-        assert_eq!(*tid, exit_code);
+        assert_eq!(*tid as i32, exit_code);
     }
     println!("main thread exited.");
     println!("threads test passed!");
@@ -39,7 +39,8 @@ pub fn main() -> i32 {
         ));
     }
     for tid in v.iter() {
-        let exit_code = waittid(*tid as usize);
+        let mut exit_code = 0;
+        waittid(*tid as usize, &mut exit_code);
         println!("thread#{} exited with code {}", tid, exit_code);
     }
     println!("main thread exited.");
@@ -16,10 +16,11 @@ const DL: u8 = 0x7fu8;
 const BS: u8 = 0x08u8;
 const LINE_START: &str = ">> ";
 
+use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 use user_lib::console::getchar;
-use user_lib::{close, dup, exec, fork, open, pipe, waitpid, OpenFlags};
+use user_lib::{chdir, close, dup2, exec, fork, open, pipe2, to_result, waitpid, OpenFlags};
 
 #[derive(Debug)]
 struct ProcessArguments {
@@ -87,7 +88,16 @@ pub fn main() -> i32 {
         match c {
             LF | CR => {
                 println!("");
-                if !line.is_empty() {
+                let words: Vec<_> = line.split(' ').filter(|w| !w.is_empty()).collect();
+                if words.first() == Some(&"cd") {
+                    // builtin: the cwd of the shell itself has to change
+                    let mut path = String::from(*words.get(1).unwrap_or(&"/"));
+                    path.push('\0');
+                    if let Err(errno) = to_result(chdir(path.as_str())) {
+                        println!("cd: {}: {}", words.get(1).unwrap_or(&"/"), errno);
+                    }
+                    line.clear();
+                } else if !line.is_empty() {
                     let splited: Vec<_> = line.as_str().split('|').collect();
                     let process_arguments_list: Vec<_> = splited
                         .iter()
@@ -114,12 +124,13 @@ pub fn main() -> i32 {
                     if !valid {
                         println!("Invalid command: Inputs/Outputs cannot be correctly binded!");
                     } else {
-                        // create pipes
+                        // create pipes, the ends not bound to stdin/stdout are
+                        // closed automatically when the children exec
                         let mut pipes_fd: Vec<[usize; 2]> = Vec::new();
                         if !process_arguments_list.is_empty() {
                             for _ in 0..process_arguments_list.len() - 1 {
                                 let mut pipe_fd = [0usize; 2];
-                                pipe(&mut pipe_fd);
+                                pipe2(&mut pipe_fd, OpenFlags::CLOEXEC);
                                 pipes_fd.push(pipe_fd);
                             }
                         }
@@ -134,49 +145,46 @@ pub fn main() -> i32 {
                                 // redirect input
                                 if !input.is_empty() {
                                     let input_fd = open(input.as_str(), OpenFlags::RDONLY);
-                                    if input_fd == -1 {
+                                    if input_fd < 0 {
                                         println!("Error when opening file {}", input);
                                         return -4;
                                     }
                                     let input_fd = input_fd as usize;
-                                    close(0);
-                                    assert_eq!(dup(input_fd), 0);
+                                    assert_eq!(dup2(input_fd, 0), 0);
                                     close(input_fd);
                                 }
                                 // redirect output
                                 if !output.is_empty() {
                                     let output_fd = open(
                                         output.as_str(),
-                                        OpenFlags::CREATE | OpenFlags::WRONLY,
+                                        OpenFlags::CREATE | OpenFlags::TRUNC | OpenFlags::WRONLY,
                                     );
-                                    if output_fd == -1 {
+                                    if output_fd < 0 {
                                         println!("Error when opening file {}", output);
                                         return -4;
                                     }
                                     let output_fd = output_fd as usize;
-                                    close(1);
-                                    assert_eq!(dup(output_fd), 1);
+                                    assert_eq!(dup2(output_fd, 1), 1);
                                     close(output_fd);
                                 }
                                 // receive input from the previous process
                                 if i > 0 {
-                                    close(0);
                                     let read_end = pipes_fd.get(i - 1).unwrap()[0];
-                                    assert_eq!(dup(read_end), 0);
+                                    assert_eq!(dup2(read_end, 0), 0);
                                 }
                                 // send output to the next process
                                 if i < process_arguments_list.len() - 1 {
-                                    close(1);
                                     let write_end = pipes_fd.get(i).unwrap()[1];
-                                    assert_eq!(dup(write_end), 1);
-                                }
-                                // close all pipe ends inherited from the parent process
-                                for pipe_fd in pipes_fd.iter() {
-                                    close(pipe_fd[0]);
-                                    close(pipe_fd[1]);
+                                    assert_eq!(dup2(write_end, 1), 1);
                                 }
+                                // apps live in the root directory unless a path is given
+                                let app = if args_copy[0].contains('/') {
+                                    args_copy[0].clone()
+                                } else {
+                                    format!("/{}", args_copy[0])
+                                };
                                 // execute new application
-                                if exec(args_copy[0].as_str(), args_addr.as_slice()) == -1 {
+                                if exec(app.as_str(), args_addr.as_slice()) < 0 {
                                     println!("Error when executing!");
                                     return -4;
                                 }
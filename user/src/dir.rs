@@ -0,0 +1,103 @@
+//! Iterate over directory entries with `getdents64`
+
+use crate::errno::{to_result, Errno};
+use crate::{close, getdents64, open, OpenFlags};
+use alloc::string::String;
+
+/// `d_type` of a directory
+pub const DT_DIR: u8 = 4;
+/// `d_type` of a regular file
+pub const DT_REG: u8 = 8;
+/// `d_type` of a symbolic link
+pub const DT_LNK: u8 = 10;
+
+/// An entry of a directory
+#[derive(Debug)]
+pub struct DirEntry {
+    /// inode number
+    pub ino: u64,
+    /// `DT_DIR`, `DT_REG` or other `d_type` values
+    pub type_: u8,
+    /// file name
+    pub name: String,
+}
+
+impl DirEntry {
+    /// whether the entry is a directory
+    pub fn is_dir(&self) -> bool {
+        self.type_ == DT_DIR
+    }
+    /// whether the entry is a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        self.type_ == DT_LNK
+    }
+}
+
+/// Iterator over the entries of a directory, "." and ".." included.
+/// The directory is closed when it is dropped.
+pub struct ReadDir {
+    fd: usize,
+    buf: [u8; 512],
+    /// `buf[pos..len]` holds records not yet returned
+    pos: usize,
+    len: usize,
+    /// set at the end of the directory or after an error
+    done: bool,
+}
+
+/// Open the directory at `path`, which must end with '\0'
+pub fn read_dir(path: &str) -> Result<ReadDir, Errno> {
+    let fd = to_result(open(path, OpenFlags::RDONLY))?;
+    Ok(ReadDir {
+        fd,
+        buf: [0; 512],
+        pos: 0,
+        len: 0,
+        done: false,
+    })
+}
+
+impl Iterator for ReadDir {
+    type Item = Result<DirEntry, Errno>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.pos == self.len {
+            match to_result(getdents64(self.fd, &mut self.buf)) {
+                Ok(0) => {
+                    self.done = true;
+                    return None;
+                }
+                Ok(len) => {
+                    self.pos = 0;
+                    self.len = len;
+                }
+                Err(errno) => {
+                    self.done = true;
+                    return Some(Err(errno));
+                }
+            }
+        }
+        // struct linux_dirent64 { ino: u64, off: i64, reclen: u16, type: u8, name: [u8] }
+        let record = &self.buf[self.pos..self.len];
+        let ino = u64::from_ne_bytes(record[0..8].try_into().unwrap());
+        let reclen = u16::from_ne_bytes(record[16..18].try_into().unwrap()) as usize;
+        let type_ = record[18];
+        let name = &record[19..reclen];
+        let name_len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        self.pos += reclen;
+        Some(Ok(DirEntry {
+            ino,
+            type_,
+            name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+        }))
+    }
+}
+
+impl Drop for ReadDir {
+    fn drop(&mut self) {
+        close(self.fd);
+    }
+}
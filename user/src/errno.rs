@@ -0,0 +1,170 @@
+//! Error numbers returned by the kernel, a negative syscall return value is `-errno`
+
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Errno {
+    /// Operation not permitted
+    EPERM = 1,
+    /// No such file or directory
+    ENOENT = 2,
+    /// No such process
+    ESRCH = 3,
+    /// I/O error
+    EIO = 5,
+    /// Argument list too long
+    E2BIG = 7,
+    /// Exec format error
+    ENOEXEC = 8,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// No child processes
+    ECHILD = 10,
+    /// Try again
+    EAGAIN = 11,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Permission denied
+    EACCES = 13,
+    /// Bad address
+    EFAULT = 14,
+    /// Device or resource busy
+    EBUSY = 16,
+    /// File exists
+    EEXIST = 17,
+    /// Cross-device link
+    EXDEV = 18,
+    /// Not a directory
+    ENOTDIR = 20,
+    /// Is a directory
+    EISDIR = 21,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Too many open files
+    EMFILE = 24,
+    /// File too large
+    EFBIG = 27,
+    /// No space left on device
+    ENOSPC = 28,
+    /// Illegal seek
+    ESPIPE = 29,
+    /// Too many links
+    EMLINK = 31,
+    /// Broken pipe
+    EPIPE = 32,
+    /// Math result not representable
+    ERANGE = 34,
+    /// Resource deadlock would occur
+    EDEADLK = 35,
+    /// File name too long
+    ENAMETOOLONG = 36,
+    /// Function not implemented
+    ENOSYS = 38,
+    /// Directory not empty
+    ENOTEMPTY = 39,
+    /// Too many symbolic links encountered
+    ELOOP = 40,
+    /// Operation not supported
+    EOPNOTSUPP = 95,
+}
+
+/// Result of a syscall, `Ok` holds the non-negative return value
+pub type SyscallResult = Result<usize, Errno>;
+
+impl Errno {
+    /// The raw value returned by a failed syscall
+    pub fn as_ret(self) -> isize {
+        -(self as isize)
+    }
+    /// Get the error of a raw syscall return value, `None` if it succeeded
+    pub fn from_ret(ret: isize) -> Option<Self> {
+        if ret >= 0 {
+            return None;
+        }
+        let errno = match -ret {
+            1 => Self::EPERM,
+            2 => Self::ENOENT,
+            3 => Self::ESRCH,
+            5 => Self::EIO,
+            7 => Self::E2BIG,
+            8 => Self::ENOEXEC,
+            9 => Self::EBADF,
+            10 => Self::ECHILD,
+            11 => Self::EAGAIN,
+            12 => Self::ENOMEM,
+            13 => Self::EACCES,
+            14 => Self::EFAULT,
+            16 => Self::EBUSY,
+            17 => Self::EEXIST,
+            18 => Self::EXDEV,
+            20 => Self::ENOTDIR,
+            21 => Self::EISDIR,
+            22 => Self::EINVAL,
+            24 => Self::EMFILE,
+            27 => Self::EFBIG,
+            28 => Self::ENOSPC,
+            29 => Self::ESPIPE,
+            31 => Self::EMLINK,
+            32 => Self::EPIPE,
+            34 => Self::ERANGE,
+            35 => Self::EDEADLK,
+            36 => Self::ENAMETOOLONG,
+            38 => Self::ENOSYS,
+            39 => Self::ENOTEMPTY,
+            40 => Self::ELOOP,
+            95 => Self::EOPNOTSUPP,
+            _ => return None,
+        };
+        Some(errno)
+    }
+    /// A short description of the error
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::EPERM => "Operation not permitted",
+            Self::ENOENT => "No such file or directory",
+            Self::ESRCH => "No such process",
+            Self::EIO => "I/O error",
+            Self::E2BIG => "Argument list too long",
+            Self::ENOEXEC => "Exec format error",
+            Self::EBADF => "Bad file descriptor",
+            Self::ECHILD => "No child processes",
+            Self::EAGAIN => "Try again",
+            Self::ENOMEM => "Out of memory",
+            Self::EACCES => "Permission denied",
+            Self::EFAULT => "Bad address",
+            Self::EBUSY => "Device or resource busy",
+            Self::EEXIST => "File exists",
+            Self::EXDEV => "Cross-device link",
+            Self::ENOTDIR => "Not a directory",
+            Self::EISDIR => "Is a directory",
+            Self::EINVAL => "Invalid argument",
+            Self::EMFILE => "Too many open files",
+            Self::EFBIG => "File too large",
+            Self::ENOSPC => "No space left on device",
+            Self::ESPIPE => "Illegal seek",
+            Self::EMLINK => "Too many links",
+            Self::EPIPE => "Broken pipe",
+            Self::ERANGE => "Math result not representable",
+            Self::EDEADLK => "Resource deadlock would occur",
+            Self::ENAMETOOLONG => "File name too long",
+            Self::ENOSYS => "Function not implemented",
+            Self::ENOTEMPTY => "Directory not empty",
+            Self::ELOOP => "Too many symbolic links encountered",
+            Self::EOPNOTSUPP => "Operation not supported",
+        }
+    }
+}
+
+impl core::fmt::Display for Errno {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+/// Convert a raw syscall return value into a [`SyscallResult`]
+pub fn to_result(ret: isize) -> SyscallResult {
+    match Errno::from_ret(ret) {
+        Some(errno) => Err(errno),
+        None => Ok(ret as usize),
+    }
+}
@@ -10,6 +10,8 @@
 
 #[macro_use]
 pub mod console;
+pub mod dir;
+pub mod errno;
 mod lang_items;
 pub mod syscall;
 
@@ -18,6 +20,8 @@ extern crate alloc;
 extern crate bitflags;
 
 pub use console::STDOUT;
+pub use dir::{read_dir, DirEntry, ReadDir};
+pub use errno::{to_result, Errno, SyscallResult};
 pub use syscall::*;
 
 use alloc::vec::Vec;
@@ -131,8 +135,12 @@ bitflags! {
         const RDONLY = 0;
         const WRONLY = 1 << 0;
         const RDWR = 1 << 1;
+        /// with `CREATE`, fail if the file exists
+        const EXCL = 1 << 7;
         const CREATE = 1 << 9;
         const TRUNC = 1 << 10;
+        const APPEND = 1 << 11;
+        const CLOEXEC = 1 << 19;
     }
 }
 
@@ -148,8 +156,20 @@ pub struct Stat {
     pub mode: StatMode,
     /// number of hard links
     pub nlink: u32,
+    /// user id of owner
+    pub uid: u32,
+    /// group id of owner
+    pub gid: u32,
+    /// total size in bytes
+    pub size: u64,
+    /// time of last access in seconds
+    pub atime: u64,
+    /// time of last modification in seconds
+    pub mtime: u64,
+    /// time of last status change in seconds
+    pub ctime: u64,
     /// unused pad
-    pad: [u64; 7],
+    pad: [u64; 2],
 }
 
 impl Stat {
@@ -159,7 +179,13 @@ impl Stat {
             ino: 0,
             mode: StatMode::NULL,
             nlink: 0,
-            pad: [0; 7],
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            pad: [0; 2],
         }
     }
 }
@@ -170,6 +196,36 @@ impl Default for Stat {
     }
 }
 
+/// The usage of a filesystem, laid out as `struct statfs` of Linux
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Statfs {
+    /// type of filesystem
+    pub type_: u64,
+    /// size of a block in bytes
+    pub bsize: u64,
+    /// total data blocks
+    pub blocks: u64,
+    /// free data blocks
+    pub bfree: u64,
+    /// free data blocks for unprivileged users
+    pub bavail: u64,
+    /// total inodes
+    pub files: u64,
+    /// free inodes
+    pub ffree: u64,
+    /// filesystem id
+    pub fsid: [i32; 2],
+    /// maximum length of names
+    pub namelen: u64,
+    /// fragment size in bytes
+    pub frsize: u64,
+    /// mount flags
+    pub flags: u64,
+    /// unused pad
+    spare: [u64; 4],
+}
+
 bitflags! {
     pub struct StatMode: u32 {
         const NULL  = 0;
@@ -177,15 +233,47 @@ bitflags! {
         const DIR   = 0o040000;
         /// ordinary regular file
         const FILE  = 0o100000;
+        /// symbolic link
+        const LNK   = 0o120000;
+        /// set-user-ID
+        const SUID  = 0o4000;
+        /// set-group-ID
+        const SGID  = 0o2000;
+        /// sticky bit
+        const SVTX  = 0o1000;
+        /// owner has read permission
+        const RUSR  = 0o400;
+        /// owner has write permission
+        const WUSR  = 0o200;
+        /// owner has execute permission
+        const XUSR  = 0o100;
+        /// group has read permission
+        const RGRP  = 0o040;
+        /// group has write permission
+        const WGRP  = 0o020;
+        /// group has execute permission
+        const XGRP  = 0o010;
+        /// others have read permission
+        const ROTH  = 0o004;
+        /// others have write permission
+        const WOTH  = 0o002;
+        /// others have execute permission
+        const XOTH  = 0o001;
     }
 }
 
-const AT_FDCWD: isize = -100;
+pub const AT_FDCWD: isize = -100;
+/// Remove a directory instead of a file in `sys_unlinkat`
+const AT_REMOVEDIR: usize = 0x200;
 
 pub fn open(path: &str, flags: OpenFlags) -> isize {
     sys_openat(AT_FDCWD as usize, path, flags.bits, OpenFlags::RDWR.bits)
 }
 
+pub fn openat(dirfd: usize, path: &str, flags: OpenFlags) -> isize {
+    sys_openat(dirfd, path, flags.bits, OpenFlags::RDWR.bits)
+}
+
 pub fn close(fd: usize) -> isize {
     sys_close(fd)
 }
@@ -238,6 +326,40 @@ pub fn read(fd: usize, buf: &mut [u8]) -> isize {
     sys_read(fd, buf)
 }
 
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    sys_lseek(fd, offset, whence)
+}
+
+pub fn pread(fd: usize, buf: &mut [u8], offset: usize) -> isize {
+    sys_pread(fd, buf, offset)
+}
+
+pub fn pwrite(fd: usize, buf: &[u8], offset: usize) -> isize {
+    sys_pwrite(fd, buf, offset)
+}
+
+/// Set the size of the file at `path` to `len` bytes
+pub fn truncate(path: &str, len: isize) -> isize {
+    sys_truncate(path, len)
+}
+
+pub fn ftruncate(fd: usize, len: isize) -> isize {
+    sys_ftruncate(fd, len)
+}
+
+/// Allocate the range but keep the file size
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+/// Deallocate the range instead, must be used with `FALLOC_FL_KEEP_SIZE`
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+
+pub fn fallocate(fd: usize, mode: u32, offset: isize, len: isize) -> isize {
+    sys_fallocate(fd, mode, offset, len)
+}
+
 pub fn getpid() -> isize {
     sys_getpid()
 }
@@ -254,10 +376,10 @@ pub fn exec(path: &str, args: &[*const u8]) -> isize {
 pub fn wait(exit_code: &mut i32) -> isize {
     loop {
         match sys_waitpid(-1, exit_code as *mut _) {
-            -2 => {
+            ret if ret == Errno::EAGAIN.as_ret() => {
                 yield_();
             }
-            // -1 or a real pid
+            // -ECHILD or a real pid
             exit_pid => return exit_pid,
         }
     }
@@ -266,10 +388,10 @@ pub fn wait(exit_code: &mut i32) -> isize {
 pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     loop {
         match sys_waitpid(pid as isize, exit_code as *mut _) {
-            -2 => {
+            ret if ret == Errno::EAGAIN.as_ret() => {
                 yield_();
             }
-            // -1 or a real pid
+            // -ECHILD or a real pid
             exit_pid => return exit_pid,
         }
     }
@@ -301,13 +423,94 @@ pub fn fstat(fd: usize, st: &Stat) -> isize {
     sys_fstat(fd, st)
 }
 
+/// Get the usage of the filesystem holding the file at `path`
+pub fn statfs(path: &str, buf: &Statfs) -> isize {
+    sys_statfs(path, buf)
+}
+
+pub fn fstatfs(fd: usize, buf: &Statfs) -> isize {
+    sys_fstatfs(fd, buf)
+}
+
+/// Rename `old_path` to `new_path`, replacing `new_path` if it exists
+pub fn rename(old_path: &str, new_path: &str) -> isize {
+    sys_renameat(AT_FDCWD as usize, old_path, AT_FDCWD as usize, new_path)
+}
+
+pub fn mkdir(path: &str) -> isize {
+    sys_mkdirat(AT_FDCWD as usize, path, 0o755)
+}
+
+pub fn rmdir(path: &str) -> isize {
+    sys_unlinkat(AT_FDCWD as usize, path, AT_REMOVEDIR)
+}
+
+pub fn symlink(target: &str, path: &str) -> isize {
+    sys_symlinkat(target, AT_FDCWD as usize, path)
+}
+
+pub fn readlink(path: &str, buf: &mut [u8]) -> isize {
+    sys_readlinkat(AT_FDCWD as usize, path, buf)
+}
+
+pub fn sync() -> isize {
+    sys_sync()
+}
+
+pub fn fsync(fd: usize) -> isize {
+    sys_fsync(fd)
+}
+
+pub fn fdatasync(fd: usize) -> isize {
+    sys_fdatasync(fd)
+}
+
+/// Read `linux_dirent64` records of the directory opened at `fd` into `buf`,
+/// see [`ReadDir`] for an iterator over them
+pub fn getdents64(fd: usize, buf: &mut [u8]) -> isize {
+    sys_getdents64(fd, buf)
+}
+
+pub fn chdir(path: &str) -> isize {
+    sys_chdir(path)
+}
+
+pub fn fchdir(fd: usize) -> isize {
+    sys_fchdir(fd)
+}
+
+pub fn chmod(path: &str, mode: u32) -> isize {
+    sys_fchmodat(AT_FDCWD as usize, path, mode)
+}
+
+pub fn fchmod(fd: usize, mode: u32) -> isize {
+    sys_fchmod(fd, mode)
+}
+
+/// Write the cwd with a trailing '\0' into `buf`, return its length or a negative errno
+pub fn getcwd(buf: &mut [u8]) -> isize {
+    sys_getcwd(buf)
+}
+
 //* ch7
 pub fn dup(fd: usize) -> isize {
     sys_dup(fd)
 }
 
+pub fn dup2(old_fd: usize, new_fd: usize) -> isize {
+    sys_dup2(old_fd, new_fd)
+}
+
+pub fn dup3(old_fd: usize, new_fd: usize, flags: OpenFlags) -> isize {
+    sys_dup3(old_fd, new_fd, flags.bits)
+}
+
 pub fn pipe(pipe_fd: &mut [usize]) -> isize {
-    sys_pipe(pipe_fd)
+    sys_pipe(pipe_fd, 0)
+}
+
+pub fn pipe2(pipe_fd: &mut [usize], flags: OpenFlags) -> isize {
+    sys_pipe(pipe_fd, flags.bits)
 }
 
 pub fn mail_read(buf: &mut [u8]) -> isize {
@@ -440,13 +643,14 @@ pub fn thread_create(entry: usize, arg: usize) -> isize {
 pub fn gettid() -> isize {
     sys_gettid()
 }
-pub fn waittid(tid: usize) -> isize {
+pub fn waittid(tid: usize, exit_code: &mut i32) -> isize {
     loop {
-        match sys_waittid(tid) {
-            -2 => {
+        match sys_waittid(tid, exit_code as *mut _) {
+            ret if ret == Errno::EAGAIN.as_ret() => {
                 yield_();
             }
-            exit_code => return exit_code,
+            // -ESRCH, -EDEADLK or the waited tid
+            ret => return ret,
         }
     }
 }